@@ -1,7 +1,17 @@
-use midly::{MidiMessage, TrackEvent, TrackEventKind};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 
+use midly::{MetaMessage, MidiMessage, TrackEvent, TrackEventKind};
+
+use super::groove::Groove;
+use super::key::{BaseKey, Key, KeyModifier, NamedKey, NamedNote, Note};
 use super::scale::Scale;
-use super::track::Track;
+use super::track::{
+    get_rhythm_end, snap_rhythm, TimedNote, Track, Voice, DEFAULT_RELEASE_VELOCITY, MAX_TICK, TICKS_PER_BEAT,
+};
 
 // struct JazzPiece {
 //     length: u8,
@@ -13,21 +23,348 @@ use super::track::Track;
 #[derive(Clone)]
 pub struct Chord {
     pub id: String,
-    pub start: u32,
+    /// The tick at which the chord track starts.
+    pub start_tick: u32,
     pub scale: Scale,
     pub chord: Vec<i8>,  // the positions of the scale played
     pub octave: i8,
-    pub notes: Vec<(bool, u8)>  // True means a note is played, False means a silence.
+    pub notes: Vec<(bool, u32)>,  // True means a note is played, False means a silence.
+    /// How many ticks apart the notes of the chord start when strummed, or 0 to play
+    /// them together.
+    pub strum_ticks: u32,
+    pub strum_direction: StrumDirection,
+    /// Leave the chord track out when writing MIDI.
+    pub muted: bool,
+    /// Only write soloed tracks to MIDI, if any track is soloed.
+    pub solo: bool,
+    /// The MIDI channel of the chord track, or None to assign one automatically.
+    pub channel: Option<u8>,
+    /// Rhythms of their own for the tones of `chord`, in the same order, e.g. to hold the
+    /// bass while the other tones move. Tones without one follow `notes`. Tones are not
+    /// strummed when any has its own rhythm.
+    pub tone_rhythms: Vec<Vec<(bool, u32)>>,
+}
+
+/// The Alberti figure of a triad, as indices of its tones from low to high: low, high,
+/// middle, high.
+pub const ALBERTI_TRIAD: [usize; 4] = [0, 2, 1, 2];
+
+/// The Alberti figure of a seventh chord, as indices of its tones from low to high,
+/// going through both middle tones between the returns to the top.
+pub const ALBERTI_SEVENTH: [usize; 8] = [0, 3, 1, 3, 2, 3, 1, 3];
+
+/// The quality of a triad, from its third and fifth above the root.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TriadQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+}
+
+/// The quality of an interval: seconds, thirds, sixths and sevenths are major or
+/// minor, and fourths and fifths perfect, unless they are augmented or diminished.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IntervalQuality {
+    Diminished,
+    Minor,
+    Perfect,
+    Major,
+    Augmented,
+}
+
+/// The structure of a chord, stacked in thirds of its scale from its root.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChordAnalysis {
+    /// The root of the chord, spelled as in the scale.
+    pub root: NamedKey,
+    /// The quality of the triad on the root, or None if the chord has no third or
+    /// its third and fifth don't form a triad, e.g. for suspended chords.
+    pub triad: Option<TriadQuality>,
+    /// The tones above the triad, as their number (7, 9, 11 or 13) and quality, from
+    /// low to high, e.g. [(7, IntervalQuality::Minor)] for a dominant seventh chord.
+    pub extensions: Vec<(u8, IntervalQuality)>,
+}
+
+/// The order in which the notes of a strummed chord are played.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StrumDirection {
+    /// From the lowest note to the highest.
+    Up,
+    /// From the highest note to the lowest.
+    Down,
+}
+
+impl Chord {
+    /// A chord track playing `chord` in the rhythm of `notes`, with its notes together
+    /// and on a channel assigned automatically.
+    pub fn new(id: String, scale: Scale, octave: i8, start_tick: u32, chord: Vec<i8>, notes: Vec<(bool, u32)>) -> Self {
+        Self {
+            id,
+            start_tick,
+            scale,
+            chord,
+            octave,
+            notes,
+            strum_ticks: 0,
+            strum_direction: StrumDirection::Up,
+            muted: false,
+            solo: false,
+            channel: None,
+            tone_rhythms: Vec::new(),
+        }
+    }
+    /// Double the chord tones at the given indices of `chord` an octave lower.
+    /// The doublings are played together with the rest of the chord.
+    ///
+    /// # Errors
+    /// - if an index is not that of a chord tone;
+    /// - if a doubled position doesn't fit in an i8.
+    pub fn with_octave_doubling(&self, positions: &[usize]) -> Result<Chord, String> {
+        let octave = i8::try_from(self.scale.len()).map_err(|_| format!("Scale of chord {} is too long!", self.id))?;
+        let mut chord = self.clone();
+        for index in positions {
+            let position = self
+                .chord
+                .get(*index)
+                .ok_or_else(|| format!("Chord {} has no tone {}!", self.id, index))?;
+            let doubling = position
+                .checked_sub(octave)
+                .ok_or_else(|| format!("Cannot double position {} of chord {} an octave lower!", position, self.id))?;
+            chord.chord.push(doubling);
+        }
+        Ok(chord)
+    }
+    /// Move the chord up by `degree` steps of its scale, e.g. from I to V with a degree
+    /// of 4. Positions beyond the scale carry over into the next octave.
+    ///
+    /// # Errors
+    /// - if a moved position doesn't fit in an i8.
+    pub fn at_degree(&self, degree: i8) -> Result<Chord, String> {
+        let mut chord = self.clone();
+        for position in chord.chord.iter_mut() {
+            *position = position
+                .checked_add(degree)
+                .ok_or_else(|| format!("Cannot move position {} of chord {} by {} degrees!", position, self.id, degree))?;
+        }
+        Ok(chord)
+    }
+    /// Break the chord into an Alberti bass of `bars` 4/4 bars of notes lasting
+    /// `unit_duration` ticks, with `ALBERTI_SEVENTH` for chords of four or more tones
+    /// and `ALBERTI_TRIAD` otherwise.
+    pub fn alberti(&self, unit_duration: u32, bars: u32) -> Voice {
+        let pattern: &[usize] = if self.chord.len() >= 4 { &ALBERTI_SEVENTH } else { &ALBERTI_TRIAD };
+        self.alberti_with_pattern(unit_duration, bars, pattern)
+    }
+    /// Break the chord into a voice of `bars` 4/4 bars playing its tones in the order
+    /// of `pattern`, which gives indices of the tones from low to high (beyond the highest
+    /// tone is the highest tone). The last note is shortened to end with the last bar.
+    pub fn alberti_with_pattern(&self, unit_duration: u32, bars: u32, pattern: &[usize]) -> Voice {
+        let mut positions = self.chord.clone();
+        positions.sort();
+
+        let mut notes = Vec::new();
+        let mut remaining_ticks = 4 * TICKS_PER_BEAT * bars;
+        let mut indices = pattern.iter().cycle();
+        while remaining_ticks > 0 && unit_duration > 0 && !positions.is_empty() {
+            let Some(index) = indices.next() else { break };
+            let position = positions[(*index).min(positions.len() - 1)];
+            let duration = unit_duration.min(remaining_ticks);
+            notes.push(TimedNote::new(Some(position), duration));
+            remaining_ticks -= duration;
+        }
+
+        let mut voice = Voice::new(format!("{}_alberti", self.id), self.scale.clone(), self.octave, self.start_tick, notes);
+        voice.channel = self.channel;
+        voice
+    }
+    /// The rhythm of the tone at `index` of `chord`.
+    fn get_tone_rhythm(&self, index: usize) -> &[(bool, u32)] {
+        self.tone_rhythms.get(index).unwrap_or(&self.notes)
+    }
+    /// Create the note events of a chord whose tones have rhythms of their own. Each
+    /// tone is played independently, and a tone is released before it is played again.
+    fn get_tone_rhythm_events(&self, channel: u8, velocity: u8) -> Vec<TrackEvent<'_>> {
+        let mut timed_events: Vec<(u32, bool, MidiMessage)> = Vec::new();
+        for (i, position) in self.chord.iter().enumerate() {
+            let key = self.scale.get_note(*position, self.octave).0;
+            let mut tick = self.start_tick;
+            for (is_played, duration) in self.get_tone_rhythm(i) {
+                // A tone without duration would be released before it is played, and be
+                // left stuck: skip it, as voices do.
+                if *is_played && *duration > 0 {
+                    timed_events.push((tick, true, MidiMessage::NoteOn { key: key.into(), vel: velocity.into() }));
+                    timed_events.push((tick + duration, false, MidiMessage::NoteOff { key: key.into(), vel: DEFAULT_RELEASE_VELOCITY.into() }));
+                }
+                tick += duration;
+            }
+        }
+        // Releases come first among simultaneous events, and the sort keeps the order of the tones.
+        timed_events.sort_by_key(|(tick, is_note_on, _)| (*tick, *is_note_on));
+
+        let mut track_events = Vec::new();
+        let mut previous_tick = 0;
+        for (tick, _, message) in timed_events {
+            track_events.push(TrackEvent {
+                delta: (tick - previous_tick).into(),
+                kind: TrackEventKind::Midi { channel: channel.into(), message },
+            });
+            previous_tick = tick;
+        }
+        track_events.push(TrackEvent {
+            delta: (self.total_ticks() - previous_tick).into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        track_events
+    }
+    /// Analyze the chord by stacking its tones in thirds of its scale: the root is the
+    /// tone from which the others reach the lowest numbers (7 rather than 9, and so on),
+    /// whatever the inversion or doublings. Returns None unless the scale has seven notes.
+    pub fn analyze(&self) -> Option<ChordAnalysis> {
+        // The number of the interval of each number of scale steps, e.g. 3 for 2 steps.
+        const NUMBERS: [u8; 7] = [1, 9, 3, 11, 5, 13, 7];
+        if self.scale.len() != NUMBERS.len() {
+            return None;
+        }
+        let len: i16 = 7;
+        let number = |root: i8, position: i8| {
+            NUMBERS[usize::try_from((i16::from(position) - i16::from(root)).rem_euclid(len)).unwrap()]
+        };
+        let root = self
+            .chord
+            .iter()
+            .copied()
+            .min_by_key(|root| (self.chord.iter().map(|position| number(*root, *position)).max(), *root))
+            .unwrap_or(0);
+
+        let offset = |position: i8| {
+            let offsets = self.scale.offsets();
+            let position = i16::from(position);
+            i16::from(offsets[usize::try_from(position.rem_euclid(len)).unwrap()]) + 12 * position.div_euclid(len)
+        };
+        let mut intervals: Vec<(u8, IntervalQuality)> = self
+            .chord
+            .iter()
+            .filter_map(|position| {
+                let semitones = i8::try_from((offset(*position) - offset(root)).rem_euclid(12)).unwrap();
+                let number = number(root, *position);
+                Some((number, get_interval_quality(number, semitones)?))
+            })
+            .collect();
+        intervals.sort_by_key(|(number, _)| *number);
+        intervals.dedup();
+
+        let interval = |number: u8| intervals.iter().find(|(other, _)| *other == number).map(|(_, quality)| *quality);
+        let triad = match (interval(3), interval(5)) {
+            (Some(IntervalQuality::Major), None | Some(IntervalQuality::Perfect)) => Some(TriadQuality::Major),
+            (Some(IntervalQuality::Minor), None | Some(IntervalQuality::Perfect)) => Some(TriadQuality::Minor),
+            (Some(IntervalQuality::Minor), Some(IntervalQuality::Diminished)) => Some(TriadQuality::Diminished),
+            (Some(IntervalQuality::Major), Some(IntervalQuality::Augmented)) => Some(TriadQuality::Augmented),
+            _ => None,
+        };
+        let root_index = usize::try_from(i16::from(root).rem_euclid(len)).unwrap();
+        Some(ChordAnalysis {
+            root: self.scale.named_keys()[root_index],
+            triad,
+            extensions: intervals.into_iter().filter(|(number, _)| *number >= 7).collect(),
+        })
+    }
+    /// The spelled notes of the chord at its octave, in the order of `chord`.
+    pub fn named_notes(&self) -> Vec<NamedNote> {
+        self.chord.iter().map(|position| self.scale.get_named_note(*position, self.octave)).collect()
+    }
 }
 
 impl Track for Chord {
     fn get_id(&self) -> &str {
         &self.id
     }
-    fn get_start(&self) -> &u32 {
-        &self.start
+    fn get_start_tick(&self) -> &u32 {
+        &self.start_tick
+    }
+    fn set_start_tick(&mut self, start_tick: u32) {
+        self.start_tick = start_tick;
+    }
+    fn clone_box(&self) -> Box<dyn Track> {
+        Box::new(self.clone())
+    }
+    fn is_muted(&self) -> bool {
+        self.muted
+    }
+    fn is_solo(&self) -> bool {
+        self.solo
+    }
+    fn get_channel(&self) -> Option<u8> {
+        self.channel
+    }
+    fn get_scale(&self) -> &Scale {
+        &self.scale
+    }
+    fn total_ticks(&self) -> u32 {
+        let rhythm_end = |rhythm: &[(bool, u32)]| {
+            get_rhythm_end(self.start_tick, rhythm.iter().map(|(_, duration)| *duration)).unwrap_or(MAX_TICK)
+        };
+        if self.tone_rhythms.is_empty() {
+            rhythm_end(&self.notes)
+        } else {
+            (0..self.chord.len()).map(|i| rhythm_end(self.get_tone_rhythm(i))).max().unwrap_or(self.start_tick)
+        }
+    }
+    fn describe(&self) -> String {
+        format!("chord {}", self.id)
+    }
+    fn check_midi(&self) -> Result<(), String> {
+        let fits = self
+            .tone_rhythms
+            .iter()
+            .chain([&self.notes])
+            .all(|rhythm| get_rhythm_end(self.start_tick, rhythm.iter().map(|(_, duration)| *duration)).is_some());
+        if !fits {
+            return Err(format!("Chord {} goes past tick {}, the last a MIDI file can hold!", self.id, MAX_TICK));
+        }
+        // The tones of a chord share their channel, which can't be bent for one of them.
+        if self.named_notes().iter().any(|named_note| named_note.get_cents_offset() != 0.0) {
+            return Err(format!("Chord {} has quarter tones, which chords can't play!", self.id));
+        }
+        Ok(())
+    }
+    /// The chord and each tone rhythm are snapped on their own.
+    fn snap_durations(&mut self) {
+        for rhythm in self.tone_rhythms.iter_mut().chain([&mut self.notes]) {
+            *rhythm = snap_rhythm(rhythm).into_iter().map(|(_, is_played, duration)| (is_played, duration)).collect();
+        }
     }
-    fn to_midi(&self, instrument: u8, channel: u8) -> Vec<TrackEvent> {
+    /// Chords are only moved in time: their notes have no velocity of their own.
+    /// Chords whose tones have rhythms of their own keep their start, and their tones
+    /// don't move before it.
+    fn apply_groove(&mut self, groove: &Groove, _velocity: u8) {
+        if self.tone_rhythms.is_empty() {
+            let (start_tick, durations, _) = groove.apply(self.start_tick, &self.notes);
+            self.start_tick = start_tick;
+            for ((_, duration), new_duration) in self.notes.iter_mut().zip(durations) {
+                *duration = new_duration;
+            }
+            return;
+        }
+        for rhythm in self.tone_rhythms.iter_mut().chain([&mut self.notes]) {
+            // A leading rest of no length lets the first note move later, but not earlier.
+            let mut padded_rhythm = vec![(false, 0)];
+            padded_rhythm.extend(rhythm.iter().copied());
+            let (_, durations, _) = groove.apply(self.start_tick, &padded_rhythm);
+            for ((_, duration), new_duration) in padded_rhythm.iter_mut().zip(durations) {
+                *duration = new_duration;
+            }
+            if padded_rhythm[0].1 == 0 {
+                padded_rhythm.remove(0);
+            }
+            *rhythm = padded_rhythm;
+        }
+    }
+    #[cfg(feature = "std")]
+    fn to_json(&self) -> Result<serde_json::Value, String> {
+        super::json_output::chord_to_json(self)
+    }
+    fn to_midi(&self, instrument: u8, channel: u8, velocity: u8) -> Vec<TrackEvent<'_>> {
         let mut track_events = Vec::<TrackEvent>::new();
 
         // Set piano as instrument
@@ -39,33 +376,54 @@ impl Track for Chord {
             },
         });
 
-        let mut next_note_delta = 0;
+        if !self.tone_rhythms.is_empty() {
+            track_events.extend(self.get_tone_rhythm_events(channel, velocity));
+            return track_events;
+        }
+
+        let mut keys: Vec<u8> =
+            self.chord.iter().map(|position| self.scale.get_note(*position, self.octave).0).collect();
+        if self.strum_ticks > 0 {
+            keys.sort();
+            if self.strum_direction == StrumDirection::Down {
+                keys.reverse();
+            }
+        }
+
+        let mut next_note_delta = self.start_tick;
 
         for (is_played, duration) in self.notes.iter() {
-            let duration = u32::from(duration.clone());
+            let duration = *duration;
 
             if *is_played {
-                for position in self.chord.iter() {
+                // When strumming, each note starts `strum_ticks` after the previous one,
+                // as long as the chord lasts.
+                let mut onset = 0;
+                for (i, key) in keys.iter().enumerate() {
+                    let next_onset = (self.strum_ticks * u32::try_from(i).unwrap()).min(duration);
+                    next_note_delta += next_onset - onset;
+                    onset = next_onset;
                     track_events.push(TrackEvent {
-                        delta: (next_note_delta).into(),
+                        delta: next_note_delta.into(),
                         kind: TrackEventKind::Midi {
                             channel: channel.into(),
                             message: MidiMessage::NoteOn {
-                                key: self.scale.get_note(*position, self.octave).0.into(),
-                                vel: 127.into(),
+                                key: (*key).into(),
+                                vel: velocity.into(),
                             },
                         },
                     });
                     next_note_delta = 0;
                 }
 
-                for position in self.chord.iter() {
+                // All notes of the chord are released together, after its duration.
+                for (i, key) in keys.iter().enumerate() {
                     track_events.push(TrackEvent {
-                        delta: duration.into(),
+                        delta: if i == 0 { duration - onset } else { 0 }.into(),
                         kind: TrackEventKind::Midi {
                             channel: channel.into(),
                             message: MidiMessage::NoteOff {
-                                key: self.scale.get_note(*position, self.octave).0.into(),
+                                key: (*key).into(),
                                 vel: 127.into(),
                             },
                         },
@@ -75,13 +433,258 @@ impl Track for Chord {
                 next_note_delta += duration;
             }
         };
+
+        // Track end, after any trailing silence
+        track_events.push(TrackEvent {
+            delta: next_note_delta.into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+
         track_events
     }
 }
 
-#[cfg(test)]
+/// Which voice, counting from the top, a drop voicing lowers by an octave.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DropKind {
+    Drop2,
+    Drop3,
+}
+
+/// Spread a close-position chord (given as scale positions) by lowering its second
+/// (drop-2) or third (drop-3) voice from the top by an octave of a scale with
+/// `scale_len` notes. Chords with too few voices are returned as they are.
+/// The positions are returned from low to high.
+///
+/// # Errors
+/// - if the dropped position doesn't fit in an i8.
+pub fn drop_voicing(chord: &[i8], scale_len: usize, kind: DropKind) -> Result<Vec<i8>, String> {
+    let mut positions = chord.to_vec();
+    positions.sort();
+
+    let voice_from_top = match kind {
+        DropKind::Drop2 => 2,
+        DropKind::Drop3 => 3,
+    };
+    if let Some(index) = positions.len().checked_sub(voice_from_top) {
+        positions[index] = i8::try_from(scale_len)
+            .ok()
+            .and_then(|octave| positions[index].checked_sub(octave))
+            .ok_or_else(|| format!("Cannot drop position {} by an octave of {} notes!", positions[index], scale_len))?;
+        positions.sort();
+    }
+    Ok(positions)
+}
+
+/// The quality of an interval of the given number (1 to 13) spanning `semitones`,
+/// counted within an octave, or None if it is too far from the usual size.
+fn get_interval_quality(number: u8, semitones: i8) -> Option<IntervalQuality> {
+    let (is_perfect, usual_semitones) = match number {
+        1 => (true, 0),
+        3 => (false, 4),
+        5 => (true, 7),
+        7 => (false, 11),
+        9 => (false, 2),
+        11 => (true, 5),
+        13 => (false, 9),
+        _ => return None,
+    };
+    match ((semitones - usual_semitones + 6).rem_euclid(12) - 6, is_perfect) {
+        (-1, true) | (-2, false) => Some(IntervalQuality::Diminished),
+        (-1, false) => Some(IntervalQuality::Minor),
+        (0, true) => Some(IntervalQuality::Perfect),
+        (0, false) => Some(IntervalQuality::Major),
+        (1, _) => Some(IntervalQuality::Augmented),
+        _ => None,
+    }
+}
+
+/// Chord symbol suffixes, by the intervals of the chord above its root.
+const CHORD_QUALITIES: [(&[i8], &str); 12] = [
+    (&[0, 4, 7], ""),
+    (&[0, 3, 7], "m"),
+    (&[0, 3, 6], "dim"),
+    (&[0, 4, 8], "aug"),
+    (&[0, 4, 7, 11], "maj7"),
+    (&[0, 4, 7, 10], "7"),
+    (&[0, 3, 7, 10], "m7"),
+    (&[0, 3, 7, 11], "mMaj7"),
+    (&[0, 3, 6, 10], "m7♭5"),
+    (&[0, 3, 6, 9], "dim7"),
+    (&[0, 5, 7], "sus4"),
+    (&[0, 2, 7], "sus2"),
+];
+
+/// Name the chord formed by the given notes, e.g. "Cmaj7", or "C/E" when the
+/// lowest note isn't the root. Returns None if the notes don't form a known chord.
+pub fn identify_chord(notes: &[Note]) -> Option<String> {
+    let bass = notes.iter().min_by_key(|note| note.0)?;
+    let (bass_key, _) = bass.decompose();
+
+    let mut keys: Vec<i8> = notes.iter().map(|note| note.decompose().0.get_value()).collect();
+    keys.sort();
+    keys.dedup();
+
+    // Prefer the bass as the root, so that e.g. C6 isn't named Am7/C.
+    let roots = [bass_key.get_value()]
+        .into_iter()
+        .chain(keys.iter().copied().filter(|key| *key != bass_key.get_value()));
+    for root in roots {
+        let mut intervals: Vec<i8> = keys.iter().map(|key| (key - root).rem_euclid(12)).collect();
+        intervals.sort();
+
+        if let Some((_, suffix)) = CHORD_QUALITIES.iter().find(|(quality, _)| *quality == intervals) {
+            let root_key = Key::new(root);
+            return Some(if root == bass_key.get_value() {
+                format!("{}{}", root_key, suffix)
+            } else {
+                format!("{}{}/{}", root_key, suffix, bass_key)
+            });
+        }
+    }
+    None
+}
+
+/// Get the note of the given key closest to `from`, or None if it's outside of the MIDI range.
+fn closest_note(from: &Note, key: &Key) -> Option<Note> {
+    let (from_key, _) = from.decompose();
+    let up = (key.get_value() - from_key.get_value()).rem_euclid(12);
+    let candidates = if up <= 6 { [up, up - 12] } else { [up - 12, up] };
+    candidates
+        .into_iter()
+        .map(|offset| i16::from(from.0) + i16::from(offset))
+        .find(|note| (0..=127).contains(note))
+        .map(|note| Note(u8::try_from(note).unwrap()))
+}
+
+/// Find the assignment of target keys to the notes of `from`, from `from_index` onwards,
+/// that moves the least. Every key is used at least once, unless there are more keys than
+/// notes, in which case each key is used at most once.
+fn voice_lead_recursive(
+    from: &[Note],
+    to_keys: &[Key],
+    from_index: usize,
+    key_uses: &mut Vec<usize>,
+) -> Option<(u32, Vec<Note>)> {
+    if from_index == from.len() {
+        let all_used = key_uses.iter().all(|uses| *uses > 0);
+        return (all_used || from.len() < to_keys.len()).then(|| (0, Vec::new()));
+    }
+
+    let mut best: Option<(u32, Vec<Note>)> = None;
+    for (key_index, key) in to_keys.iter().enumerate() {
+        if from.len() < to_keys.len() && key_uses[key_index] > 0 {
+            continue;
+        }
+        let Some(note) = closest_note(&from[from_index], key) else {
+            continue;
+        };
+        key_uses[key_index] += 1;
+        if let Some((cost, mut notes)) = voice_lead_recursive(from, to_keys, from_index + 1, key_uses) {
+            let cost = cost + u32::from(note.0.abs_diff(from[from_index].0));
+            if best.as_ref().is_none_or(|(best_cost, _)| cost < *best_cost) {
+                notes.insert(0, note);
+                best = Some((cost, notes));
+            }
+        }
+        key_uses[key_index] -= 1;
+    }
+    best
+}
+
+/// Voice the given keys so as to move as little as possible from the notes of the
+/// previous chord. If the previous chord has more notes than there are keys, some keys
+/// are doubled; if it has fewer, the remaining keys are added closest to its lowest note.
+/// The notes are returned from low to high.
+pub fn voice_lead(from: &[Note], to_keys: &[Key]) -> Vec<Note> {
+    let mut to_keys = to_keys.to_vec();
+    to_keys.sort_by_key(|key| key.get_value());
+    to_keys.dedup();
+    let Some(lowest) = from.iter().min_by_key(|note| note.0) else {
+        return Vec::new();
+    };
+
+    let mut key_uses = vec![0; to_keys.len()];
+    let (_, mut notes) =
+        voice_lead_recursive(from, &to_keys, 0, &mut key_uses).unwrap_or_default();
+
+    for key in to_keys.iter() {
+        let key_used = notes.iter().any(|note| note.decompose().0 == *key);
+        if !key_used {
+            notes.extend(closest_note(lowest, key));
+        }
+    }
+    notes.sort_by_key(|note| note.0);
+    notes
+}
+
+/// Walk a quarter-note bass line through `progression`. Each chord lasts as many beats
+/// as its notes, and its root (its lowest position) is played on its first beat at its
+/// octave. The following beats step through the notes of `scale` towards the root of the
+/// next chord, turning back rather than reaching it early, and the last beat approaches
+/// it by a half step. The bass walks up the scale over the last chord.
+///
+/// The voice is written in the chromatic scale starting at C-1, so that its positions
+/// are MIDI keys, and starts with the first chord.
+pub fn walking_bass(progression: &[Chord], scale: &Scale) -> Voice {
+    let pitch_classes = scale.pitch_class_set();
+    // The closest key of the scale above (or below) `key`, or `key` if there is none.
+    let step = |key: u8, up: bool| -> u8 {
+        let mut candidates: Box<dyn Iterator<Item = u8>> =
+            if up { Box::new(key.saturating_add(1)..=127) } else { Box::new((0..key).rev()) };
+        candidates.find(|candidate| pitch_classes.contains(&(candidate % 12))).unwrap_or(key)
+    };
+    let roots: Vec<u8> = progression
+        .iter()
+        .map(|chord| {
+            let root = chord.chord.iter().min().copied().unwrap_or(0);
+            chord.scale.get_note(root, chord.octave).0
+        })
+        .collect();
+
+    let mut notes = Vec::new();
+    for (i, chord) in progression.iter().enumerate() {
+        let beats = (chord.total_ticks().saturating_sub(chord.start_tick) / TICKS_PER_BEAT).max(1);
+        let root = roots[i];
+        let mut key = root;
+        notes.push(key);
+        match roots.get(i + 1) {
+            Some(&target) => {
+                let up = target >= root;
+                let approach = if up { target.saturating_sub(1) } else { target.saturating_add(1).min(127) };
+                for beat in 1..beats {
+                    key = if beat == beats - 1 {
+                        approach
+                    } else {
+                        let next_key = step(key, up);
+                        let overshoots = if up { next_key >= approach } else { next_key <= approach };
+                        if overshoots { step(key, !up) } else { next_key }
+                    };
+                    notes.push(key);
+                }
+            }
+            None => {
+                for _ in 1..beats {
+                    key = step(key, true);
+                    notes.push(key);
+                }
+            }
+        }
+    }
+
+    let notes = notes
+        .into_iter()
+        .map(|key| TimedNote::new(Some(i8::try_from(key).unwrap()), TICKS_PER_BEAT))
+        .collect();
+    let c = NamedKey::new(BaseKey::C, KeyModifier::Natural);
+    let start = progression.first().map_or(0, |chord| chord.start_tick);
+    Voice::new("walking_bass".to_string(), Scale::chromatic(c), -1, start, notes)
+}
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use super::super::{Piece, NamedKey};
+    use super::super::track::DEFAULT_VELOCITY;
+    use super::super::{Piece, NamedKey, NamedNote};
     use super::*;
     use std::io::Cursor;
 
@@ -92,17 +695,398 @@ mod tests {
 
         let left_hand = Piece {
             bpm: 120, 
-            tracks: vec![Box::new(Chord{
-                id: "chord_1".to_string(),
-                start: 0,
-                scale: c_major_scale,
-                chord: vec![0, 2, 6],
-                octave: 3,
-                notes: vec![(true, 12), (true, 24), (true, 24), (false, 24), (true, 12)],
-            })]
+            tracks: vec![Box::new(Chord::new(
+                "chord_1".to_string(),
+                c_major_scale,
+                3,
+                0,
+                vec![0, 2, 6],
+                vec![(true, 12), (true, 24), (true, 24), (false, 24), (true, 12)],
+            ))],
+            ..Default::default()
         };
 
         let mut buffer = Cursor::new(vec![0; 100]);
         left_hand.write_midi(&mut buffer).unwrap();
+        assert_eq!(left_hand.tracks[0].total_ticks(), 96);
+    }
+
+    #[test]
+    fn can_reject_quarter_tones() {
+        let scale = Scale::from_named_keys(
+            ["C", "D", "E𝄳", "F", "G", "A", "B𝄳"].map(|key| str::parse::<NamedKey>(key).unwrap()).to_vec(),
+        )
+        .unwrap();
+        let mut chord = Chord::new("chord_1".to_string(), scale, 3, 0, vec![0, 3, 5], vec![(true, 24)]);
+        assert!(chord.check_midi().is_ok());
+        chord.chord = vec![0, 2, 4];
+        assert_eq!(chord.check_midi().unwrap_err(), "Chord chord_1 has quarter tones, which chords can't play!");
+        let piece = Piece { tracks: vec![Box::new(chord)], ..Default::default() };
+        assert!(piece.to_midi_bytes().is_err());
+    }
+
+    #[test]
+    fn can_end_chord_tracks() {
+        let chord = Chord::new(
+            "chord_1".to_string(),
+            str::parse::<Scale>("Cmaj").unwrap(),
+            3,
+            TICKS_PER_BEAT,
+            vec![0, 2, 4],
+            vec![(true, 24), (false, 12), (true, 12), (false, 48)],
+        );
+        let track_events = chord.to_midi(1, 0, DEFAULT_VELOCITY);
+
+        let last_event = track_events.last().unwrap();
+        assert_eq!(last_event.kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+        assert_eq!(last_event.delta.as_int(), 48);
+        let total_length: u32 = track_events.iter().map(|event| event.delta.as_int()).sum();
+        assert_eq!(total_length, chord.total_ticks());
+        assert_eq!(total_length, 24 + 24 + 12 + 12 + 48);
+    }
+
+    #[test]
+    fn can_move_chords_to_degrees() {
+        let tonic = Chord::new(
+            "chord_1".to_string(),
+            str::parse::<Scale>("Cmaj").unwrap(),
+            3,
+            0,
+            vec![0, 2, 4],
+            vec![(true, 24)],
+        );
+        let keys = |chord: &Chord| -> Vec<u8> {
+            chord.chord.iter().map(|position| chord.scale.get_note(*position, chord.octave).0).collect()
+        };
+
+        // G3, B3 and D4: the fifth of the dominant is in the next octave.
+        assert_eq!(keys(&tonic.at_degree(4).unwrap()), [55, 59, 62]);
+        // G2, B2 and D3: the dominant below.
+        assert_eq!(keys(&tonic.at_degree(-3).unwrap()), [43, 47, 50]);
+        assert_eq!(keys(&tonic.at_degree(7).unwrap()), keys(&tonic).iter().map(|key| key + 12).collect::<Vec<_>>());
+        assert_eq!(tonic.at_degree(125).err(), Some("Cannot move position 4 of chord chord_1 by 125 degrees!".to_string()));
+    }
+
+    #[test]
+    fn can_name_chord_notes() {
+        let chord = Chord::new(
+            "chord_1".to_string(),
+            str::parse::<Scale>("Cmaj").unwrap(),
+            3,
+            0,
+            vec![0, 2, 4],
+            vec![(true, 24)],
+        );
+        let names = |chord: &Chord| -> Vec<String> { chord.named_notes().iter().map(NamedNote::to_string).collect() };
+        assert_eq!(names(&chord), ["C3", "E3", "G3"]);
+        assert_eq!(names(&chord.at_degree(4).unwrap()), ["G3", "B3", "D4"]);
+
+        let b_flat_chord = Chord { scale: str::parse::<Scale>("Bbmaj").unwrap(), ..chord };
+        assert_eq!(names(&b_flat_chord), ["B♭3", "D4", "F4"]);
+    }
+
+    #[test]
+    fn can_strum_chords() {
+        let mut chord = Chord::new(
+            "chord_1".to_string(),
+            str::parse::<Scale>("Cmaj").unwrap(),
+            3,
+            0,
+            vec![4, 0, 2],
+            vec![(true, 24), (true, 24)],
+        );
+        chord.strum_ticks = 2;
+        let note_events = |chord: &Chord| -> Vec<(u32, u8, bool)> {
+            chord
+                .to_midi(1, 0, DEFAULT_VELOCITY)
+                .iter()
+                .filter_map(|event| match event.kind {
+                    TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => {
+                        Some((event.delta.as_int(), key.as_int(), true))
+                    }
+                    TrackEventKind::Midi { message: MidiMessage::NoteOff { key, .. }, .. } => {
+                        Some((event.delta.as_int(), key.as_int(), false))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let strummed_chord = [
+            (0, 48, true), (2, 52, true), (2, 55, true), (20, 48, false), (0, 52, false), (0, 55, false),
+        ];
+        assert_eq!(note_events(&chord), [strummed_chord, strummed_chord].concat());
+
+        chord.strum_direction = StrumDirection::Down;
+        assert_eq!(
+            note_events(&chord)[..6],
+            [(0, 55, true), (2, 52, true), (2, 48, true), (20, 55, false), (0, 52, false), (0, 48, false)]
+        );
+
+        // Without strumming, the notes keep the order of the chord.
+        chord.strum_ticks = 0;
+        assert_eq!(
+            note_events(&chord)[..6],
+            [(0, 55, true), (0, 48, true), (0, 52, true), (24, 55, false), (0, 48, false), (0, 52, false)]
+        );
+    }
+
+    #[test]
+    fn can_give_chord_tones_their_own_rhythm() {
+        let mut chord = Chord::new(
+            "chord_1".to_string(),
+            str::parse::<Scale>("Cmaj").unwrap(),
+            3,
+            0,
+            vec![0, 4, 2],
+            vec![(false, 24), (true, 24)],
+        );
+        chord.tone_rhythms = vec![vec![(true, 48)], vec![(true, 12); 4]];
+        let note_events = |chord: &Chord| -> Vec<(u32, u8, bool)> {
+            chord
+                .to_midi(1, 0, DEFAULT_VELOCITY)
+                .iter()
+                .filter_map(|event| match event.kind {
+                    TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => {
+                        Some((event.delta.as_int(), key.as_int(), true))
+                    }
+                    TrackEventKind::Midi { message: MidiMessage::NoteOff { key, .. }, .. } => {
+                        Some((event.delta.as_int(), key.as_int(), false))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // C3 is held, G3 is repeated, and E3 follows the rhythm of the chord.
+        assert_eq!(
+            note_events(&chord),
+            [
+                (0, 48, true), (0, 55, true),
+                (12, 55, false), (0, 55, true),
+                (12, 55, false), (0, 55, true), (0, 52, true),
+                (12, 55, false), (0, 55, true),
+                (12, 48, false), (0, 55, false), (0, 52, false),
+            ]
+        );
+        assert_eq!(chord.total_ticks(), 48);
+        // Tones are released like the notes of a voice.
+        assert!(chord.to_midi(1, 0, DEFAULT_VELOCITY).iter().all(|event| match event.kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOff { vel, .. }, .. } => vel == DEFAULT_RELEASE_VELOCITY,
+            _ => true,
+        }));
+
+        chord.tone_rhythms[0] = vec![(true, 72)];
+        assert_eq!(chord.total_ticks(), 72);
+        let track_events = chord.to_midi(1, 0, DEFAULT_VELOCITY);
+        assert_eq!(track_events.iter().map(|event| event.delta.as_int()).sum::<u32>(), 72);
+
+        // A tone of no length is skipped rather than left stuck.
+        chord.tone_rhythms = vec![vec![(true, 0), (true, 24)]];
+        chord.notes = vec![(false, 24)];
+        assert_eq!(note_events(&chord), [(0, 48, true), (24, 48, false)]);
+    }
+
+    #[test]
+    fn can_play_alberti_basses() {
+        let chord = Chord::new(
+            "chord_1".to_string(),
+            str::parse::<Scale>("Cmaj").unwrap(),
+            3,
+            0,
+            vec![4, 0, 2],
+            vec![(true, 4 * TICKS_PER_BEAT)],
+        );
+        let names = |voice: &Voice| -> Vec<String> {
+            voice
+                .notes
+                .iter()
+                .map(|timed_note| voice.scale.get_named_note(timed_note.position.unwrap(), voice.octave).to_string())
+                .collect()
+        };
+
+        let alberti = chord.alberti(TICKS_PER_BEAT / 2, 1);
+        assert_eq!(names(&alberti), ["C3", "G3", "E3", "G3"].repeat(2));
+        assert!(alberti.notes.iter().all(|timed_note| timed_note.duration == TICKS_PER_BEAT / 2));
+
+        let seventh_chord = Chord { chord: vec![0, 2, 4, 6], ..chord.clone() };
+        assert_eq!(
+            names(&seventh_chord.alberti(TICKS_PER_BEAT / 2, 1)),
+            ["C3", "B3", "E3", "B3", "G3", "B3", "E3", "B3"]
+        );
+
+        // Triplets don't fill a bar evenly: the last note is cut short.
+        let alberti = chord.alberti_with_pattern(36, 1, &ALBERTI_TRIAD);
+        assert_eq!(names(&alberti), ["C3", "G3", "E3"]);
+        assert_eq!(alberti.notes[2].duration, 24);
+    }
+
+    #[test]
+    fn can_walk_bass_lines() {
+        let chord = |scale: &str, start: u32| Chord::new(
+            scale.to_string(),
+            str::parse::<Scale>(scale).unwrap(),
+            2,
+            start,
+            vec![0, 2, 4, 6],
+            vec![(true, 4 * TICKS_PER_BEAT)],
+        );
+        let progression: Vec<Chord> = ["Cmaj", "Amaj", "Dmin", "Gmaj"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, scale)| chord(scale, 4 * TICKS_PER_BEAT * u32::try_from(i).unwrap()))
+            .collect();
+
+        let bass = walking_bass(&progression, &str::parse::<Scale>("Cmaj").unwrap());
+        let keys: Vec<i8> = bass.notes.iter().map(|timed_note| timed_note.position.unwrap()).collect();
+        // C2, A2, D2 and G2 on the downbeats.
+        assert_eq!([keys[0], keys[4], keys[8], keys[12]], [36, 45, 38, 43]);
+        assert_eq!(
+            keys,
+            [
+                36, 38, 40, 44, // C2 D2 E2 G♯2, walking up to A2
+                45, 43, 41, 39, // A2 G2 F2 E♭2, walking down to D2
+                38, 40, 41, 42, // D2 E2 F2 F♯2, walking up to G2
+                43, 45, 47, 48, // G2 A2 B2 C3
+            ]
+        );
+        assert!(bass.notes.iter().all(|timed_note| timed_note.duration == TICKS_PER_BEAT));
+    }
+
+    #[test]
+    fn can_double_chord_tones() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+
+        let chord = Chord::new("chord_1".to_string(), c_major_scale, 3, 0, vec![0, 2, 4], vec![(true, 24)]);
+        assert_eq!(chord.with_octave_doubling(&[3]).err(), Some("Chord chord_1 has no tone 3!".to_string()));
+        let low_chord = Chord { chord: vec![-125, 0], ..chord.clone() };
+        assert_eq!(
+            low_chord.with_octave_doubling(&[0]).err(),
+            Some("Cannot double position -125 of chord chord_1 an octave lower!".to_string())
+        );
+        let chord = chord.with_octave_doubling(&[0]).unwrap();
+
+        let keys: Vec<u8> = chord
+            .to_midi(1, 0, DEFAULT_VELOCITY)
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => {
+                    Some(key.as_int())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, [48, 52, 55, 36]);
+    }
+
+    #[test]
+    fn can_analyze_chords() {
+        let chord = |scale: &str, positions: Vec<i8>| Chord::new(
+            "chord_1".to_string(),
+            str::parse::<Scale>(scale).unwrap(),
+            3,
+            0,
+            positions,
+            vec![(true, 24)],
+        );
+        let analysis = |root: &str, triad: Option<TriadQuality>, extensions: Vec<(u8, IntervalQuality)>| ChordAnalysis {
+            root: str::parse::<NamedKey>(root).unwrap(),
+            triad,
+            extensions,
+        };
+
+        assert_eq!(
+            chord("Cmaj", vec![0, 2, 4, 6]).analyze(),
+            Some(analysis("C", Some(TriadQuality::Major), vec![(7, IntervalQuality::Major)]))
+        );
+        assert_eq!(
+            chord("Cmaj", vec![4, 6, 8, 10]).analyze(),
+            Some(analysis("G", Some(TriadQuality::Major), vec![(7, IntervalQuality::Minor)]))
+        );
+        // Inversions and doublings don't change the root.
+        assert_eq!(chord("Cmaj", vec![-2, 1, 3, 5]).analyze(), Some(analysis("D", Some(TriadQuality::Minor), vec![])));
+        assert_eq!(
+            chord("Cmaj", vec![6, 8, 10, 12]).analyze(),
+            Some(analysis("B", Some(TriadQuality::Diminished), vec![(7, IntervalQuality::Minor)]))
+        );
+        assert_eq!(
+            chord("Amin", vec![2, 4, 6]).analyze(),
+            Some(analysis("C", Some(TriadQuality::Augmented), vec![]))
+        );
+        assert_eq!(
+            chord("Fmaj", vec![0, 2, 4, 6, 8]).analyze(),
+            Some(analysis("F", Some(TriadQuality::Major), vec![(7, IntervalQuality::Major), (9, IntervalQuality::Major)]))
+        );
+        assert_eq!(
+            chord("Cmaj", vec![4, 6, 8, 10, 12, 14]).analyze().unwrap().extensions,
+            [(7, IntervalQuality::Minor), (9, IntervalQuality::Major), (11, IntervalQuality::Perfect)]
+        );
+        // A suspended chord has no triad.
+        assert_eq!(chord("Cmaj", vec![0, 1, 4]).analyze(), Some(analysis("C", None, vec![(9, IntervalQuality::Major)])));
+
+        // Positions far apart don't overflow, and other scales can't be stacked in thirds.
+        assert_eq!(
+            chord("Cmaj", vec![-128, 127]).analyze().map(|analysis| analysis.root),
+            Some(str::parse::<NamedKey>("D").unwrap())
+        );
+        let pentatonic = Scale::new(str::parse::<NamedKey>("C").unwrap(), vec![0, 2, 4, 7, 9]).unwrap();
+        assert_eq!(Chord { scale: pentatonic, ..chord("Cmaj", vec![0, 2, 4]) }.analyze(), None);
+    }
+
+    #[test]
+    fn can_identify_chords() {
+        let notes = |names: &[&str]| -> Vec<Note> {
+            names.iter().map(|s| str::parse::<NamedNote>(s).unwrap().to_note()).collect()
+        };
+
+        assert_eq!(identify_chord(&notes(&["C4", "E4", "G4"])), Some("C".to_string()));
+        assert_eq!(identify_chord(&notes(&["D3", "F#3", "A3", "C4"])), Some("D7".to_string()));
+        assert_eq!(identify_chord(&notes(&["E3", "G3", "C4"])), Some("C/E".to_string()));
+        assert_eq!(identify_chord(&notes(&["C4", "E4", "G4", "B4"])), Some("Cmaj7".to_string()));
+        assert_eq!(identify_chord(&notes(&["C4", "C#4", "D4"])), None);
+    }
+
+    #[test]
+    fn can_drop_voicings() {
+        let c_major_seventh = [0, 2, 4, 6];
+        assert_eq!(drop_voicing(&c_major_seventh, 7, DropKind::Drop2), Ok(vec![-3, 0, 2, 6]));
+        assert_eq!(drop_voicing(&c_major_seventh, 7, DropKind::Drop3), Ok(vec![-5, 0, 4, 6]));
+        assert_eq!(drop_voicing(&[0, 2], 7, DropKind::Drop3), Ok(vec![0, 2]));
+        assert_eq!(
+            drop_voicing(&[-125, -124, -123, -122], 7, DropKind::Drop2),
+            Err("Cannot drop position -123 by an octave of 7 notes!".to_string())
+        );
+        assert!(drop_voicing(&c_major_seventh, 128, DropKind::Drop2).is_err());
+
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+        let drop_2 = drop_voicing(&c_major_seventh, 7, DropKind::Drop2).unwrap();
+        assert_eq!(
+            c_major_scale.get_note(drop_2[0], 4).0,
+            c_major_scale.get_note(4, 4).0 - 12
+        );
+    }
+
+    #[test]
+    fn can_voice_lead() {
+        let notes = |names: &[&str]| -> Vec<Note> {
+            names.iter().map(|s| str::parse::<NamedNote>(s).unwrap().to_note()).collect()
+        };
+        let keys = |names: &[&str]| -> Vec<Key> {
+            names.iter().map(|s| str::parse::<NamedKey>(s).unwrap().to_key()).collect()
+        };
+
+        // C stays, E and G move up a step to F and A.
+        let c_major = notes(&["C4", "E4", "G4"]);
+        assert_eq!(voice_lead(&c_major, &keys(&["F", "A", "C"])), notes(&["C4", "F4", "A4"]));
+
+        // Four voices onto a triad double a key, two voices onto a triad add the third one.
+        let c_major_seventh = notes(&["C4", "E4", "G4", "B4"]);
+        assert_eq!(voice_lead(&c_major_seventh, &keys(&["F", "A", "C"])).len(), 4);
+        assert_eq!(
+            voice_lead(&notes(&["C4", "A4"]), &keys(&["F", "A", "C"])),
+            notes(&["C4", "F4", "A4"])
+        );
     }
 }
\ No newline at end of file
@@ -0,0 +1,299 @@
+// This module provides the following struct:
+// DrumPattern: a percussion track written as step sequences, one row per instrument,
+// e.g. "x...x...x...x..." for a kick drum on every beat.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use midly::{MetaMessage, MidiMessage, TrackEvent, TrackEventKind};
+
+use super::groove::Groove;
+use super::key::{BaseKey, KeyModifier, NamedKey};
+use super::scale::Scale;
+use super::track::{get_rhythm_end, snap_rhythm, Track, MAX_TICK, TICKS_PER_BEAT};
+
+/// The General MIDI percussion keys of the instruments drum patterns can name.
+pub const GM_DRUM_KEYS: [(&str, u8); 16] = [
+    ("kick", 36),
+    ("rim", 37),
+    ("snare", 38),
+    ("clap", 39),
+    ("low_tom", 41),
+    ("hihat", 42),
+    ("pedal_hihat", 44),
+    ("mid_tom", 45),
+    ("open_hihat", 46),
+    ("high_tom", 48),
+    ("crash", 49),
+    ("ride", 51),
+    ("tambourine", 54),
+    ("cowbell", 56),
+    ("shaker", 70),
+    ("woodblock", 76),
+];
+
+/// The length of a step of a drum pattern: a sixteenth note.
+pub const DRUM_STEP_TICKS: u32 = TICKS_PER_BEAT / 4;
+
+/// The velocity of accented hits, written with a capital X.
+pub const ACCENT_VELOCITY: u8 = 127;
+
+/// The General MIDI percussion channel, which drum patterns claim unless told otherwise.
+pub const PERCUSSION_CHANNEL: u8 = 9;
+
+/// What happens on a step of a drum pattern.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DrumHit {
+    Rest,
+    Hit,
+    Accent,
+}
+
+/// A percussion track: each row plays one instrument, given by its General MIDI key,
+/// with its own rhythm of hits and rests, as (hit, duration).
+#[derive(Clone)]
+pub struct DrumPattern {
+    pub id: String,
+    /// The tick at which the pattern starts.
+    pub start_tick: u32,
+    pub rows: Vec<(u8, Vec<(DrumHit, u32)>)>,
+    /// Leave the pattern out when writing MIDI.
+    pub muted: bool,
+    /// Only write soloed tracks to MIDI, if any track is soloed.
+    pub solo: bool,
+    /// The MIDI channel of the pattern, the percussion channel by default.
+    pub channel: Option<u8>,
+    /// Keys are the positions of a chromatic scale starting at C-1.
+    scale: Scale,
+}
+
+impl DrumPattern {
+    pub fn new(id: String, start_tick: u32, rows: Vec<(u8, Vec<(DrumHit, u32)>)>) -> Self {
+        let c = NamedKey::new(BaseKey::C, KeyModifier::Natural);
+        Self {
+            id,
+            start_tick,
+            rows,
+            muted: false,
+            solo: false,
+            channel: Some(PERCUSSION_CHANNEL),
+            scale: Scale::chromatic(c),
+        }
+    }
+    /// Create a pattern from step sequences, as (instrument, steps). Instruments are
+    /// named as in `GM_DRUM_KEYS`, and each step lasts a sixteenth note: "x" is a hit,
+    /// "X" an accented hit and "." a rest. Bar lines ("|") and spaces are ignored.
+    ///
+    /// # Errors
+    /// - if an instrument is unknown;
+    /// - if a step is not one of "x", "X" or ".".
+    pub fn from_steps(id: String, start_tick: u32, rows: &[(&str, &str)]) -> Result<Self, String> {
+        let rows = rows
+            .iter()
+            .map(|(instrument, steps)| Ok((get_drum_key(instrument)?, parse_steps(steps)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self::new(id, start_tick, rows))
+    }
+}
+
+/// The General MIDI key of the instrument called `name` in `GM_DRUM_KEYS`.
+///
+/// # Errors
+/// - if there is no such instrument.
+pub fn get_drum_key(name: &str) -> Result<u8, String> {
+    GM_DRUM_KEYS
+        .iter()
+        .find(|(instrument, _)| *instrument == name)
+        .map(|(_, key)| *key)
+        .ok_or_else(|| format!("Unknown drum {}!", name))
+}
+
+/// Read a step sequence as a rhythm of hits lasting a step, and of rests lasting
+/// until the next hit.
+fn parse_steps(steps: &str) -> Result<Vec<(DrumHit, u32)>, String> {
+    let mut rhythm: Vec<(DrumHit, u32)> = Vec::new();
+    for step in steps.chars().filter(|step| *step != '|' && !step.is_whitespace()) {
+        let hit = match step {
+            'x' => DrumHit::Hit,
+            'X' => DrumHit::Accent,
+            '.' => DrumHit::Rest,
+            _ => return Err(format!("Invalid drum step {}!", step)),
+        };
+        match rhythm.last_mut() {
+            Some((DrumHit::Rest, duration)) if hit == DrumHit::Rest => *duration += DRUM_STEP_TICKS,
+            _ => rhythm.push((hit, DRUM_STEP_TICKS)),
+        }
+    }
+    Ok(rhythm)
+}
+
+impl Track for DrumPattern {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+    fn get_start_tick(&self) -> &u32 {
+        &self.start_tick
+    }
+    fn set_start_tick(&mut self, start_tick: u32) {
+        self.start_tick = start_tick;
+    }
+    fn clone_box(&self) -> Box<dyn Track> {
+        Box::new(self.clone())
+    }
+    fn is_muted(&self) -> bool {
+        self.muted
+    }
+    fn is_solo(&self) -> bool {
+        self.solo
+    }
+    fn get_channel(&self) -> Option<u8> {
+        self.channel
+    }
+    fn get_scale(&self) -> &Scale {
+        &self.scale
+    }
+    fn total_ticks(&self) -> u32 {
+        let rhythm_end = |rhythm: &[(DrumHit, u32)]| {
+            get_rhythm_end(self.start_tick, rhythm.iter().map(|(_, duration)| *duration)).unwrap_or(MAX_TICK)
+        };
+        self.rows.iter().map(|(_, rhythm)| rhythm_end(rhythm)).max().unwrap_or(self.start_tick)
+    }
+    fn describe(&self) -> String {
+        format!("drums {}", self.id)
+    }
+    fn check_midi(&self) -> Result<(), String> {
+        let fits = self
+            .rows
+            .iter()
+            .all(|(_, rhythm)| get_rhythm_end(self.start_tick, rhythm.iter().map(|(_, duration)| *duration)).is_some());
+        if !fits {
+            return Err(format!("Drum pattern {} goes past tick {}, the last a MIDI file can hold!", self.id, MAX_TICK));
+        }
+        Ok(())
+    }
+    /// Each row is snapped on its own.
+    fn snap_durations(&mut self) {
+        for (_, rhythm) in self.rows.iter_mut() {
+            let played: Vec<(bool, u32)> = rhythm.iter().map(|(hit, duration)| (*hit != DrumHit::Rest, *duration)).collect();
+            *rhythm = snap_rhythm(&played)
+                .into_iter()
+                .map(|(index, _, duration)| (index.map_or(DrumHit::Rest, |index| rhythm[index].0), duration))
+                .collect();
+        }
+    }
+    /// Drum patterns are only moved in time: the velocity of their hits doesn't change.
+    /// Like chords whose tones have rhythms of their own, drum patterns keep their
+    /// start, and their hits don't move before it.
+    fn apply_groove(&mut self, groove: &Groove, _velocity: u8) {
+        for (_, rhythm) in self.rows.iter_mut() {
+            // A leading rest of no length lets the first hit move later, but not earlier.
+            let mut padded_rhythm = vec![(DrumHit::Rest, 0)];
+            padded_rhythm.extend(rhythm.iter().copied());
+            let played: Vec<(bool, u32)> =
+                padded_rhythm.iter().map(|(hit, duration)| (*hit != DrumHit::Rest, *duration)).collect();
+            let (_, durations, _) = groove.apply(self.start_tick, &played);
+            for ((_, duration), new_duration) in padded_rhythm.iter_mut().zip(durations) {
+                *duration = new_duration;
+            }
+            if padded_rhythm[0].1 == 0 {
+                padded_rhythm.remove(0);
+            }
+            *rhythm = padded_rhythm;
+        }
+    }
+    #[cfg(feature = "std")]
+    fn to_json(&self) -> Result<serde_json::Value, String> {
+        super::json_output::drum_pattern_to_json(self)
+    }
+    fn to_midi(&self, instrument: u8, channel: u8, velocity: u8) -> Vec<TrackEvent<'_>> {
+        let mut timed_events: Vec<(u32, bool, MidiMessage)> = Vec::new();
+        for (key, rhythm) in self.rows.iter() {
+            let mut tick = self.start_tick;
+            for (hit, duration) in rhythm.iter() {
+                let vel = match hit {
+                    DrumHit::Rest => None,
+                    DrumHit::Hit => Some(velocity),
+                    DrumHit::Accent => Some(ACCENT_VELOCITY),
+                };
+                if let Some(vel) = vel {
+                    timed_events.push((tick, true, MidiMessage::NoteOn { key: (*key).into(), vel: vel.into() }));
+                    timed_events.push((tick + duration, false, MidiMessage::NoteOff { key: (*key).into(), vel: 127.into() }));
+                }
+                tick += duration;
+            }
+        }
+        // Releases come first among simultaneous events, and the sort keeps the order of the rows.
+        timed_events.sort_by_key(|(tick, is_note_on, _)| (*tick, *is_note_on));
+
+        let mut track_events = vec![TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel: channel.into(),
+                message: MidiMessage::ProgramChange { program: instrument.into() },
+            },
+        }];
+        let mut previous_tick = 0;
+        for (tick, _, message) in timed_events {
+            track_events.push(TrackEvent {
+                delta: (tick - previous_tick).into(),
+                kind: TrackEventKind::Midi { channel: channel.into(), message },
+            });
+            previous_tick = tick;
+        }
+        track_events.push(TrackEvent {
+            delta: (self.total_ticks() - previous_tick).into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        track_events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn can_play_drum_patterns() {
+        let pattern = DrumPattern::from_steps(
+            "beat".to_string(),
+            0,
+            &[("kick", "x...x...x...x...|x...x...x...x..."), ("snare", "....X.......x...")],
+        )
+        .unwrap();
+        assert_eq!(pattern.total_ticks(), 8 * TICKS_PER_BEAT);
+        assert_eq!(pattern.get_channel(), Some(PERCUSSION_CHANNEL));
+
+        let mut tick = 0;
+        let note_ons: Vec<(u32, u8, u8)> = pattern
+            .to_midi(0, PERCUSSION_CHANNEL, 100)
+            .iter()
+            .filter_map(|event| {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } => {
+                        Some((tick, key.as_int(), vel.as_int()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        // The kick plays on every beat, four times a bar.
+        let kicks: Vec<u32> = note_ons.iter().filter(|(_, key, _)| *key == 36).map(|(tick, _, _)| *tick).collect();
+        assert_eq!(kicks, (0..8).map(|beat| beat * TICKS_PER_BEAT).collect::<Vec<u32>>());
+        assert_eq!(kicks.iter().filter(|tick| **tick < 4 * TICKS_PER_BEAT).count(), 4);
+        // The snare on beat 2 is accented.
+        let snares: Vec<(u32, u8, u8)> = note_ons.into_iter().filter(|(_, key, _)| *key == 38).collect();
+        assert_eq!(snares, [(TICKS_PER_BEAT, 38, ACCENT_VELOCITY), (3 * TICKS_PER_BEAT, 38, 100)]);
+        assert_eq!(tick, 8 * TICKS_PER_BEAT);
+
+        assert_eq!(
+            DrumPattern::from_steps("beat".to_string(), 0, &[("cymbal", "x")]).err(),
+            Some("Unknown drum cymbal!".to_string())
+        );
+        assert!(DrumPattern::from_steps("beat".to_string(), 0, &[("kick", "x-x")]).is_err());
+    }
+}
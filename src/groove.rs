@@ -0,0 +1,144 @@
+// This module provides the following struct:
+// Groove: how the notes starting on each subdivision of the beat are nudged, e.g. to swing.
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::track::TICKS_PER_BEAT;
+
+/// A groove template, applied to every track of a piece at once. Subdivisions are
+/// counted from the start of the piece, and the notes starting on the n-th one are
+/// moved by the n-th step (cycling through the steps) of (tick offset, velocity change).
+#[derive(Clone, PartialEq, Debug)]
+pub struct Groove {
+    subdivision_ticks: u32,
+    steps: Vec<(i32, i8)>,
+}
+
+impl Groove {
+    /// Create a groove nudging the notes on each subdivision of `subdivision_ticks` ticks
+    /// by the steps in turn.
+    ///
+    /// # Errors
+    /// - if the subdivision is 0 ticks;
+    /// - if there are no steps.
+    pub fn new(subdivision_ticks: u32, steps: Vec<(i32, i8)>) -> Result<Self, String> {
+        if subdivision_ticks == 0 {
+            return Err("A groove subdivision should last at least one tick!".to_string());
+        }
+        if steps.is_empty() {
+            return Err("A groove needs at least one step!".to_string());
+        }
+        Ok(Self { subdivision_ticks, steps })
+    }
+    /// Swung eighths: the second eighth of each beat starts a triplet eighth late, so
+    /// that eighths are played long-short, and is played a little softer.
+    pub fn swing_8ths() -> Self {
+        Self::new(TICKS_PER_BEAT / 2, vec![(0, 0), (TICKS_PER_BEAT as i32 / 6, -10)]).unwrap()
+    }
+    /// The step for a note starting at `tick`, or no change if it doesn't start on a
+    /// subdivision.
+    fn get_step(&self, tick: u32) -> (i32, i8) {
+        if !tick.is_multiple_of(self.subdivision_ticks) {
+            return (0, 0);
+        }
+        let index = usize::try_from(tick / self.subdivision_ticks).unwrap() % self.steps.len();
+        self.steps[index]
+    }
+    /// Apply the groove to a track starting at `start` with the given notes, as
+    /// (played, duration). Only played notes are moved: the note or rest before them
+    /// gets longer or shorter to make room, and the end of the track doesn't move. A
+    /// note isn't moved if it would start before the note before it or at its own end.
+    ///
+    /// Returns the new start, the new durations, and the velocity change of each note.
+    pub(crate) fn apply(&self, start: u32, notes: &[(bool, u32)]) -> (u32, Vec<u32>, Vec<i8>) {
+        let mut onsets = Vec::with_capacity(notes.len());
+        let mut velocity_changes = Vec::with_capacity(notes.len());
+        let mut tick = start;
+        for (played, duration) in notes.iter() {
+            let (offset, velocity_change) = if *played { self.get_step(tick) } else { (0, 0) };
+            let nudged_tick = i64::from(tick) + i64::from(offset);
+            let earliest_tick = onsets.last().map_or(0, |onset| i64::from(*onset) + 1);
+            let is_nudged = offset != 0 && nudged_tick >= earliest_tick && nudged_tick < i64::from(tick + duration);
+            onsets.push(if is_nudged { u32::try_from(nudged_tick).unwrap() } else { tick });
+            velocity_changes.push(velocity_change);
+            tick += duration;
+        }
+
+        let new_start = onsets.first().copied().unwrap_or(start);
+        let durations = onsets
+            .iter()
+            .zip(onsets.iter().skip(1).chain([&tick]))
+            .map(|(onset, next_onset)| next_onset - onset)
+            .collect();
+        (new_start, durations, velocity_changes)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{Chord, Piece, Scale, TimedNote, Track, Voice};
+
+    #[test]
+    fn can_swing_eighths() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let eighths = Voice::new(
+            "eighths".to_string(),
+            c_major_scale.clone(),
+            4,
+            0,
+            (0..4).map(|position| TimedNote::new(Some(position), TICKS_PER_BEAT / 2)).collect(),
+        );
+        let quarters = Voice::new(
+            "quarters".to_string(),
+            c_major_scale.clone(),
+            3,
+            0,
+            (0..2).map(|position| TimedNote::new(Some(position), TICKS_PER_BEAT)).collect(),
+        );
+        let chord = |notes: Vec<(bool, u32)>| Chord::new(
+            "chord".to_string(),
+            c_major_scale.clone(),
+            3,
+            TICKS_PER_BEAT,
+            vec![0, 2, 4],
+            notes,
+        );
+        let tracks: Vec<Box<dyn Track>> =
+            vec![Box::new(eighths), Box::new(quarters), Box::new(chord(vec![(true, 12), (true, 12)]))];
+        let mut piece = Piece::new(120, tracks).unwrap();
+
+        piece.apply_groove(&Groove::swing_8ths());
+
+        let mut expected_eighths = Voice::new(
+            "eighths".to_string(),
+            c_major_scale.clone(),
+            4,
+            0,
+            [(0, 16), (1, 8), (2, 16), (3, 8)]
+                .into_iter()
+                .map(|(position, duration)| TimedNote::new(Some(position), duration))
+                .collect(),
+        );
+        expected_eighths.notes[1].velocity = Some(90);
+        expected_eighths.notes[3].velocity = Some(90);
+        assert_eq!(piece.tracks[0].to_midi(1, 0, 100), expected_eighths.to_midi(1, 0, 100));
+        assert_eq!(piece.tracks[2].to_midi(1, 1, 100), chord(vec![(true, 16), (true, 8)]).to_midi(1, 1, 100));
+        // Notes on the beat don't move.
+        assert_eq!(piece.tracks[1].total_ticks(), 2 * TICKS_PER_BEAT);
+    }
+
+    #[test]
+    fn can_create_grooves() {
+        assert!(Groove::new(0, vec![(0, 0)]).is_err());
+        assert!(Groove::new(12, vec![]).is_err());
+
+        // A note isn't moved past the end of its own duration.
+        let groove = Groove::new(12, vec![(0, 0), (12, 0)]).unwrap();
+        assert_eq!(groove.apply(0, &[(true, 12), (true, 12)]), (0, vec![12, 12], vec![0, 0]));
+        // Rests are not moved, and the note after them is.
+        assert_eq!(groove.apply(0, &[(false, 12), (true, 24)]), (0, vec![24, 12], vec![0, 0]));
+    }
+}
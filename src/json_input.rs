@@ -1,120 +1,500 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
 use indexmap::IndexMap;
 
 use regex::Regex;
-use serde_json::{Value, Map};
+use serde_json::{Map, Number, Value};
 
-use super::track::{Track, TimedNote, TICKS_PER_BEAT};
+use super::key::{BaseKey, KeyModifier, NamedKey, NamedNote};
+use super::track::{Track, TimedNote, DEFAULT_VELOCITY, TICKS_PER_BEAT};
 use super::chord::Chord;
+use super::drums::DrumPattern;
+use super::scale::ScaleRegistry;
 use super::{Scale, Piece, Voice};
 
 // This is the definition of the JSON data format we are using.
 //
-// Piece  = [ Track* ]
-// Track  = { "id": String, "scale": string, "bpm": int, "start": Start, "notes": Notes }
-// Start  = int | { String: offset<int> }
-// Notes  = [ Note | { duration<int>: Notes } | Notes ]
-// Note   = null | int
+// Piece  = { "bpm": int | "tempo": Tempo, "tracks": [ Track* ], "title"?: String, "copyright"?: String,
+//            "text"?: String, "default_velocity"?: int, "anacrusis_ticks"?: int }
+// Tempo  = { "unit": String, "bpm": int }   (how many of the note value `unit` are played a minute,
+//          e.g. {"unit": "dotted_quarter", "bpm": 60}; units are named in `TEMPO_UNITS`)
+// Track  = { "id": String, "scale": Scale, "octave": int, "start": Start, "notes": Notes, "lyrics"?: [ String* ],
+//            "default_duration"?: DefaultDuration, Flags }
+//        | { "id": String, "start": Start, "notes": NoteDsl, "lyrics"?: [ String* ],
+//            "default_duration"?: DefaultDuration, Flags }
+//        | { "id": String, "type": "drums", "start": Start, "pattern": { instrument<String>: Steps }, Flags }
+//        | { "include": path<String> }
+// DefaultDuration = beats<number> | duration<String>   (what the notes of a voice last unless
+//                   their durations say otherwise, e.g. 0.5 or "1/2"; a beat by default)
+// Flags  = "muted"?: bool, "solo"?: bool, "channel"?: int
+//          (muted tracks, and non-soloed tracks when any is soloed, are not written; tracks without
+//          a channel between 0 and 15 get the unclaimed channels in order, except drums, which
+//          play on the percussion channel 9)
+// Steps  = String of sixteenth notes: "x" a hit, "X" an accented hit, "." a rest, e.g. "x...x...",
+//          where bar lines "|" and spaces are ignored (instruments are named in `GM_DRUM_KEYS`)
+// Scale  = name<String> | [ key<String>+ ]   (e.g. "Cmaj", or ["C", "D", "Eb", "F#", "G", "A", "Bb"])
+// Start  = beats<number> | { String: offset<number> }   (beats may be fractional, e.g. 2.5)
+// Notes  = [ Note | { duration<String>: Notes } | Repeat | Tuplet | Measures | Notes ]
+//          (durations are multiples of the current duration, e.g. "2", "1/3" or "/2", optionally
+//          dotted: "1." is 1.5 times the current duration and "1.." 1.75 times)
+// Repeat = { "repeat": count<int>, "notes": Notes }
+// Tuplet = { "tuplet": [count<int>, time<int>], "notes": Notes }   (count notes in the time of `time`)
+// Measures = { "measures": [ Notes* ], "time_signature"?: [beats<int>, beat_unit<int>] }
+//            (each element must fill exactly one bar, 4/4 by default)
+// Note   = int | Rest
+// Rest   = null | false | "" | "rest" | "r"
+// NoteDsl = non-empty String of note names, see `parse_note_dsl`
+//
+// Keys of pieces and tracks that are not part of the format are ignored, except by
+// `parse_piece_strict`.
 
 pub fn parse_piece(json_str: &str) -> Result<Piece, String> {
+    parse_piece_with_scales(json_str, &ScaleRegistry::default())
+}
+
+/// Parse a piece whose scales may use the custom names registered in `scales`.
+pub fn parse_piece_with_scales(json_str: &str, scales: &ScaleRegistry) -> Result<Piece, String> {
+    let json: Value =
+        serde_json::from_str(json_str).map_err(|_| "Could not parse JSON!".to_string())?;
+    parse_piece_in_dir(&json, Path::new(""), scales, false)
+}
+
+/// Parse a piece like `parse_piece`, but reject keys of the piece or of its tracks that
+/// are not part of the format, e.g. misspelled ones, rather than ignoring them.
+pub fn parse_piece_strict(json_str: &str) -> Result<Piece, String> {
+    parse_piece_strict_with_scales(json_str, &ScaleRegistry::default())
+}
+
+/// Parse a piece strictly like `parse_piece_strict`, with the custom scale names
+/// registered in `scales`.
+pub fn parse_piece_strict_with_scales(json_str: &str, scales: &ScaleRegistry) -> Result<Piece, String> {
     let json: Value =
-        serde_json::from_str(json_str).or_else(|_| Err("Could not parse JSON!".to_string()))?;
+        serde_json::from_str(json_str).map_err(|_| "Could not parse JSON!".to_string())?;
+    parse_piece_in_dir(&json, Path::new(""), scales, true)
+}
+
+/// Parse a piece directly from a reader, without loading its contents into a string first.
+pub fn parse_piece_reader<R: Read>(reader: R) -> Result<Piece, String> {
+    let json: Value =
+        serde_json::from_reader(reader).map_err(|_| "Could not parse JSON!".to_string())?;
+    parse_piece_in_dir(&json, Path::new(""), &ScaleRegistry::default(), false)
+}
+
+/// Parse the piece stored at the given path. Included tracks are resolved
+/// relative to the directory of that file.
+pub fn parse_piece_from_path<P: AsRef<Path>>(path: P) -> Result<Piece, String> {
+    let path = path.as_ref();
+    let json = read_json_file(path)?;
+    parse_piece_in_dir(&json, path.parent().unwrap_or(Path::new("")), &ScaleRegistry::default(), false)
+}
+
+/// Parse every `*.json` piece in `input_dir` and write it to a MIDI file with the same
+/// name in `output_dir`. A piece that can't be parsed or written doesn't stop the
+/// others: the errors are returned, one per failed piece, prefixed with its path.
+///
+/// # Errors
+/// - if `input_dir` can't be read;
+/// - if `output_dir` can't be created.
+pub fn render_dir<P: AsRef<Path>, Q: AsRef<Path>>(input_dir: P, output_dir: Q) -> Result<Vec<String>, String> {
+    let (input_dir, output_dir) = (input_dir.as_ref(), output_dir.as_ref());
+    let entries = fs::read_dir(input_dir)
+        .map_err(|err| format!("Could not read {}: {}", input_dir.display(), err))?;
+    fs::create_dir_all(output_dir)
+        .map_err(|err| format!("Could not create {}: {}", output_dir.display(), err))?;
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    paths.sort();
 
+    let mut errors = Vec::new();
+    for path in paths {
+        let output_path = output_dir.join(path.with_extension("mid").file_name().unwrap());
+        let rendered = parse_piece_from_path(&path).and_then(|piece| {
+            let mut file = fs::File::create(&output_path).map_err(|err| err.to_string())?;
+            piece.write_midi(&mut file).map_err(|err| err.to_string())
+        });
+        if let Err(err) = rendered {
+            errors.push(format!("{}: {}", path.display(), err));
+        }
+    }
+    Ok(errors)
+}
+
+fn read_json_file(path: &Path) -> Result<Value, String> {
+    let file = fs::File::open(path)
+        .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|_| format!("Could not parse JSON in {}!", path.display()))
+}
+
+/// The keys of a piece, and of each type of track, in the JSON format.
+const PIECE_KEYS: [&str; 8] =
+    ["bpm", "tempo", "tracks", "title", "copyright", "text", "default_velocity", "anacrusis_ticks"];
+const FLAG_KEYS: [&str; 3] = ["muted", "solo", "channel"];
+const VOICE_KEYS: [&str; 8] = ["id", "type", "scale", "octave", "start", "notes", "lyrics", "default_duration"];
+const CHORD_KEYS: [&str; 7] = ["id", "type", "scale", "octave", "start", "chord", "notes"];
+const DRUMS_KEYS: [&str; 4] = ["id", "type", "start", "pattern"];
+
+/// The note values a tempo can count, and their lengths in ticks.
+pub(crate) const TEMPO_UNITS: [(&str, u32); 7] = [
+    ("whole", 4 * TICKS_PER_BEAT),
+    ("dotted_half", 3 * TICKS_PER_BEAT),
+    ("half", 2 * TICKS_PER_BEAT),
+    ("dotted_quarter", 3 * TICKS_PER_BEAT / 2),
+    ("quarter", TICKS_PER_BEAT),
+    ("dotted_eighth", 3 * TICKS_PER_BEAT / 4),
+    ("eighth", TICKS_PER_BEAT / 2),
+];
+
+/// Check that every key of `object` is one of `known_keys`.
+///
+/// # Errors
+/// - naming the first unknown key and what it was found in.
+fn check_keys(object: &Map<String, Value>, known_keys: &[&str], found_in: &str) -> Result<(), String> {
+    match object.keys().find(|key| !known_keys.contains(&key.as_str())) {
+        Some(key) => Err(format!("Unknown key {} in {}!", key, found_in)),
+        None => Ok(()),
+    }
+}
+
+fn parse_piece_in_dir(json: &Value, base_dir: &Path, scales: &ScaleRegistry, strict: bool) -> Result<Piece, String> {
     let piece_json = json
         .as_object()
-        .ok_or_else(|| "JSON should be an object!")?;
+        .ok_or("JSON should be an object!")?;
+    if strict {
+        check_keys(piece_json, &PIECE_KEYS, "piece")?;
+    }
 
-    let bpm = piece_json.get("bpm").ok_or_else(|| "bpm missing!")?;
-    let bpm = bpm.as_u64().ok_or_else(|| "bpm must be uint!")?;
-    let bpm = u8::try_from(bpm).map_err(|_| "Could not cast bpm to u8!")?;
+    let (bpm, tempo_unit_ticks) = match (piece_json.get("bpm"), piece_json.get("tempo")) {
+        (Some(bpm), None) => (parse_bpm(bpm)?, TICKS_PER_BEAT),
+        (None, Some(tempo)) => parse_tempo(tempo)?,
+        (Some(_), Some(_)) => return Err("A piece should have either bpm or tempo, not both!".to_string()),
+        (None, None) => return Err("bpm missing!".to_string()),
+    };
 
     let tracks_json = piece_json
         .get("tracks")
-        .ok_or_else(|| "tracks missing!")?
+        .ok_or("tracks missing!")?
         .as_array()
-        .ok_or_else(|| "tracks should be an array!")?;
+        .ok_or("tracks should be an array!")?;
     let mut tracks_by_id: IndexMap<String, Box<dyn Track>> = IndexMap::new();
 
     for track_json in tracks_json.iter() {
-        let track = parse_track(track_json, &tracks_by_id)?;
+        let track = parse_track(track_json, &tracks_by_id, base_dir, scales, strict)?;
         tracks_by_id.insert(track.get_id().to_string(), track);
     }
     let tracks: Vec<Box<dyn Track>> = tracks_by_id.into_values().collect();
 
-    Ok(Piece { bpm, tracks })
+    let get_optional_string = |key: &str| -> Result<Option<String>, String> {
+        piece_json
+            .get(key)
+            .map(|value| value.as_str().map(str::to_string).ok_or(format!("{} should be string!", key)))
+            .transpose()
+    };
+
+    let default_velocity = match piece_json.get("default_velocity") {
+        None => DEFAULT_VELOCITY,
+        Some(default_velocity) => default_velocity
+            .as_u64()
+            .and_then(|default_velocity| u8::try_from(default_velocity).ok())
+            .filter(|default_velocity| (1..=127).contains(default_velocity))
+            .ok_or("default_velocity should be an int between 1 and 127!")?,
+    };
+
+    let anacrusis_ticks = match piece_json.get("anacrusis_ticks") {
+        None => 0,
+        Some(anacrusis_ticks) => anacrusis_ticks
+            .as_u64()
+            .and_then(|anacrusis_ticks| u32::try_from(anacrusis_ticks).ok())
+            .ok_or("anacrusis_ticks should be uint!")?,
+    };
+
+    Ok(Piece {
+        bpm,
+        tempo_unit_ticks,
+        tracks,
+        title: get_optional_string("title")?,
+        copyright: get_optional_string("copyright")?,
+        text: get_optional_string("text")?,
+        default_velocity,
+        anacrusis_ticks,
+        ..Default::default()
+    })
+}
+
+fn parse_bpm(bpm: &Value) -> Result<u8, String> {
+    let bpm = bpm.as_u64().ok_or("bpm must be uint!")?;
+    u8::try_from(bpm).map_err(|_| "Could not cast bpm to u8!".to_string())
+}
+
+/// Parse a tempo counting a note value, as (bpm, length of the note value in ticks).
+///
+/// # Errors
+/// - if the unit is missing or not one of `TEMPO_UNITS`;
+/// - if the bpm is missing or doesn't fit in a u8.
+fn parse_tempo(tempo: &Value) -> Result<(u8, u32), String> {
+    let tempo = tempo.as_object().ok_or("tempo should be an object!")?;
+    let unit = tempo
+        .get("unit")
+        .ok_or("tempo unit missing!")?
+        .as_str()
+        .ok_or("tempo unit should be string!")?;
+    let unit_ticks = TEMPO_UNITS
+        .iter()
+        .find(|(name, _)| *name == unit)
+        .map(|(_, ticks)| *ticks)
+        .ok_or_else(|| format!("Unknown tempo unit {}!", unit))?;
+    let bpm = parse_bpm(tempo.get("bpm").ok_or("tempo bpm missing!")?)?;
+    Ok((bpm, unit_ticks))
 }
 
 fn parse_track(
     track_json: &Value,
-    tracks_by_id: &IndexMap<String, Box<dyn Track>>
+    tracks_by_id: &IndexMap<String, Box<dyn Track>>,
+    base_dir: &Path,
+    scales: &ScaleRegistry,
+    strict: bool,
 ) -> Result<Box<dyn Track>, String> {
     let track_json = track_json
         .as_object()
-        .ok_or_else(|| "Each track should be a JSON object!")?;
+        .ok_or("Each track should be a JSON object!")?;
+
+    if let Some(include) = track_json.get("include") {
+        if strict {
+            check_keys(track_json, &["include"], "included track")?;
+        }
+        let include = include.as_str().ok_or("include should be string!")?;
+        return parse_included_track(&base_dir.join(include), tracks_by_id, scales, strict);
+    }
 
     let track_type = track_json.get("type")
-        .ok_or_else(|| "type missing!")?
+        .ok_or("type missing!")?
         .as_str()
-        .ok_or_else(|| "type shoudl be string!")?
+        .ok_or("type shoudl be string!")?
         .to_string();
 
+    if strict {
+        let type_keys: &[&str] = match track_type.as_str() {
+            "voice" => &VOICE_KEYS,
+            "chord" => &CHORD_KEYS,
+            "drums" => &DRUMS_KEYS,
+            _ => return Err("Invalid track type!".to_string()),
+        };
+        let known_keys: Vec<&str> = type_keys.iter().chain(FLAG_KEYS.iter()).copied().collect();
+        let id = track_json.get("id").and_then(Value::as_str).unwrap_or("without id");
+        check_keys(track_json, &known_keys, &format!("track {}", id))?;
+    }
+
     match track_type.as_str() {
-        "voice" => parse_voice(track_json, tracks_by_id).map(|voice| Box::new(voice) as Box<dyn Track>),
-        "chord" => parse_chord(track_json, tracks_by_id).map(|voice| Box::new(voice) as Box<dyn Track>),
+        "voice" => parse_voice(track_json, tracks_by_id, scales).map(|voice| Box::new(voice) as Box<dyn Track>),
+        "chord" => parse_chord(track_json, tracks_by_id, scales).map(|voice| Box::new(voice) as Box<dyn Track>),
+        "drums" => parse_drum_pattern(track_json, tracks_by_id).map(|drums| Box::new(drums) as Box<dyn Track>),
         _ => Err("Invalid track type!".to_string()),
     }
 }
 
+/// Parse a track stored in its own file. Includes inside that file are
+/// resolved relative to its own directory.
+fn parse_included_track(
+    path: &Path,
+    tracks_by_id: &IndexMap<String, Box<dyn Track>>,
+    scales: &ScaleRegistry,
+    strict: bool,
+) -> Result<Box<dyn Track>, String> {
+    let track_json = read_json_file(path)?;
+    parse_track(&track_json, tracks_by_id, path.parent().unwrap_or(Path::new("")), scales, strict)
+}
+
 fn parse_voice(
     voice_json: &Map<String, Value>,
     tracks_by_id: &IndexMap<String, Box<dyn Track>>,
+    scales: &ScaleRegistry,
 ) -> Result<Voice, String> {
 
     let id = voice_json
         .get("id")
-        .ok_or_else(|| "id missing!")?
+        .ok_or("id missing!")?
         .as_str()
-        .ok_or_else(|| "id should be string!")?
+        .ok_or("id should be string!")?
         .to_string();
 
-    let scale = voice_json
-        .get("scale")
-        .ok_or_else(|| "scale missing!")?
-        .as_str()
-        .ok_or_else(|| "scale should be string!")?;
-    let scale = str::parse::<Scale>(scale)?;
+    let start = voice_json.get("start").ok_or("start missing!")?;
+    let start = parse_track_start(start, tracks_by_id)?;
+
+    let notes = voice_json.get("notes").ok_or("notes missing!")?;
+    let default_duration = parse_default_duration(voice_json)?;
+
+    // Note names are resolved to their MIDI keys, which are the positions of a
+    // chromatic scale starting at C-1: the voice's own scale and octave are not needed.
+    if let Some(note_dsl) = notes.as_str().filter(|note_dsl| !note_dsl.is_empty()) {
+        let c = NamedKey::new(BaseKey::C, KeyModifier::Natural);
+        let mut voice =
+            Voice::new(id, Scale::chromatic(c), -1, start, parse_note_dsl(note_dsl, default_duration)?);
+        if let Some(lyrics) = voice_json.get("lyrics") {
+            voice.lyrics = parse_lyrics(lyrics)?;
+        }
+        voice.muted = parse_flag(voice_json, "muted")?;
+        voice.solo = parse_flag(voice_json, "solo")?;
+        voice.channel = parse_channel(voice_json)?;
+        return Ok(voice);
+    }
+
+    let scale = parse_scale(voice_json.get("scale").ok_or("scale missing!")?, scales)?;
 
     let octave = voice_json
         .get("octave")
-        .ok_or_else(|| "octave missing!")?
+        .ok_or("octave missing!")?
         .as_i64()
-        .ok_or_else(|| "octave should be int!")?;
+        .ok_or("octave should be int!")?;
     let octave = i8::try_from(octave).map_err(|_| "Could not convert octave to i8!")?;
 
-    let start = voice_json.get("start").ok_or_else(|| "start missing!")?;
-    let start = parse_track_start(start, tracks_by_id)?;
+    let notes = parse_voice_notes_lasting(notes, default_duration)?;
+    check_positions_in_range(&scale, octave, notes.iter().filter_map(|timed_note| timed_note.position))?;
 
-    let notes = voice_json.get("notes").ok_or_else(|| "notes missing!")?;
-    let notes = parse_voice_notes(notes, &scale, octave)?;
+    let mut voice = Voice::new(id, scale, octave, start, notes);
+    if let Some(lyrics) = voice_json.get("lyrics") {
+        voice.lyrics = parse_lyrics(lyrics)?;
+    }
+    voice.muted = parse_flag(voice_json, "muted")?;
+    voice.solo = parse_flag(voice_json, "solo")?;
+    voice.channel = parse_channel(voice_json)?;
+    Ok(voice)
+}
 
-    Ok(Voice {
-        id,
-        scale,
-        octave,
-        start,
-        notes,
-    })
+/// Parse a scale, given by name (e.g. "Cmaj") or as the list of its key names.
+fn parse_scale(scale_json: &Value, scales: &ScaleRegistry) -> Result<Scale, String> {
+    match scale_json {
+        Value::String(scale) => scales.parse(scale),
+        Value::Array(keys) => {
+            let keys = keys
+                .iter()
+                .map(|key| {
+                    key.as_str()
+                        .ok_or("each key of a scale should be string!".to_string())
+                        .and_then(str::parse::<NamedKey>)
+                })
+                .collect::<Result<Vec<NamedKey>, String>>()?;
+            Scale::from_named_keys(keys)
+        }
+        _ => Err("scale should be string or array of key names!".to_string()),
+    }
+}
+
+/// Parse an optional boolean field of a track, which is false when missing.
+fn parse_flag(track_json: &Map<String, Value>, key: &str) -> Result<bool, String> {
+    track_json
+        .get(key)
+        .map_or(Ok(false), |flag| flag.as_bool().ok_or(format!("{} should be bool!", key)))
+}
+
+/// Parse the optional MIDI channel claimed by a track.
+fn parse_channel(track_json: &Map<String, Value>) -> Result<Option<u8>, String> {
+    track_json
+        .get("channel")
+        .map(|channel| {
+            channel
+                .as_u64()
+                .and_then(|channel| u8::try_from(channel).ok())
+                .filter(|channel| *channel <= 15)
+                .ok_or("channel should be an int between 0 and 15!".to_string())
+        })
+        .transpose()
+}
+
+/// Make sure that the lowest and highest of the given positions resolve to MIDI notes.
+fn check_positions_in_range(
+    scale: &Scale,
+    octave: i8,
+    positions: impl Iterator<Item = i8> + Clone,
+) -> Result<(), String> {
+    for position in [positions.clone().min(), positions.max()].into_iter().flatten() {
+        if scale.try_get_note(position, octave).is_none() {
+            return Err(format!(
+                "Position {} in octave {} is outside the MIDI note range 0..=127!",
+                position, octave
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_lyrics(lyrics_json: &Value) -> Result<Vec<String>, String> {
+    lyrics_json
+        .as_array()
+        .ok_or("lyrics should be an array!")?
+        .iter()
+        .map(|syllable| {
+            syllable
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| "each lyric should be string!".to_string())
+        })
+        .collect()
+}
+
+/// Parse a whitespace-separated list of note names such as `"C4 E4 G4 r/2 A4"`.
+///
+/// Each token is a note name or `r` for a rest, optionally followed by `*n`
+/// and/or `/n` to multiply or divide the default duration. Notes are returned
+/// as their MIDI keys, i.e. positions in a chromatic scale starting at C-1.
+pub fn parse_note_dsl(note_dsl: &str, default_duration: u32) -> Result<Vec<TimedNote>, String> {
+    let token_regex =
+        Regex::new("^(r|[A-G](?:bb|[b♭#♯x𝄪𝄫])?(?:-1|[0-9]))(?:\\*(\\d+))?(?:\\/(\\d+))?$").unwrap();
+
+    let mut notes: Vec<TimedNote> = Vec::new();
+    for token in note_dsl.split_whitespace() {
+        let captures = token_regex
+            .captures(token)
+            .ok_or_else(|| format!("Invalid note: {}", token))?;
+
+        let position = match &captures[1] {
+            "r" => None,
+            note_name => {
+                let note = str::parse::<NamedNote>(note_name)?
+                    .try_to_note()
+                    .ok_or_else(|| format!("Note out of range: {}", token))?;
+                Some(i8::try_from(note.0).map_err(|_| format!("Note out of range: {}", token))?)
+            }
+        };
+
+        let numerator = match captures.get(2) {
+            None => 1,
+            Some(numerator) => str::parse::<u32>(numerator.as_str())
+                .map_err(|_| format!("Invalid duration: {}", token))?,
+        };
+        let denominator = match captures.get(3) {
+            None => 1,
+            Some(denominator) => str::parse::<u32>(denominator.as_str())
+                .ok()
+                .filter(|denominator| *denominator != 0)
+                .ok_or_else(|| format!("Invalid duration: {}", token))?,
+        };
+
+        let duration = default_duration
+            .checked_mul(numerator)
+            .ok_or_else(|| format!("Duration too long: {}", token))?
+            / denominator;
+        if duration == 0 {
+            return Err(format!("Note should last at least one tick: {}", token));
+        }
+        notes.push(TimedNote::new(position, duration));
+    }
+    Ok(notes)
 }
 
+/// Parse a track start, given in beats, into ticks.
 fn parse_track_start(
     track_start_json: &Value,
     tracks_by_id: &IndexMap<String, Box<dyn Track>>,
 ) -> Result<u32, String> {
     match track_start_json {
         Value::Number(start) => {
-            let start = start
-                .as_u64()
-                .ok_or_else(|| "Voice start should be a uint!")?;
-            let start = u32::try_from(start).map_err(|_| "Could not cast track start to u8!")?;
+            let start = parse_beats_as_ticks(start).map_err(|_| "Voice start should be a positive number of beats!")?;
+            let start = u32::try_from(start).map_err(|_| "Voice start should be a positive number of beats!")?;
             Ok(start)
         }
         Value::Object(map_track_start) => {
@@ -122,11 +502,12 @@ fn parse_track_start(
             for (key, value) in map_track_start {
                 let reference_track = tracks_by_id
                     .get(key)
-                    .ok_or_else(|| "Invalid reference track!")?;
+                    .ok_or("Invalid reference track!")?;
                 let offset = value
-                    .as_i64()
-                    .ok_or_else(|| "Offset to reference track must be int!")?;
-                let offset = i64::from(*reference_track.get_start()) + offset;
+                    .as_number()
+                    .ok_or("Offset to reference track must be a number of beats!")
+                    .and_then(|offset| parse_beats_as_ticks(offset))?;
+                let offset = i64::from(*reference_track.get_start_tick()) + offset;
                 let offset = u32::try_from(offset).map_err(|_| "Could not cast start to u32!")?;
                 track_start = Some(offset);
             }
@@ -136,40 +517,79 @@ fn parse_track_start(
                 Err("Empty object!".to_string())
             }
         }
-        _ => Err("start should be int or Json object!".to_string()),
+        _ => Err("start should be a number or Json object!".to_string()),
     }
 }
 
-fn parse_voice_notes(
-    track_notes_json: &Value,
-    scale: &Scale,
-    octave: i8,
-) -> Result<Vec<TimedNote>, String> {
-    // matches e.g. 3, 1/3, /3.
-    let duration_regex = Regex::new("^(\\d+)?(?:\\/(\\d+))?$").unwrap();
-    parse_voice_notes_recursive(track_notes_json, scale, octave, TICKS_PER_BEAT, &duration_regex, false)
+/// Convert a whole or fractional number of beats into ticks.
+///
+/// # Errors
+/// - if the beats don't fall on a whole tick, e.g. a fifth of a beat.
+fn parse_beats_as_ticks(beats: &Number) -> Result<i64, &'static str> {
+    let ticks_per_beat = i64::from(TICKS_PER_BEAT);
+    match beats.as_i64() {
+        Some(beats) => beats.checked_mul(ticks_per_beat).ok_or("Start is too far!"),
+        None => {
+            let ticks = beats.as_f64().ok_or("Start is too far!")? * f64::from(TICKS_PER_BEAT);
+            if ticks.fract() != 0.0 || ticks.abs() > f64::from(u32::MAX) {
+                return Err("Start should fall on a whole tick!");
+            }
+            Ok(ticks as i64)
+        }
+    }
+}
+
+fn get_duration_regex() -> Regex {
+    // matches e.g. 3, 1/3, /3, and dotted durations such as 1. or /2..
+    Regex::new("^(\\d+)?(?:\\/(\\d+))?(\\.{0,2})$").unwrap()
+}
+
+fn parse_voice_notes(track_notes_json: &Value) -> Result<Vec<TimedNote>, String> {
+    parse_voice_notes_lasting(track_notes_json, TICKS_PER_BEAT)
+}
+
+/// Parse notes whose base duration is `default_duration` ticks rather than a beat.
+fn parse_voice_notes_lasting(track_notes_json: &Value, default_duration: u32) -> Result<Vec<TimedNote>, String> {
+    parse_voice_notes_recursive(track_notes_json, default_duration, &get_duration_regex(), false)
+}
+
+/// Parse the optional base duration of the notes of a voice, given in beats (e.g. 0.5)
+/// or as a duration specifier (e.g. "1/2"), which is a beat when missing.
+fn parse_default_duration(voice_json: &Map<String, Value>) -> Result<u32, String> {
+    let default_duration = match voice_json.get("default_duration") {
+        None => TICKS_PER_BEAT,
+        Some(Value::String(key)) => parse_duration(key, TICKS_PER_BEAT, &get_duration_regex())?,
+        Some(Value::Number(beats)) => {
+            let ticks = beats.as_f64().unwrap_or(-1.0) * f64::from(TICKS_PER_BEAT);
+            if ticks.fract() != 0.0 || !(0.0..=f64::from(u32::MAX)).contains(&ticks) {
+                return Err("default_duration should fall on a whole number of ticks!".to_string());
+            }
+            ticks as u32
+        }
+        Some(_) => return Err("default_duration should be a number of beats or a duration string!".to_string()),
+    };
+    if default_duration == 0 {
+        return Err("default_duration should last at least one tick!".to_string());
+    }
+    Ok(default_duration)
 }
 
 fn parse_voice_notes_recursive(
     track_notes_json: &Value,
-    scale: &Scale,
-    octave: i8,
-    duration: u8,
+    duration: u32,
     duration_regex: &Regex,
     halve_array: bool,
 ) -> Result<Vec<TimedNote>, String> {
+    if duration == 0 {
+        return Err("Notes should last at least one tick!".to_string());
+    }
     let mut notes: Vec<TimedNote> = Vec::new();
-    let mut push_note = |position: Option<i8>, duration: u8| {
-        notes.push(match position {
-            Some(position) => {
-                (Some(position), duration)
-            }
-            None => (None, duration),
-        });
+    let mut push_note = |position: Option<i8>, duration: u32| {
+        notes.push(TimedNote::new(position, duration));
     };
     match track_notes_json {
         Value::Number(num) => {
-            let position = num.as_i64().ok_or_else(|| "Note value must be int!")?;
+            let position = num.as_i64().ok_or("Note value must be int!")?;
             let position =
                 i8::try_from(position).map_err(|_| "Could not cast note value to i8!")?;
             push_note(Some(position), duration);
@@ -182,8 +602,8 @@ fn parse_voice_notes_recursive(
 
         }
         Value::String(string) => {
-            if string.as_str() != "" {
-                return Err("Only an empty string can be used to signify a silence!".to_string());
+            if !["", "rest", "r"].contains(&string.as_str()) {
+                return Err("Only \"\", \"rest\" or \"r\" can be used to signify a silence!".to_string());
             }
             push_note(None, duration);
         }
@@ -194,91 +614,229 @@ fn parse_voice_notes_recursive(
             for value in track_notes_json {
                 let duration = if halve_array { duration / 2 } else { duration };
                 let notes_deeper =
-                    parse_voice_notes_recursive(value, scale, octave, duration, &duration_regex, true)?;
-                notes.extend(notes_deeper.into_iter());
+                    parse_voice_notes_recursive(value, duration, duration_regex, true)?;
+                notes.extend(notes_deeper);
+            }
+        }
+        Value::Object(repeat_json) if repeat_json.contains_key("repeat") => {
+            let count = repeat_json
+                .get("repeat")
+                .unwrap()
+                .as_u64()
+                .ok_or("repeat should be uint!")?;
+            let repeated_json = repeat_json.get("notes").ok_or("notes missing in repeat!")?;
+
+            // A repeat block is expanded in place, so its notes keep the current duration.
+            let repeated_notes =
+                parse_voice_notes_recursive(repeated_json, duration, duration_regex, false)?;
+            for _ in 0..count {
+                notes.extend(repeated_notes.iter().cloned());
+            }
+        }
+        Value::Object(tuplet_json) if tuplet_json.contains_key("tuplet") => {
+            let ratio: Vec<u32> = tuplet_json
+                .get("tuplet")
+                .unwrap()
+                .as_array()
+                .ok_or("tuplet should be an array of two uints!")?
+                .iter()
+                .map(|value| value.as_u64().and_then(|value| u32::try_from(value).ok()))
+                .collect::<Option<Vec<u32>>>()
+                .filter(|ratio| ratio.len() == 2 && !ratio.contains(&0))
+                .ok_or("tuplet should be an array of two uints!")?;
+            let (count, time) = (ratio[0], ratio[1]);
+            let tuplet_notes_json = tuplet_json.get("notes").ok_or("notes missing in tuplet!")?;
+
+            // Fit `count` notes into the time of `time` notes of the current duration.
+            let tuplet_duration = duration
+                .checked_mul(time)
+                .filter(|total_duration| total_duration.is_multiple_of(count))
+                .ok_or_else(|| format!("A duration of {} ticks can't be split into a {}:{} tuplet!", duration, count, time))?
+                / count;
+            let tuplet_notes =
+                parse_voice_notes_recursive(tuplet_notes_json, tuplet_duration, duration_regex, false)?;
+            notes.extend(tuplet_notes);
+        }
+        Value::Object(measures_json) if measures_json.contains_key("measures") => {
+            let time_signature: Vec<u32> = match measures_json.get("time_signature") {
+                None => vec![4, 4],
+                Some(time_signature) => time_signature
+                    .as_array()
+                    .ok_or("time_signature should be an array of two uints!")?
+                    .iter()
+                    .map(|value| value.as_u64().and_then(|value| u32::try_from(value).ok()))
+                    .collect::<Option<Vec<u32>>>()
+                    .filter(|time_signature| time_signature.len() == 2 && !time_signature.contains(&0))
+                    .ok_or("time_signature should be an array of two uints!")?,
+            };
+            let (beats_per_bar, beat_unit) = (time_signature[0], time_signature[1]);
+            let bar_ticks = Some(TICKS_PER_BEAT * 4)
+                .filter(|whole_note_ticks| whole_note_ticks.is_multiple_of(beat_unit))
+                .and_then(|whole_note_ticks| (whole_note_ticks / beat_unit).checked_mul(beats_per_bar))
+                .ok_or_else(|| format!("Unsupported time signature: {}/{}!", beats_per_bar, beat_unit))?;
+
+            let measures = measures_json
+                .get("measures")
+                .unwrap()
+                .as_array()
+                .ok_or("measures should be an array!")?;
+            for (i, measure_json) in measures.iter().enumerate() {
+                let measure_notes = parse_voice_notes_recursive(measure_json, duration, duration_regex, false)?;
+                let measure_ticks = measure_notes
+                    .iter()
+                    .try_fold(0u32, |ticks, timed_note| ticks.checked_add(timed_note.duration))
+                    .ok_or_else(|| format!("Measure {} is too long!", i + 1))?;
+                if measure_ticks < bar_ticks {
+                    return Err(format!(
+                        "Measure {} is missing {} ticks to fill a {}/{} bar!",
+                        i + 1, bar_ticks - measure_ticks, beats_per_bar, beat_unit
+                    ));
+                }
+                if measure_ticks > bar_ticks {
+                    return Err(format!(
+                        "Measure {} is {} ticks longer than a {}/{} bar!",
+                        i + 1, measure_ticks - bar_ticks, beats_per_bar, beat_unit
+                    ));
+                }
+                notes.extend(measure_notes);
             }
         }
         Value::Object(map_note_value) => {
             for (key, value) in map_note_value {
-                let captures = duration_regex
-                    .captures(key)
-                    .ok_or_else(|| format!("Invalid duration specifier: {}", key))?;
-
-                let numerator = match captures.get(1) {
-                    None => 1,
-                    Some(numerator) => str::parse::<u8>(numerator.as_str()).unwrap()
-                };
-                let denominator = match captures.get(2) {
-                    None => 1,
-                    Some(denominator) => str::parse::<u8>(denominator.as_str()).unwrap()
-                };
-
-                let duration = duration * numerator / denominator;
-                let notes_deeper = parse_voice_notes_recursive(value, scale, octave, duration, &duration_regex, false)?;
-                notes.extend(notes_deeper.into_iter());
+                let duration = parse_duration(key, duration, duration_regex)?;
+                let notes_deeper = parse_voice_notes_recursive(value, duration, duration_regex, false)?;
+                notes.extend(notes_deeper);
             }
         }
-        _ => {
-            return Err("Notes must be a number, string, null, Array or Object!".to_string());
-        }
     };
     Ok(notes)
 }
 
-fn parse_chord(chord_json: &Map<String, Value>, tracks_by_id: &IndexMap<String, Box<dyn Track>>) -> Result<Chord, String> {
+/// Parse a duration specifier such as "2", "1/3", "/2" or "1.", as a multiple of `duration`.
+fn parse_duration(key: &str, duration: u32, duration_regex: &Regex) -> Result<u32, String> {
+    let captures = duration_regex
+        .captures(key)
+        .ok_or_else(|| format!("Invalid duration specifier: {}", key))?;
+
+    let numerator = match captures.get(1) {
+        None => 1,
+        Some(numerator) => str::parse::<u32>(numerator.as_str())
+            .map_err(|_| format!("Invalid duration specifier: {}", key))?
+    };
+    let denominator = match captures.get(2) {
+        None => 1,
+        Some(denominator) => str::parse::<u32>(denominator.as_str())
+            .map_err(|_| format!("Invalid duration specifier: {}", key))?
+    };
+    if denominator == 0 {
+        return Err(format!("Invalid duration specifier: {}, cannot divide by 0!", key));
+    }
+    // A dot adds half of the duration, and a second dot half of that again.
+    let (dot_numerator, dot_denominator) = match captures.get(3).map_or(0, |dots| dots.as_str().len()) {
+        0 => (1, 1),
+        1 => (3, 2),
+        _ => (7, 4),
+    };
+
+    // Durations are multiplied before they are divided, to stay exact.
+    let divisor = denominator
+        .checked_mul(dot_denominator)
+        .ok_or_else(|| format!("Invalid duration specifier: {}, the duration is too short!", key))?;
+    Ok(duration
+        .checked_mul(numerator)
+        .and_then(|duration| duration.checked_mul(dot_numerator))
+        .ok_or_else(|| format!("Invalid duration specifier: {}, the duration is too long!", key))?
+        / divisor)
+}
+
+fn parse_chord(
+    chord_json: &Map<String, Value>,
+    tracks_by_id: &IndexMap<String, Box<dyn Track>>,
+    scales: &ScaleRegistry,
+) -> Result<Chord, String> {
     let id = chord_json
         .get("id")
-        .ok_or_else(|| "id missing!")?
+        .ok_or("id missing!")?
         .as_str()
-        .ok_or_else(|| "id should be string!")?
+        .ok_or("id should be string!")?
         .to_string();
 
-    let scale = chord_json
-        .get("scale")
-        .ok_or_else(|| "scale missing!")?
-        .as_str()
-        .ok_or_else(|| "scale should be string!")?;
-    let scale = str::parse::<Scale>(scale)?;
+    let scale = parse_scale(chord_json.get("scale").ok_or("scale missing!")?, scales)?;
 
     let octave = chord_json
         .get("octave")
-        .ok_or_else(|| "octave missing!")?
+        .ok_or("octave missing!")?
         .as_i64()
-        .ok_or_else(|| "octave should be int!")?;
+        .ok_or("octave should be int!")?;
     let octave = i8::try_from(octave).map_err(|_| "Could not convert octave to i8!")?;
 
     let chord_array = chord_json
         .get("chord")
-        .ok_or_else(|| "start missing!")?
+        .ok_or("start missing!")?
         .as_array()
-        .ok_or_else(|| "chord should be array!")?;
+        .ok_or("chord should be array!")?;
 
-    let start = chord_json.get("start").ok_or_else(|| "start missing!")?;
+    let start = chord_json.get("start").ok_or("start missing!")?;
     let start = parse_track_start(start, tracks_by_id)?;
 
     let mut chord_positions: Vec<i8> = Vec::new();
-    for chord_position in chord_array.into_iter() {
-        let chord_position = chord_position.as_i64().ok_or_else(|| "each chord value should be int!")?;
+    for chord_position in chord_array.iter() {
+        let chord_position = chord_position.as_i64().ok_or("each chord value should be int!")?;
         let chord_position = i8::try_from(chord_position).map_err(|_| "Could not convert chord value to i8!")?;
         chord_positions.push(chord_position);
     }
+    check_positions_in_range(&scale, octave, chord_positions.iter().copied())?;
 
-    let notes = chord_json.get("notes").ok_or_else(|| "notes missing!")?;
-    let notes = parse_voice_notes(notes, &scale, octave)?.into_iter().map(|value| (value.0.is_some(), value.1)).collect();
+    let notes = chord_json.get("notes").ok_or("notes missing!")?;
+    let notes = parse_voice_notes(notes)?.into_iter().map(|value| (value.position.is_some(), value.duration)).collect();
 
-    Ok(Chord {
-        id,
-        scale,
-        start,
-        octave,
-        chord: chord_positions,
-        notes,
-    })
+    let mut chord = Chord::new(id, scale, octave, start, chord_positions, notes);
+    chord.muted = parse_flag(chord_json, "muted")?;
+    chord.solo = parse_flag(chord_json, "solo")?;
+    chord.channel = parse_channel(chord_json)?;
+    Ok(chord)
+}
+
+fn parse_drum_pattern(
+    drums_json: &Map<String, Value>,
+    tracks_by_id: &IndexMap<String, Box<dyn Track>>,
+) -> Result<DrumPattern, String> {
+    let id = drums_json
+        .get("id")
+        .ok_or("id missing!")?
+        .as_str()
+        .ok_or("id should be string!")?
+        .to_string();
+
+    let start = drums_json.get("start").ok_or("start missing!")?;
+    let start = parse_track_start(start, tracks_by_id)?;
+
+    let pattern = drums_json
+        .get("pattern")
+        .ok_or("pattern missing!")?
+        .as_object()
+        .ok_or("pattern should be an object!")?;
+    let rows = pattern
+        .iter()
+        .map(|(instrument, steps)| {
+            let steps = steps.as_str().ok_or(format!("The steps of {} should be string!", instrument))?;
+            Ok((instrument.as_str(), steps))
+        })
+        .collect::<Result<Vec<(&str, &str)>, String>>()?;
+
+    let mut drums = DrumPattern::from_steps(id, start, &rows)?;
+    drums.muted = parse_flag(drums_json, "muted")?;
+    drums.solo = parse_flag(drums_json, "solo")?;
+    if let Some(channel) = parse_channel(drums_json)? {
+        drums.channel = Some(channel);
+    }
+    Ok(drums)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use midly::{MidiMessage, TrackEventKind};
 
     #[test]
     fn can_load_data() {
@@ -311,4 +869,471 @@ mod tests {
 
         let _piece = parse_piece(data).unwrap();
     }
+
+    #[test]
+    fn can_load_piece_texts() {
+        let data = r#"{"bpm": 120, "tracks": [], "title": "Fugue", "copyright": "© Moira"}"#;
+
+        let piece = parse_piece(data).unwrap();
+        assert_eq!(piece.title.as_deref(), Some("Fugue"));
+        assert_eq!(piece.copyright.as_deref(), Some("© Moira"));
+        assert_eq!(piece.text, None);
+        assert!(parse_piece(r#"{"bpm": 120, "tracks": [], "title": 1}"#).is_err());
+    }
+
+    #[test]
+    fn can_load_default_velocity() {
+        let piece = parse_piece(r#"{"bpm": 120, "tracks": [], "default_velocity": 64}"#).unwrap();
+        assert_eq!(piece.default_velocity, 64);
+        let piece = parse_piece(r#"{"bpm": 120, "tracks": []}"#).unwrap();
+        assert_eq!(piece.default_velocity, DEFAULT_VELOCITY);
+        assert!(parse_piece(r#"{"bpm": 120, "tracks": [], "default_velocity": 0}"#).is_err());
+        assert!(parse_piece(r#"{"bpm": 120, "tracks": [], "default_velocity": 128}"#).is_err());
+    }
+
+    #[test]
+    fn can_load_tempo_units() {
+        let piece =
+            parse_piece(r#"{"tempo": {"unit": "dotted_quarter", "bpm": 60}, "tracks": []}"#).unwrap();
+        assert_eq!((piece.bpm, piece.tempo_unit_ticks), (60, 3 * TICKS_PER_BEAT / 2));
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let tempo = smf.tracks[0].iter().find_map(|event| match event.kind {
+            midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(tempo)) => Some(tempo.as_int()),
+            _ => None,
+        });
+        // A dotted quarter lasts a second, so a quarter lasts two thirds of one.
+        assert_eq!(tempo, Some(666666));
+
+        assert_eq!(parse_piece(r#"{"bpm": 120, "tracks": []}"#).unwrap().tempo_unit_ticks, TICKS_PER_BEAT);
+        assert_eq!(
+            parse_piece(r#"{"tempo": {"unit": "triplet", "bpm": 60}, "tracks": []}"#).unwrap_err(),
+            "Unknown tempo unit triplet!"
+        );
+        assert!(parse_piece(r#"{"tempo": {"unit": "half"}, "tracks": []}"#).is_err());
+        assert!(parse_piece(r#"{"bpm": 120, "tempo": {"unit": "half", "bpm": 60}, "tracks": []}"#).is_err());
+    }
+
+    #[test]
+    fn can_load_anacrusis() {
+        let piece = parse_piece(r#"{"bpm": 120, "tracks": [], "anacrusis_ticks": 24}"#).unwrap();
+        assert_eq!(piece.anacrusis_ticks, 24);
+        assert!(parse_piece(r#"{"bpm": 120, "tracks": [], "anacrusis_ticks": -24}"#).is_err());
+    }
+
+    #[test]
+    fn can_load_data_from_reader() {
+        let reader = std::io::Cursor::new(include_bytes!("../examples/wtc_1_1_fugue.json"));
+        let piece = parse_piece_reader(reader).unwrap();
+
+        let expected_piece = parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
+        let (mut midi, mut expected_midi) = (Vec::new(), Vec::new());
+        piece.write_midi(&mut midi).unwrap();
+        expected_piece.write_midi(&mut expected_midi).unwrap();
+        assert_eq!(midi, expected_midi);
+    }
+
+    #[test]
+    fn can_include_tracks() {
+        let dir = std::env::temp_dir().join(format!("moira_include_{}", std::process::id()));
+        fs::create_dir_all(dir.join("voices")).unwrap();
+        fs::write(
+            dir.join("voices/bass.json"),
+            r#"{"id": "bass", "scale": "Cmaj", "octave": 2, "start": {"melody": 2}, "type": "voice", "notes": [0, 4]}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("piece.json"),
+            r#"{
+                "bpm": 120,
+                "tracks": [
+                    {"id": "melody", "scale": "Cmaj", "octave": 4, "start": 1, "type": "voice", "notes": [0, 2, 4]},
+                    {"include": "voices/bass.json"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let piece = parse_piece_from_path(dir.join("piece.json")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let ids: Vec<&str> = piece.tracks.iter().map(|track| track.get_id()).collect();
+        assert_eq!(ids, ["melody", "bass"]);
+        assert_eq!(*piece.tracks[1].get_start_tick(), 3 * TICKS_PER_BEAT);
+    }
+
+    #[test]
+    fn can_render_directories() {
+        let dir = std::env::temp_dir().join(format!("moira_render_dir_{}", std::process::id()));
+        let (input_dir, output_dir) = (dir.join("pieces"), dir.join("midi"));
+        fs::create_dir_all(&input_dir).unwrap();
+        for name in ["first", "second"] {
+            fs::write(
+                input_dir.join(format!("{}.json", name)),
+                r#"{"bpm": 120, "tracks": [{"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0, 2, 4]}]}"#,
+            )
+            .unwrap();
+        }
+        fs::write(input_dir.join("broken.json"), r#"{"bpm": 120"#).unwrap();
+        fs::write(input_dir.join("notes.txt"), "not a piece").unwrap();
+
+        let errors = render_dir(&input_dir, &output_dir).unwrap();
+        let mut rendered: Vec<String> = fs::read_dir(&output_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        rendered.sort();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rendered, ["first.mid", "second.mid"]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("broken.json"), "{}", errors[0]);
+
+        assert!(render_dir(dir.join("missing"), &output_dir).is_err());
+    }
+
+    #[test]
+    fn can_use_registered_scales() {
+        let mut scales = ScaleRegistry::default();
+        scales.register("hungarian_minor", vec![0, 2, 3, 6, 7, 8, 11]).unwrap();
+        assert!(scales.register("hungarian minor", vec![0, 2]).is_err());
+        assert!(scales.register("descending", vec![11, 0]).is_err());
+
+        let json = r#"{"bpm": 120, "tracks": [{"id": "voice_1", "scale": "A hungarian_minor", "octave": 4, "start": 0, "type": "voice", "notes": [0, 3, 6]}]}"#;
+        let piece = parse_piece_with_scales(json, &scales).unwrap();
+        assert!(parse_piece_strict(json).is_err());
+        assert!(parse_piece_strict_with_scales(json, &scales).is_ok());
+        let events = piece.tracks[0].to_midi(0, 0, DEFAULT_VELOCITY);
+        let keys: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, [69, 75, 80]);
+
+        assert_eq!(parse_piece(json).unwrap_err(), "Unknown scale: hungarian_minor");
+        assert_eq!(scales.parse("Amin").unwrap(), str::parse::<Scale>("Amin").unwrap());
+    }
+
+    #[test]
+    fn can_load_fractional_starts() {
+        let piece = parse_piece(
+            r#"{
+                "bpm": 120,
+                "tracks": [
+                    {"id": "melody", "scale": "Cmaj", "octave": 4, "start": 2.5, "type": "voice", "notes": [0, 2]},
+                    {"id": "bass", "scale": "Cmaj", "octave": 2, "start": {"melody": -0.5}, "type": "voice", "notes": [0]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(*piece.tracks[0].get_start_tick(), 5 * TICKS_PER_BEAT / 2);
+        assert_eq!(*piece.tracks[1].get_start_tick(), 2 * TICKS_PER_BEAT);
+
+        let events = piece.tracks[0].to_midi(0, 0, DEFAULT_VELOCITY);
+        let first_note_on = events
+            .iter()
+            .find(|event| matches!(event.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. }))
+            .unwrap();
+        assert_eq!(first_note_on.delta.as_int(), 5 * TICKS_PER_BEAT / 2);
+
+        let start_between_ticks =
+            r#"{"bpm": 120, "tracks": [{"id": "melody", "scale": "Cmaj", "octave": 4, "start": 0.01, "type": "voice", "notes": [0]}]}"#;
+        assert!(parse_piece(start_between_ticks).is_err());
+    }
+
+    #[test]
+    fn can_load_inline_scales() {
+        let json = r#"{"bpm": 120, "tracks": [
+            {"id": "voice_1", "scale": ["C", "D", "Eb", "F#", "G", "A", "Bb"], "octave": 4, "start": 0, "type": "voice", "notes": [0, 2, 3, 6, 7]}
+        ]}"#;
+        let piece = parse_piece(json).unwrap();
+        let events = piece.tracks[0].to_midi(0, 0, DEFAULT_VELOCITY);
+        let keys: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, [60, 63, 66, 70, 72]);
+
+        let scale = Scale::from_named_keys(["A", "B", "C", "D#"].map(|key| str::parse::<NamedKey>(key).unwrap()).to_vec()).unwrap();
+        assert_eq!(scale.offsets(), [0, 2, 3, 6]);
+        assert_eq!(scale.get_named_note(3, 4).to_string(), "D♯5");
+
+        for invalid_scale in [r#"["C", "E", "D"]"#, r#"["C", "D", "C"]"#, r#"[]"#, r#"["C", 2]"#] {
+            let json = format!(
+                r#"{{"bpm": 120, "tracks": [{{"id": "voice_1", "scale": {}, "octave": 4, "start": 0, "type": "voice", "notes": [0]}}]}}"#,
+                invalid_scale
+            );
+            assert!(parse_piece(&json).is_err(), "{}", invalid_scale);
+        }
+    }
+
+    #[test]
+    fn can_parse_note_dsl() {
+        let notes = parse_note_dsl("C4 Eb4 G4 r/2 A4*2 C5/2", TICKS_PER_BEAT).unwrap();
+        assert_eq!(
+            notes,
+            [
+                TimedNote::new(Some(60), TICKS_PER_BEAT),
+                TimedNote::new(Some(63), TICKS_PER_BEAT),
+                TimedNote::new(Some(67), TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT / 2),
+                TimedNote::new(Some(69), TICKS_PER_BEAT * 2),
+                TimedNote::new(Some(72), TICKS_PER_BEAT / 2),
+            ]
+        );
+
+        assert!(parse_note_dsl("C4 H4", TICKS_PER_BEAT).is_err());
+        assert!(parse_note_dsl("C4/x", TICKS_PER_BEAT).is_err());
+        assert_eq!(parse_note_dsl("A9", TICKS_PER_BEAT).unwrap_err(), "Note out of range: A9");
+        assert_eq!(parse_note_dsl("Cb-1", TICKS_PER_BEAT).unwrap_err(), "Note out of range: Cb-1");
+    }
+
+    #[test]
+    fn can_load_note_dsl_voice() {
+        let data = r#"
+        {
+            "bpm": 120,
+            "tracks": [
+                {"id": "voice_1", "start": 0, "type": "voice", "notes": "C4 E4 G4 r/2 A4"}
+            ]
+        }"#;
+
+        let piece = parse_piece(data).unwrap();
+        let voice = piece.tracks[0].to_midi(1, 0, DEFAULT_VELOCITY);
+        let keys: Vec<u8> = voice
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => {
+                    Some(key.as_int())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, [60, 64, 67, 69]);
+    }
+
+    #[test]
+    fn can_repeat_notes() {
+        let notes = parse_voice_notes(&serde_json::json!({"repeat": 3, "notes": [0, 2]})).unwrap();
+        assert_eq!(notes.len(), 6);
+        assert_eq!(
+            notes.iter().map(|note| note.position).collect::<Vec<_>>(),
+            [0, 2, 0, 2, 0, 2].map(Some)
+        );
+
+        // Repeats nest, and take their duration from the enclosing duration object.
+        let notes = parse_voice_notes(&serde_json::json!(
+            [{"/2": {"repeat": 2, "notes": [{"repeat": 2, "notes": [1]}, 3]}}, 4]
+        ))
+        .unwrap();
+        let expected_positions = [1, 1, 3, 1, 1, 3, 4].map(Some);
+        assert_eq!(notes.iter().map(|note| note.position).collect::<Vec<_>>(), expected_positions);
+        assert!(notes[..6].iter().all(|note| note.duration == TICKS_PER_BEAT / 2));
+        assert_eq!(notes[6].duration, TICKS_PER_BEAT);
+    }
+
+    #[test]
+    fn can_parse_tuplets() {
+        // An eighth-note triplet, followed by a quarter-note triplet taking up two beats.
+        let notes = parse_voice_notes(&serde_json::json!([
+            {"/2": {"tuplet": [3, 2], "notes": [0, 2, 4]}},
+            {"tuplet": [3, 2], "notes": [5, 4, 2]}
+        ]))
+        .unwrap();
+        assert_eq!(notes.len(), 6);
+        assert!(notes[..3].iter().all(|note| note.duration == TICKS_PER_BEAT / 3));
+        assert_eq!(notes[..3].iter().map(|note| note.duration).sum::<u32>(), TICKS_PER_BEAT);
+        assert_eq!(notes[3..].iter().map(|note| note.duration).sum::<u32>(), 2 * TICKS_PER_BEAT);
+
+        let error = parse_voice_notes(&serde_json::json!({"tuplet": [5, 4], "notes": [0, 1, 2, 3, 4]})).unwrap_err();
+        assert!(error.contains("5:4 tuplet"), "{}", error);
+        assert!(parse_voice_notes(&serde_json::json!({"tuplet": [3, 0], "notes": [0]})).is_err());
+        assert!(parse_voice_notes(&serde_json::json!({"tuplet": 3, "notes": [0]})).is_err());
+    }
+
+    #[test]
+    fn can_parse_measures() {
+        let notes = parse_voice_notes(&serde_json::json!({
+            "measures": [[0, 1, 2, 3], [{"2": 4}, 5, 6]]
+        }))
+        .unwrap();
+        let durations: Vec<u32> = notes.iter().map(|note| note.duration).collect();
+        assert_eq!(durations, [TICKS_PER_BEAT, TICKS_PER_BEAT, TICKS_PER_BEAT, TICKS_PER_BEAT, 2 * TICKS_PER_BEAT, TICKS_PER_BEAT, TICKS_PER_BEAT]);
+
+        let notes = parse_voice_notes(&serde_json::json!({
+            "measures": [{"/2": [0, 1, 2, 3, 4, 5]}], "time_signature": [6, 8]
+        }))
+        .unwrap();
+        assert_eq!(notes.len(), 6);
+
+        assert_eq!(
+            parse_voice_notes(&serde_json::json!({"measures": [[0, 1, 2, 3], [0, 1, 2]]})).unwrap_err(),
+            format!("Measure 2 is missing {} ticks to fill a 4/4 bar!", TICKS_PER_BEAT)
+        );
+        assert_eq!(
+            parse_voice_notes(&serde_json::json!({"measures": [[0, 1, 2, 3]], "time_signature": [3, 4]})).unwrap_err(),
+            format!("Measure 1 is {} ticks longer than a 3/4 bar!", TICKS_PER_BEAT)
+        );
+        assert_eq!(
+            parse_voice_notes(&serde_json::json!({"measures": [[0]], "time_signature": [4, 0]})).unwrap_err(),
+            "time_signature should be an array of two uints!"
+        );
+        assert_eq!(
+            parse_voice_notes(&serde_json::json!({"measures": [[0]], "time_signature": [u32::MAX, 4]})).unwrap_err(),
+            format!("Unsupported time signature: {}/4!", u32::MAX)
+        );
+        let long_note = u32::MAX / TICKS_PER_BEAT;
+        assert_eq!(
+            parse_voice_notes(&serde_json::json!({"measures": [[{long_note.to_string(): [0, 0]}]]})).unwrap_err(),
+            "Measure 1 is too long!"
+        );
+    }
+
+    #[test]
+    fn can_parse_rests() {
+        let notes = parse_voice_notes(&serde_json::json!([0, "rest", {"2": "r"}, null, false, ""])).unwrap();
+        assert_eq!(
+            notes,
+            [
+                TimedNote::new(Some(0), TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT),
+                TimedNote::new(None, 2 * TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT),
+            ]
+        );
+        assert!(parse_voice_notes(&serde_json::json!(["silence"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_dotted_durations() {
+        let notes = parse_voice_notes(&serde_json::json!([{"1.": 0}, {"1..": 1}, {"/2.": [2, 3]}, {".": 4}])).unwrap();
+        let durations: Vec<u32> = notes.iter().map(|note| note.duration).collect();
+        assert_eq!(
+            durations,
+            [
+                TICKS_PER_BEAT * 3 / 2,
+                TICKS_PER_BEAT * 7 / 4,
+                TICKS_PER_BEAT * 3 / 4,
+                TICKS_PER_BEAT * 3 / 4,
+                TICKS_PER_BEAT * 3 / 2,
+            ]
+        );
+        assert!(parse_voice_notes(&serde_json::json!({"1...": 0})).is_err());
+        assert_eq!(
+            parse_voice_notes(&serde_json::json!({"/2000000000..": [0]})).unwrap_err(),
+            "Invalid duration specifier: /2000000000.., the duration is too short!"
+        );
+    }
+
+    #[test]
+    fn can_set_default_durations() {
+        for default_duration in [serde_json::json!(0.5), serde_json::json!("1/2")] {
+            let piece = parse_piece(&format!(
+                r#"{{"bpm": 120, "tracks": [{{"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice",
+                    "default_duration": {}, "notes": [0, 1, {{"2": 2}}, [3, 4]]}}]}}"#,
+                default_duration
+            ))
+            .unwrap();
+            // 0 and 1 last half a beat, 2 a beat, and 3 and 4 a quarter of a beat each.
+            assert_eq!(piece.tracks[0].total_ticks(), 5 * TICKS_PER_BEAT / 2);
+        }
+
+        let piece = parse_piece(
+            r#"{"bpm": 120, "tracks": [{"id": "voice_1", "start": 0, "type": "voice", "default_duration": "/2", "notes": "C4 D4"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(piece.tracks[0].total_ticks(), TICKS_PER_BEAT);
+
+        for default_duration in ["0", "0.3", "\"long\"", "true"] {
+            assert!(parse_piece(&format!(
+                r#"{{"bpm": 120, "tracks": [{{"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice",
+                    "default_duration": {}, "notes": [0]}}]}}"#,
+                default_duration
+            ))
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn can_reject_zero_durations() {
+        let error = parse_voice_notes(&serde_json::json!({"1/0": [0, 2]})).unwrap_err();
+        assert!(error.contains("cannot divide by 0"), "{}", error);
+        assert!(parse_voice_notes(&serde_json::json!({"1/25": [0]})).is_err());
+        assert!(parse_note_dsl("C4/0", TICKS_PER_BEAT).is_err());
+        assert!(parse_note_dsl("C4/25", TICKS_PER_BEAT).is_err());
+    }
+
+    #[test]
+    fn can_parse_long_durations() {
+        // 10 beats used to overflow and wrap around.
+        let notes = parse_voice_notes(&serde_json::json!({"10": [0]})).unwrap();
+        assert_eq!(notes[0].duration, 10 * TICKS_PER_BEAT);
+        let notes = parse_voice_notes(&serde_json::json!({"1000": {"1000": [0]}})).unwrap();
+        assert_eq!(notes[0].duration, 1_000_000 * TICKS_PER_BEAT);
+
+        let error = parse_voice_notes(&serde_json::json!({"100000": {"100000": [0]}})).unwrap_err();
+        assert!(error.contains("too long"), "{}", error);
+        assert!(parse_voice_notes(&serde_json::json!({"99999999999": [0]})).is_err());
+        assert!(parse_note_dsl("C4*999999999", TICKS_PER_BEAT).is_err());
+    }
+
+    #[test]
+    fn can_reject_notes_out_of_midi_range() {
+        let error = parse_piece(
+            r#"{"bpm": 120, "tracks": [
+                {"id": "voice_1", "scale": "Cmaj", "octave": 9, "start": 0, "type": "voice", "notes": [0, 20]}
+            ]}"#,
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("Position 20 in octave 9"), "{}", error);
+
+        let error = parse_piece(
+            r#"{"bpm": 120, "tracks": [
+                {"id": "chord_1", "scale": "Cmaj", "octave": -1, "start": 0, "type": "chord", "chord": [-1, 0], "notes": [0]}
+            ]}"#,
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("Position -1 in octave -1"), "{}", error);
+    }
+
+    #[test]
+    fn can_reject_unknown_keys_when_strict() {
+        let misspelled = r#"{"bpm": 120, "tracks": [
+            {"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0], "instument": 40}
+        ]}"#;
+        assert!(parse_piece(misspelled).is_ok());
+        assert_eq!(parse_piece_strict(misspelled).unwrap_err(), "Unknown key instument in track voice_1!");
+        assert_eq!(
+            parse_piece_strict(r#"{"bpm": 120, "tempi": 60, "tracks": []}"#).unwrap_err(),
+            "Unknown key tempi in piece!"
+        );
+        let unknown_type = r#"{"bpm": 120, "tracks": [{"id": "voice_1", "type": "voise", "notes": [0]}]}"#;
+        assert_eq!(parse_piece(unknown_type).unwrap_err(), "Invalid track type!");
+        assert_eq!(parse_piece_strict(unknown_type).unwrap_err(), "Invalid track type!");
+
+        for example in [
+            include_str!("../examples/wtc_1_1_prelude.json"),
+            include_str!("../examples/wtc_1_1_fugue.json"),
+            include_str!("../examples/ballad.json"),
+        ] {
+            assert!(parse_piece_strict(example).is_ok());
+        }
+        assert!(parse_piece_strict(
+            r#"{"bpm": 120, "title": "Beat", "tracks": [
+                {"id": "voice_1", "start": 0, "type": "voice", "notes": "C4 E4", "default_duration": 2, "solo": true},
+                {"id": "beat", "start": 0, "type": "drums", "pattern": {"kick": "x..."}, "channel": 9}
+            ]}"#
+        )
+        .is_ok());
+    }
 }
@@ -0,0 +1,319 @@
+// This module writes pieces back to the JSON format read by `json_input`. Notes are
+// written with their durations relative to a beat, grouping notes of the same duration,
+// so that a piece written out and read back plays exactly the same.
+
+use serde_json::{json, Map, Number, Value};
+
+use super::chord::{Chord, StrumDirection};
+use super::drums::{DrumHit, DrumPattern, DRUM_STEP_TICKS, GM_DRUM_KEYS, PERCUSSION_CHANNEL};
+use super::json_input::TEMPO_UNITS;
+use super::scale::Scale;
+use super::track::{Piece, Voice, DEFAULT_RELEASE_VELOCITY, DEFAULT_VELOCITY, TICKS_PER_BEAT};
+
+impl Piece {
+    /// Write the piece in the JSON format read by `parse_piece`.
+    ///
+    /// # Errors
+    /// - if the piece has markers, tempo changes, pans, note offs written as note ons or a
+    ///   merged conductor track, which the JSON format can't express;
+    /// - if its tempo counts a note value that isn't one of `TEMPO_UNITS`;
+    /// - if one of its tracks can't be written as JSON.
+    pub fn to_json(&self) -> Result<String, String> {
+        if !self.markers.is_empty()
+            || !self.tempo_changes.is_empty()
+            || !self.pans.is_empty()
+            || self.note_off_as_note_on
+            || self.merge_conductor_track
+        {
+            return Err(
+                "Markers, tempo changes, pans, note offs as note ons and merged conductor tracks can't be written as JSON!"
+                    .to_string(),
+            );
+        }
+
+        let mut piece_json = Map::new();
+        if self.tempo_unit_ticks == TICKS_PER_BEAT {
+            piece_json.insert("bpm".to_string(), json!(self.bpm));
+        } else {
+            let (unit, _) = TEMPO_UNITS
+                .iter()
+                .find(|(_, ticks)| *ticks == self.tempo_unit_ticks)
+                .ok_or(format!("A tempo unit of {} ticks can't be written as JSON!", self.tempo_unit_ticks))?;
+            piece_json.insert("tempo".to_string(), json!({"unit": unit, "bpm": self.bpm}));
+        }
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|track| track.to_json())
+            .collect::<Result<Vec<Value>, String>>()?;
+        piece_json.insert("tracks".to_string(), Value::Array(tracks));
+        for (key, value) in [("title", &self.title), ("copyright", &self.copyright), ("text", &self.text)] {
+            if let Some(value) = value {
+                piece_json.insert(key.to_string(), json!(value));
+            }
+        }
+        if self.default_velocity != DEFAULT_VELOCITY {
+            piece_json.insert("default_velocity".to_string(), json!(self.default_velocity));
+        }
+        if self.anacrusis_ticks != 0 {
+            piece_json.insert("anacrusis_ticks".to_string(), json!(self.anacrusis_ticks));
+        }
+        serde_json::to_string_pretty(&Value::Object(piece_json)).map_err(|err| err.to_string())
+    }
+}
+
+pub(crate) fn voice_to_json(voice: &Voice) -> Result<Value, String> {
+    if voice.release_velocity != DEFAULT_RELEASE_VELOCITY
+        || !voice.slurs.is_empty()
+        || !voice.expression_ramps().is_empty()
+        || !voice.aftertouch_ramps().is_empty()
+    {
+        return Err(format!(
+            "Voice {} has a release velocity, slurs, expression or aftertouch ramps, which can't be written as JSON!",
+            voice.id
+        ));
+    }
+    if voice
+        .notes
+        .iter()
+        .any(|timed_note| {
+            timed_note.velocity.is_some()
+                || timed_note.fermata.is_some()
+                || timed_note.channel.is_some()
+                || timed_note.probability.is_some()
+        })
+    {
+        return Err(format!(
+            "Voice {} has notes with a velocity, fermata, channel or probability, which can't be written as JSON!",
+            voice.id
+        ));
+    }
+
+    let mut voice_json = Map::new();
+    voice_json.insert("id".to_string(), json!(voice.id));
+    voice_json.insert("type".to_string(), json!("voice"));
+    voice_json.insert("scale".to_string(), scale_to_json(&voice.scale)?);
+    voice_json.insert("octave".to_string(), json!(voice.octave));
+    voice_json.insert("start".to_string(), ticks_to_beats(voice.start_tick)?);
+    let notes = voice
+        .notes
+        .iter()
+        .map(|timed_note| (timed_note.position.map_or(Value::Null, |position| json!(position)), timed_note.duration));
+    voice_json.insert("notes".to_string(), notes_to_json(notes));
+    if !voice.lyrics.is_empty() {
+        voice_json.insert("lyrics".to_string(), json!(voice.lyrics));
+    }
+    insert_flags(&mut voice_json, voice.muted, voice.solo, voice.channel);
+    Ok(Value::Object(voice_json))
+}
+
+pub(crate) fn chord_to_json(chord: &Chord) -> Result<Value, String> {
+    if chord.strum_ticks != 0 || chord.strum_direction != StrumDirection::Up {
+        return Err(format!("Chord {} is strummed, which can't be written as JSON!", chord.id));
+    }
+    if !chord.tone_rhythms.is_empty() {
+        return Err(format!("Chord {} has tones with rhythms of their own, which can't be written as JSON!", chord.id));
+    }
+
+    let mut chord_json = Map::new();
+    chord_json.insert("id".to_string(), json!(chord.id));
+    chord_json.insert("type".to_string(), json!("chord"));
+    chord_json.insert("scale".to_string(), scale_to_json(&chord.scale)?);
+    chord_json.insert("octave".to_string(), json!(chord.octave));
+    chord_json.insert("start".to_string(), ticks_to_beats(chord.start_tick)?);
+    chord_json.insert("chord".to_string(), json!(chord.chord));
+    let notes = chord
+        .notes
+        .iter()
+        .map(|(played, duration)| (if *played { Value::Bool(true) } else { Value::Null }, *duration));
+    chord_json.insert("notes".to_string(), notes_to_json(notes));
+    insert_flags(&mut chord_json, chord.muted, chord.solo, chord.channel);
+    Ok(Value::Object(chord_json))
+}
+
+pub(crate) fn drum_pattern_to_json(drums: &DrumPattern) -> Result<Value, String> {
+    if drums.channel.is_none() {
+        return Err(format!("Drum pattern {} has no channel, which can't be written as JSON!", drums.id));
+    }
+
+    let mut pattern_json = Map::new();
+    for (key, rhythm) in drums.rows.iter() {
+        let (instrument, _) = GM_DRUM_KEYS
+            .iter()
+            .find(|(_, drum_key)| drum_key == key)
+            .ok_or(format!("Drum pattern {} plays key {}, which has no name!", drums.id, key))?;
+        let mut steps = String::new();
+        for (hit, duration) in rhythm.iter() {
+            if *duration == 0 || !duration.is_multiple_of(DRUM_STEP_TICKS) {
+                return Err(format!("Drum pattern {} has hits off the sixteenth notes!", drums.id));
+            }
+            let rests = usize::try_from(duration / DRUM_STEP_TICKS).unwrap();
+            match hit {
+                DrumHit::Rest => steps.push_str(&".".repeat(rests)),
+                DrumHit::Hit => steps.push_str(&format!("x{}", ".".repeat(rests - 1))),
+                DrumHit::Accent => steps.push_str(&format!("X{}", ".".repeat(rests - 1))),
+            }
+        }
+        if pattern_json.insert(instrument.to_string(), json!(steps)).is_some() {
+            return Err(format!("Drum pattern {} plays {} in more than one row!", drums.id, instrument));
+        }
+    }
+
+    let mut drums_json = Map::new();
+    drums_json.insert("id".to_string(), json!(drums.id));
+    drums_json.insert("type".to_string(), json!("drums"));
+    drums_json.insert("start".to_string(), ticks_to_beats(drums.start_tick)?);
+    drums_json.insert("pattern".to_string(), Value::Object(pattern_json));
+    let channel = drums.channel.filter(|channel| *channel != PERCUSSION_CHANNEL);
+    insert_flags(&mut drums_json, drums.muted, drums.solo, channel);
+    Ok(Value::Object(drums_json))
+}
+
+/// Write a scale as the list of its key names, which keeps their spelling.
+fn scale_to_json(scale: &Scale) -> Result<Value, String> {
+    if scale.get_tuning().is_some() {
+        return Err("Tuned scales can't be written as JSON!".to_string());
+    }
+    Ok(json!(scale.named_keys().iter().map(|named_key| named_key.to_string()).collect::<Vec<String>>()))
+}
+
+/// Write a start tick as a whole or fractional number of beats.
+fn ticks_to_beats(ticks: u32) -> Result<Value, String> {
+    if ticks.is_multiple_of(TICKS_PER_BEAT) {
+        return Ok(json!(ticks / TICKS_PER_BEAT));
+    }
+    let beats = f64::from(ticks) / f64::from(TICKS_PER_BEAT);
+    if beats * f64::from(TICKS_PER_BEAT) != f64::from(ticks) {
+        return Err(format!("Tick {} can't be written as a number of beats!", ticks));
+    }
+    Ok(Value::Number(Number::from_f64(beats).unwrap()))
+}
+
+/// Write notes as a list, where notes lasting a beat are written as they are and runs of
+/// notes of another duration are grouped under that duration, e.g. {"1/2": [0, 2]}.
+fn notes_to_json(notes: impl Iterator<Item = (Value, u32)>) -> Value {
+    let mut notes_json: Vec<Value> = Vec::new();
+    let mut run: Option<(u32, Vec<Value>)> = None;
+    let end_run = |notes_json: &mut Vec<Value>, run: Option<(u32, Vec<Value>)>| {
+        if let Some((duration, values)) = run {
+            notes_json.push(json!({ duration_to_json(duration): values }));
+        }
+    };
+    for (value, duration) in notes {
+        if duration == TICKS_PER_BEAT {
+            end_run(&mut notes_json, run.take());
+            notes_json.push(value);
+            continue;
+        }
+        match run.as_mut() {
+            Some((run_duration, values)) if *run_duration == duration => values.push(value),
+            _ => {
+                end_run(&mut notes_json, run.take());
+                run = Some((duration, vec![value]));
+            }
+        }
+    }
+    end_run(&mut notes_json, run);
+    Value::Array(notes_json)
+}
+
+/// Write a duration in ticks as a reduced fraction of a beat, e.g. "3/2" or "2".
+fn duration_to_json(duration: u32) -> String {
+    let divisor = gcd(duration, TICKS_PER_BEAT);
+    let (numerator, denominator) = (duration / divisor, TICKS_PER_BEAT / divisor);
+    if denominator == 1 {
+        numerator.to_string()
+    } else {
+        format!("{}/{}", numerator, denominator)
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn insert_flags(track_json: &mut Map<String, Value>, muted: bool, solo: bool, channel: Option<u8>) {
+    if muted {
+        track_json.insert("muted".to_string(), json!(true));
+    }
+    if solo {
+        track_json.insert("solo".to_string(), json!(true));
+    }
+    if let Some(channel) = channel {
+        track_json.insert("channel".to_string(), json!(channel));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::json_input::parse_piece;
+    use super::*;
+
+    /// Parse `json_str`, write it back to JSON and parse it again, and check that both
+    /// pieces render to the same MIDI, and that writing the second one gives the same JSON.
+    fn assert_piece_roundtrip(json_str: &str) {
+        let piece = parse_piece(json_str).unwrap();
+        let piece_json = piece.to_json().unwrap();
+        let roundtrip_piece = parse_piece(&piece_json).unwrap();
+
+        assert_eq!(piece.to_midi_bytes().unwrap(), roundtrip_piece.to_midi_bytes().unwrap());
+        assert_eq!(roundtrip_piece.to_json().unwrap(), piece_json);
+    }
+
+    #[test]
+    fn can_roundtrip_prelude() {
+        assert_piece_roundtrip(include_str!("../examples/wtc_1_1_prelude.json"));
+    }
+
+    #[test]
+    fn can_roundtrip_fugue() {
+        assert_piece_roundtrip(include_str!("../examples/wtc_1_1_fugue.json"));
+    }
+
+    #[test]
+    fn can_roundtrip_ballad() {
+        assert_piece_roundtrip(include_str!("../examples/ballad.json"));
+    }
+
+    #[test]
+    fn can_write_pieces_as_json() {
+        assert_piece_roundtrip(
+            r#"{
+                "bpm": 90, "title": "Study", "default_velocity": 80, "anacrusis_ticks": 12,
+                "tracks": [
+                    {"id": "melody", "scale": ["C", "D", "E♭", "F♯"], "octave": 4, "start": 0.5, "type": "voice",
+                     "notes": [0, {"1/3": [1, 2, 3]}, {"1.": "r"}, [0, 1]], "lyrics": ["la", "la"], "solo": true},
+                    {"id": "bass", "start": 2, "type": "voice", "notes": "C2 E2", "channel": 3},
+                    {"id": "chord", "scale": "Fmaj", "octave": 3, "start": 0, "type": "chord",
+                     "chord": [0, 2, 4], "notes": [true, null, {"2": true}], "muted": true},
+                    {"id": "beat", "start": 1, "type": "drums",
+                     "pattern": {"kick": "x...x...x...x...", "snare": "....X.......x..."}}
+                ]
+            }"#,
+        );
+
+        let piece = parse_piece(
+            r#"{"bpm": 120, "tracks": [{"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0, [2, 4], {"3/2": [5, 7]}]}]}"#,
+        )
+        .unwrap();
+        let piece_json: Value = serde_json::from_str(&piece.to_json().unwrap()).unwrap();
+        assert_eq!(piece_json["tracks"][0]["notes"], json!([0, {"1/2": [2, 4]}, {"3/2": [5, 7]}]));
+        assert_eq!(piece_json["tracks"][0]["scale"], json!(["C", "D", "E", "F", "G", "A", "B"]));
+
+        let mut piece = piece;
+        piece.tempo_unit_ticks = 3 * TICKS_PER_BEAT / 2;
+        let piece_json: Value = serde_json::from_str(&piece.to_json().unwrap()).unwrap();
+        assert_eq!(piece_json["tempo"], json!({"unit": "dotted_quarter", "bpm": 120}));
+        assert!(piece_json.get("bpm").is_none());
+        piece.tempo_unit_ticks = 5;
+        assert!(piece.to_json().is_err());
+
+        piece.tempo_unit_ticks = TICKS_PER_BEAT;
+        piece.tempo_changes.push((24, 60));
+        assert!(piece.to_json().is_err());
+    }
+}
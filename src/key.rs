@@ -2,12 +2,19 @@
 // Key: a key (one of the 12 semitones in Western tuning)
 // Note: a note, with same values as MIDI (0 is C(-1), 60 is C4, etc.)
 // NamedKey: a key that is called a certain way (e.g. D# or Eb).
-// NamedNote: a note that is called a certain way (e.g. D#4 or Eb4).
+// NamedNote: a note that is called a certain way (e.g. D#4 or Eb4), possibly a quarter tone
+// away from the MIDI note it is played as (e.g. C𝄲4).
 
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Display};
+use core::ops::Add;
+#[cfg(feature = "std")]
+use core::str::FromStr;
+
+#[cfg(feature = "std")]
 use regex::Regex;
-use std::fmt::{self, Debug, Display};
-use std::ops::Add;
-use std::str::FromStr;
+
+use super::math::{log2, round};
 
 /// Represents any of the 12 distinct keys in Western tuning
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -18,13 +25,18 @@ impl Key {
         Self(key.rem_euclid(12))
     }
 
+    /// The key as a number of semitones above C, between 0 and 11.
+    pub fn get_value(&self) -> i8 {
+        self.0
+    }
+
     pub fn get_named_key_starting_with(&self, base_key: &BaseKey) -> Option<NamedKey> {
         match (self.0, base_key) {
             (0, BaseKey::B) => Some(NamedKey::new(BaseKey::B, KeyModifier::Sharp)),
             (0, BaseKey::C) => Some(NamedKey::new(BaseKey::C, KeyModifier::Natural)),
             (1, BaseKey::C) => Some(NamedKey::new(BaseKey::C, KeyModifier::Sharp)),
             (1, BaseKey::D) => Some(NamedKey::new(BaseKey::D, KeyModifier::Flat)),
-            (2, BaseKey::C) => Some(NamedKey::new(BaseKey::D, KeyModifier::DoubleSharp)),
+            (2, BaseKey::C) => Some(NamedKey::new(BaseKey::C, KeyModifier::DoubleSharp)),
             (2, BaseKey::D) => Some(NamedKey::new(BaseKey::D, KeyModifier::Natural)),
             (3, BaseKey::D) => Some(NamedKey::new(BaseKey::D, KeyModifier::Sharp)),
             (3, BaseKey::E) => Some(NamedKey::new(BaseKey::E, KeyModifier::Flat)),
@@ -34,11 +46,11 @@ impl Key {
             (5, BaseKey::F) => Some(NamedKey::new(BaseKey::F, KeyModifier::Natural)),
             (6, BaseKey::F) => Some(NamedKey::new(BaseKey::F, KeyModifier::Sharp)),
             (6, BaseKey::G) => Some(NamedKey::new(BaseKey::G, KeyModifier::Flat)),
-            (7, BaseKey::F) => Some(NamedKey::new(BaseKey::G, KeyModifier::DoubleSharp)),
+            (7, BaseKey::F) => Some(NamedKey::new(BaseKey::F, KeyModifier::DoubleSharp)),
             (7, BaseKey::G) => Some(NamedKey::new(BaseKey::G, KeyModifier::Natural)),
             (8, BaseKey::G) => Some(NamedKey::new(BaseKey::G, KeyModifier::Sharp)),
             (8, BaseKey::A) => Some(NamedKey::new(BaseKey::A, KeyModifier::Flat)),
-            (9, BaseKey::G) => Some(NamedKey::new(BaseKey::A, KeyModifier::DoubleSharp)),
+            (9, BaseKey::G) => Some(NamedKey::new(BaseKey::G, KeyModifier::DoubleSharp)),
             (9, BaseKey::A) => Some(NamedKey::new(BaseKey::A, KeyModifier::Natural)),
             (10, BaseKey::A) => Some(NamedKey::new(BaseKey::A, KeyModifier::Sharp)),
             (10, BaseKey::B) => Some(NamedKey::new(BaseKey::B, KeyModifier::Flat)),
@@ -109,16 +121,28 @@ impl Note {
         Self(key.0.try_into().unwrap()) + &((octave + 1) * 12)
     }
 
+    /// The note closest to the frequency `hz`, with A4 tuned to `a4_hz`, and how far the
+    /// frequency is from it in cents, e.g. 445 Hz is A4 about 20 cents sharp when A4 is
+    /// 440 Hz. Frequencies beyond the MIDI range give its lowest or highest note, as many
+    /// cents away as they are from it, and 0 Hz is infinitely many cents below C-1.
+    pub fn from_frequency(hz: f64, a4_hz: f64) -> (Note, f64) {
+        // How many semitones the frequency is above C-1.
+        let semitones = if hz.is_infinite() {
+            f64::INFINITY
+        } else if hz > 0.0 {
+            69.0 + 12.0 * log2(hz / a4_hz)
+        } else {
+            f64::NEG_INFINITY
+        };
+        let height = round(semitones).clamp(0.0, 127.0);
+        (Note(height as u8), (semitones - height) * 100.0)
+    }
+
     pub fn get_named_note_starting_with(&self, base_key: &BaseKey) -> Option<NamedNote> {
-        let (key, octave) = self.decompose();
+        let (key, _) = self.decompose();
         let named_key = key.get_named_key_starting_with(base_key)?;
 
-        // handle the B#, Cb case correctly
-        Some(match (named_key.base_key, named_key.key_modifier) {
-            (BaseKey::B, KeyModifier::Sharp) => NamedNote::new(named_key, octave - 1),
-            (BaseKey::C, KeyModifier::Flat) => NamedNote::new(named_key, octave + 1),
-            _ => NamedNote::new(named_key, octave),
-        })
+        Some(NamedNote::spell(*self, named_key))
     }
 }
 
@@ -150,15 +174,42 @@ pub enum KeyModifier {
     Flat,
     Sharp,
     DoubleSharp,
+    DoubleFlat,
+    /// A quarter tone below the natural.
+    HalfFlat,
+    /// A quarter tone above the natural.
+    HalfSharp,
 }
 
 impl KeyModifier {
+    /// The number of whole semitones the modifier moves a key by. Quarter-tone
+    /// modifiers don't move the key: see `get_cents` for their full offset.
     pub fn get_value(&self) -> i8 {
         match self {
             KeyModifier::Flat => -1,
-            KeyModifier::Natural => 0,
+            KeyModifier::Natural | KeyModifier::HalfFlat | KeyModifier::HalfSharp => 0,
             KeyModifier::Sharp => 1,
             KeyModifier::DoubleSharp => 2,
+            KeyModifier::DoubleFlat => -2,
+        }
+    }
+    /// The modifier moving a key by the given number of whole semitones, if any.
+    pub fn from_value(value: i8) -> Option<KeyModifier> {
+        match value {
+            -2 => Some(KeyModifier::DoubleFlat),
+            -1 => Some(KeyModifier::Flat),
+            0 => Some(KeyModifier::Natural),
+            1 => Some(KeyModifier::Sharp),
+            2 => Some(KeyModifier::DoubleSharp),
+            _ => None,
+        }
+    }
+    /// The offset of the modifier in cents.
+    pub fn get_cents(&self) -> f64 {
+        match self {
+            KeyModifier::HalfFlat => -50.0,
+            KeyModifier::HalfSharp => 50.0,
+            _ => f64::from(self.get_value()) * 100.0,
         }
     }
 }
@@ -170,6 +221,9 @@ impl Display for KeyModifier {
             KeyModifier::Flat => "♭",
             KeyModifier::Sharp => "♯",
             KeyModifier::DoubleSharp => "𝄪",
+            KeyModifier::DoubleFlat => "𝄫",
+            KeyModifier::HalfFlat => "𝄳",
+            KeyModifier::HalfSharp => "𝄲",
         };
         write!(f, "{}", key_modifier_str)
     }
@@ -193,7 +247,7 @@ pub enum BaseKey {
 }
 
 impl BaseKey {
-    pub fn to_key(&self) -> Key {
+    pub fn to_key(self) -> Key {
         let key = match self {
             Self::C => 0,
             Self::D => 2,
@@ -258,16 +312,39 @@ impl NamedKey {
     pub fn get_components(&self) -> (BaseKey, KeyModifier) {
         (self.base_key, self.key_modifier)
     }
-    pub fn to_key(&self) -> Key {
+    pub fn to_key(self) -> Key {
         self.base_key.to_key() + &self.key_modifier.get_value()
     }
+    /// The key signature of the major (or minor) key starting on this key, as the
+    /// number of sharps (positive) or flats (negative). Theoretical keys such as
+    /// G♯ major get more than 7 sharps. Keys a quarter tone away from a natural, sharp
+    /// or flat have no key signature, and give None.
+    pub fn key_signature(&self, minor: bool) -> Option<i8> {
+        if matches!(self.key_modifier, KeyModifier::HalfFlat | KeyModifier::HalfSharp) {
+            return None;
+        }
+        // Position on the circle of fifths, starting from C.
+        let base_fifths = match self.base_key {
+            BaseKey::F => -1,
+            BaseKey::C => 0,
+            BaseKey::G => 1,
+            BaseKey::D => 2,
+            BaseKey::A => 3,
+            BaseKey::E => 4,
+            BaseKey::B => 5,
+        };
+        // A minor key shares its key signature with the major key a minor third above.
+        let mode_fifths = if minor { -3 } else { 0 };
+        Some(base_fifths + 7 * self.key_modifier.get_value() + mode_fifths)
+    }
 }
 
+#[cfg(feature = "std")]
 impl FromStr for NamedKey {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re = Regex::new("^([A-G])([b♭#♯x𝄪])?$").unwrap();
+        let re = Regex::new("^([A-G])(bb|[b♭#♯x𝄪𝄫d𝄳+𝄲])?$").unwrap();
         let captures = re
             .captures(s)
             .ok_or_else(|| format!("Invalid key: {}", s))?;
@@ -289,6 +366,9 @@ impl FromStr for NamedKey {
                 "b" | "♭" => Ok(KeyModifier::Flat),
                 "#" | "♯" => Ok(KeyModifier::Sharp),
                 "x" | "𝄪" => Ok(KeyModifier::DoubleSharp),
+                "bb" | "𝄫" => Ok(KeyModifier::DoubleFlat),
+                "d" | "𝄳" => Ok(KeyModifier::HalfFlat),
+                "+" | "𝄲" => Ok(KeyModifier::HalfSharp),
                 _ => Err(format!("Invalid key: {}", s)),
             },
         }?;
@@ -320,17 +400,56 @@ impl NamedNote {
     pub fn new(key: NamedKey, octave: i8) -> Self {
         NamedNote { key, octave }
     }
-    pub fn to_note(&self) -> Note {
+    /// Spell `note` as `key`, which should be one of its names. The octave follows the
+    /// letter rather than the sound, so that e.g. B♯3 and C𝄫5 are spelled correctly.
+    pub(crate) fn spell(note: Note, key: NamedKey) -> Self {
+        let natural = i16::from(note.0) - i16::from(key.key_modifier.get_value());
+        let octave = (natural - i16::from(key.base_key.to_key().get_value())).div_euclid(12) - 1;
+        NamedNote::new(key, octave.try_into().unwrap())
+    }
+    pub fn to_note(self) -> Note {
         // Do it this way to handle Cb5 is B4, B#4 is C5
         Note::compose(self.key.base_key.to_key(), self.octave) + &self.key.key_modifier.get_value()
     }
+    /// The note as `to_note` gives it, or None if it falls outside the MIDI range of 0
+    /// to 127, e.g. for A9 or C♭-1.
+    pub fn try_to_note(self) -> Option<Note> {
+        let height = (i16::from(self.octave) + 1) * 12
+            + i16::from(self.key.base_key.to_key().get_value())
+            + i16::from(self.key.key_modifier.get_value());
+        u8::try_from(height).ok().filter(|height| *height <= 127).map(Note)
+    }
+    /// How far the note is from the MIDI note it is played as, in cents. This is only
+    /// non-zero for quarter tones.
+    pub fn get_cents_offset(&self) -> f64 {
+        let modifier = self.key.key_modifier;
+        modifier.get_cents() - f64::from(modifier.get_value()) * 100.0
+    }
+    /// Move the note by the given number of semitones. The result is spelled as a
+    /// natural when possible, and otherwise with the `prefer`red sharp or flat.
+    pub fn transpose(&self, semitones: i8, prefer: KeyModifier) -> NamedNote {
+        let note = self.to_note() + &semitones;
+        let (key, _) = note.decompose();
+        let spellings: Vec<NamedKey> = BaseKey::C
+            .get_keys_in_order()
+            .filter_map(|base_key| key.get_named_key_starting_with(&base_key))
+            .collect();
+        let named_key = spellings
+            .iter()
+            .find(|named_key| named_key.key_modifier == KeyModifier::Natural)
+            .or_else(|| spellings.iter().find(|named_key| named_key.key_modifier == prefer))
+            .copied()
+            .unwrap_or_else(|| key.get_default_named_key());
+        note.get_named_note_starting_with(&named_key.base_key).unwrap()
+    }
 }
 
+#[cfg(feature = "std")]
 impl FromStr for NamedNote {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re = Regex::new("^([A-G][b♭#♯x𝄪]?)(-1|[0-9])$").unwrap();
+        let re = Regex::new("^([A-G](?:bb|[b♭#♯x𝄪𝄫d𝄳+𝄲])?)(-1|[0-9])$").unwrap();
         let captures = re
             .captures(s)
             .ok_or_else(|| format!("Invalid note:{}", s))?;
@@ -353,3 +472,99 @@ impl Debug for NamedNote {
         write!(f, "{}", self)
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::super::tuning::get_pitch_bend;
+    use super::*;
+
+    #[test]
+    fn can_find_notes_from_frequencies() {
+        let (note, cents) = Note::from_frequency(445.0, 440.0);
+        assert_eq!(note.0, 69);
+        assert!((cents - 19.56).abs() < 0.01);
+        assert_eq!(Note::from_frequency(440.0, 440.0).1, 0.0);
+        let (note, cents) = Note::from_frequency(261.0, 440.0);
+        assert_eq!(note.0, 60);
+        assert!((-5.0..0.0).contains(&cents));
+        // With A4 at 415 Hz, 440 Hz is a semitone higher.
+        assert_eq!(Note::from_frequency(440.0, 415.0).0 .0, 70);
+
+        // Frequencies beyond the MIDI range clamp to it.
+        let (note, cents) = Note::from_frequency(1.0, 440.0);
+        assert_eq!(note.0, 0);
+        assert!(cents < -1000.0);
+        let (note, cents) = Note::from_frequency(20000.0, 440.0);
+        assert_eq!(note.0, 127);
+        assert!(cents > 800.0);
+        assert_eq!(Note::from_frequency(0.0, 440.0).1, f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn can_transpose_named_notes() {
+        let c4 = str::parse::<NamedNote>("C4").unwrap();
+        assert_eq!(c4.transpose(3, KeyModifier::Flat), str::parse::<NamedNote>("Eb4").unwrap());
+        assert_eq!(c4.transpose(3, KeyModifier::Sharp), str::parse::<NamedNote>("D#4").unwrap());
+        assert_eq!(c4.transpose(4, KeyModifier::Flat), str::parse::<NamedNote>("E4").unwrap());
+        assert_eq!(c4.transpose(-1, KeyModifier::Flat), str::parse::<NamedNote>("B3").unwrap());
+        assert_eq!(c4.transpose(-13, KeyModifier::Sharp), str::parse::<NamedNote>("B2").unwrap());
+
+        let b4 = str::parse::<NamedNote>("B4").unwrap();
+        assert_eq!(b4.transpose(1, KeyModifier::Sharp), str::parse::<NamedNote>("C5").unwrap());
+        assert_eq!(b4.transpose(2, KeyModifier::Flat), str::parse::<NamedNote>("Db5").unwrap());
+    }
+
+    #[test]
+    fn can_get_key_signatures() {
+        let major_keys = [("C", 0), ("D", 2), ("F", -1), ("Bb", -2), ("F#", 6), ("Cb", -7), ("C#", 7), ("G#", 8)];
+        for (key, expected_key_signature) in major_keys {
+            let named_key = str::parse::<NamedKey>(key).unwrap();
+            assert_eq!(named_key.key_signature(false), Some(expected_key_signature), "{} major", key);
+        }
+        let minor_keys = [("A", 0), ("E", 1), ("D", -1), ("C", -3), ("F#", 3), ("Eb", -6), ("A#", 7), ("Ab", -7)];
+        for (key, expected_key_signature) in minor_keys {
+            let named_key = str::parse::<NamedKey>(key).unwrap();
+            assert_eq!(named_key.key_signature(true), Some(expected_key_signature), "{} minor", key);
+        }
+        assert_eq!(str::parse::<NamedKey>("C𝄲").unwrap().key_signature(false), None);
+        assert_eq!(str::parse::<NamedKey>("Ed").unwrap().key_signature(true), None);
+    }
+
+    #[test]
+    fn can_parse_quarter_tones() {
+        let c_half_sharp = str::parse::<NamedNote>("C𝄲4").unwrap();
+        assert_eq!(c_half_sharp, str::parse::<NamedNote>("C+4").unwrap());
+        assert_eq!(c_half_sharp.to_string(), "C𝄲4");
+        assert_eq!(c_half_sharp.to_note(), Note(60));
+        assert_eq!(c_half_sharp.get_cents_offset(), 50.0);
+        // A quarter of the two-semitone pitch bend range.
+        assert_eq!(get_pitch_bend(c_half_sharp.get_cents_offset()).as_int(), 2048);
+
+        let e_half_flat = str::parse::<NamedNote>("Ed4").unwrap();
+        assert_eq!(e_half_flat.to_string(), "E𝄳4");
+        assert_eq!(e_half_flat.to_note(), Note(64));
+        assert_eq!(e_half_flat.get_cents_offset(), -50.0);
+
+        assert_eq!(str::parse::<NamedNote>("Eb4").unwrap().get_cents_offset(), 0.0);
+    }
+
+    #[test]
+    fn can_parse_unicode_accidentals() {
+        for (unicode, ascii) in [("B\u{266D}", "Bb"), ("F\u{266F}", "F#"), ("F\u{1D12A}", "Fx"), ("E\u{1D12B}", "Ebb")] {
+            assert_eq!(str::parse::<NamedKey>(unicode).unwrap(), str::parse::<NamedKey>(ascii).unwrap());
+        }
+        let b_flat = str::parse::<NamedNote>("B\u{266D}4").unwrap();
+        assert_eq!(b_flat.to_note(), Note(70));
+        assert_eq!(b_flat.to_string(), "B♭4");
+        assert_eq!(str::parse::<NamedNote>("C\u{1D12A}4").unwrap().to_note(), Note(62));
+    }
+
+    #[test]
+    fn can_try_to_convert_notes() {
+        assert_eq!(str::parse::<NamedNote>("G9").unwrap().try_to_note(), Some(Note(127)));
+        assert_eq!(str::parse::<NamedNote>("C-1").unwrap().try_to_note(), Some(Note(0)));
+        assert_eq!(str::parse::<NamedNote>("Cb4").unwrap().try_to_note(), Some(Note(59)));
+        assert_eq!(str::parse::<NamedNote>("A9").unwrap().try_to_note(), None);
+        assert_eq!(str::parse::<NamedNote>("Cb-1").unwrap().try_to_note(), None);
+    }
+}
@@ -0,0 +1,64 @@
+//! Moira generates music from basic music theory: keys, scales, and tracks
+//! (voices, chords and drum patterns) that are written out as MIDI.
+//!
+//! ```
+//! # #[cfg(feature = "std")] {
+//! let piece = moira::parse_piece(
+//!     r#"{
+//!         "bpm": 120,
+//!         "tracks": [
+//!             {"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0, 2, 4]}
+//!         ]
+//!     }"#,
+//! )
+//! .unwrap();
+//!
+//! let mut buffer = Vec::new();
+//! piece.write_midi(&mut buffer).unwrap();
+//! assert_eq!(&buffer[..4], b"MThd");
+//! # }
+//! ```
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod chord;
+pub mod drums;
+pub mod groove;
+#[cfg(feature = "std")]
+pub mod json_input;
+#[cfg(feature = "std")]
+mod json_output;
+pub mod key;
+mod math;
+#[cfg(feature = "std")]
+pub mod midi_input;
+pub mod ornament;
+#[cfg(feature = "play")]
+pub mod playback;
+pub mod scale;
+pub mod track;
+pub mod tuning;
+
+pub use chord::{
+    drop_voicing, identify_chord, voice_lead, walking_bass, Chord, ChordAnalysis, DropKind, IntervalQuality, StrumDirection,
+    TriadQuality,
+};
+pub use drums::{DrumHit, DrumPattern};
+#[cfg(feature = "std")]
+pub use json_input::{
+    parse_piece, parse_piece_from_path, parse_piece_reader, parse_piece_strict, parse_piece_strict_with_scales,
+    parse_piece_with_scales, render_dir,
+};
+pub use groove::Groove;
+pub use key::{Key, NamedKey, NamedNote, Note};
+#[cfg(feature = "std")]
+pub use midi_input::{read_named_notes, read_time_signature};
+#[cfg(feature = "std")]
+pub use scale::ScaleRegistry;
+pub use scale::Scale;
+#[cfg(feature = "std")]
+pub use track::Piece;
+pub use track::{TimedNote, Track, Voice, TICKS_PER_BEAT};
+pub use tuning::Tuning;
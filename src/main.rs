@@ -1,50 +1,134 @@
 use std::fs::File;
+use std::process::ExitCode;
 
-mod chord;
-mod json_input;
-mod key;
-mod scale;
-mod track;
+use moira::parse_piece_from_path;
 
-use key::NamedKey;
-use scale::Scale;
-use track::{Piece, Voice, TICKS_PER_BEAT};
+const USAGE: &str = "Usage:
+    moira render <input.json> <output.mid>
+    moira play <input.json>";
 
-fn main() {
+#[derive(Debug, PartialEq)]
+enum Command {
+    Render { input: String, output: String },
+    Play { input: String },
+}
+
+/// Parse the arguments that follow the program name into a `Command`.
+///
+/// # Errors
+/// - If the subcommand is missing or unknown
+/// - If the subcommand gets the wrong number of arguments
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    match args {
+        [command, input, output] if command == "render" => Ok(Command::Render {
+            input: input.clone(),
+            output: output.clone(),
+        }),
+        [command, input] if command == "play" => Ok(Command::Play {
+            input: input.clone(),
+        }),
+        [command, ..] if command == "render" || command == "play" => {
+            Err(format!("Wrong number of arguments for {}!", command))
+        }
+        [command, ..] => Err(format!("Unknown command {}!", command)),
+        [] => Err("No command given!".to_string()),
+    }
+}
+
+fn run(command: &Command) -> Result<(), String> {
+    match command {
+        Command::Render { input, output } => {
+            let piece = parse_piece_from_path(input)?;
+            let mut file = File::create(output)
+                .map_err(|err| format!("Could not create {}: {}", output, err))?;
+            piece
+                .write_midi(&mut file)
+                .map_err(|err| format!("Could not write {}: {}", output, err))
+        }
+        #[cfg(feature = "play")]
+        Command::Play { input } => parse_piece_from_path(input)?.play(),
+        #[cfg(not(feature = "play"))]
+        Command::Play { .. } => Err("Playing needs the play feature!".to_string()),
+    }
+}
+
+fn main() -> ExitCode {
     env_logger::init();
 
-    let c = str::parse::<NamedKey>("C").unwrap();
-    let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match parse_args(&args).and_then(|command| run(&command)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
 
-    let wtc_1_1_prelude_voice = Box::new(Voice {
-        id: "voice_1".to_string(),
-        start: 0,
-        scale: c_major_scale.clone(),
-        octave: 4,
-        notes: [0, 2, 4, 7, 9, 4, 7, 9]
-            .into_iter()
-            .map(|position| (Some(position), TICKS_PER_BEAT / 2))
-            .collect(),
-    });
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
 
-    let wtc_1_1_prelude = Piece {
-        bpm: 120,
-        tracks: vec![wtc_1_1_prelude_voice.clone()],
-    };
+    #[test]
+    fn can_parse_args() {
+        assert_eq!(
+            parse_args(&args(&["render", "in.json", "out.mid"])),
+            Ok(Command::Render {
+                input: "in.json".to_string(),
+                output: "out.mid".to_string()
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["play", "in.json"])),
+            Ok(Command::Play {
+                input: "in.json".to_string()
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["render", "in.json"])),
+            Err("Wrong number of arguments for render!".to_string())
+        );
+        assert_eq!(
+            parse_args(&args(&["compose"])),
+            Err("Unknown command compose!".to_string())
+        );
+        assert_eq!(parse_args(&[]), Err("No command given!".to_string()));
+    }
 
-    println!("{}", wtc_1_1_prelude_voice);
+    #[test]
+    #[cfg(not(feature = "play"))]
+    fn cannot_play_without_the_play_feature() {
+        let command = Command::Play { input: "in.json".to_string() };
+        assert_eq!(run(&command), Err("Playing needs the play feature!".to_string()));
+    }
 
-    let mut buffer = File::create("results/wtc_1_1_prelude.mid").unwrap();
+    #[test]
+    fn can_render_files() {
+        let dir = std::env::temp_dir().join(format!("moira_cli_render_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("piece.json"), dir.join("piece.mid"));
+        fs::write(
+            &input,
+            r#"{"bpm": 120, "tracks": [{"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0, 2, 4]}]}"#,
+        )
+        .unwrap();
 
-    wtc_1_1_prelude.write_midi(&mut buffer).unwrap();
+        let command = parse_args(&args(&[
+            "render",
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+        ]))
+        .unwrap();
+        run(&command).unwrap();
 
-    let wtc_1_1_fugue =
-        json_input::parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
-    let mut buffer = File::create("results/wtc_1_1_fugue.mid").unwrap();
-    wtc_1_1_fugue.write_midi(&mut buffer).unwrap();
+        assert!(output.exists());
+        assert_eq!(&fs::read(&output).unwrap()[..4], b"MThd");
 
-    let ballad = 
-        json_input::parse_piece(include_str!("../examples/ballad.json")).unwrap();
-    let mut buffer = File::create("results/ballad.mid").unwrap();
-    ballad.write_midi(&mut buffer).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,77 @@
+// This module provides the float functions the crate needs that are not in core, so
+// that it can be used without std.
+
+/// Round to the nearest integer, away from zero at halfway like `f64::round`.
+/// Values beyond the range of i64 saturate.
+pub(crate) fn round(x: f64) -> f64 {
+    let truncated = x as i64 as f64;
+    let fraction = x - truncated;
+    if fraction >= 0.5 {
+        truncated + 1.0
+    } else if fraction <= -0.5 {
+        truncated - 1.0
+    } else {
+        truncated
+    }
+}
+
+/// The base 2 logarithm of a positive, finite and normal x, precise to about 1e-15.
+pub(crate) fn log2(x: f64) -> f64 {
+    // x is mantissa * 2^exponent, with a mantissa between 1 and 2.
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64 - 1023;
+    let mantissa = f64::from_bits((bits & 0x000f_ffff_ffff_ffff) | 0x3ff0_0000_0000_0000);
+    // ln(m) = 2 atanh(t) with t = (m - 1) / (m + 1) below 1/3, so that the series of
+    // t^(2k + 1) / (2k + 1) converges quickly.
+    let t = (mantissa - 1.0) / (mantissa + 1.0);
+    let (mut power, mut ln_mantissa) = (t, 0.0);
+    for k in 0..20 {
+        ln_mantissa += power / f64::from(2 * k + 1);
+        power *= t * t;
+    }
+    exponent as f64 + 2.0 * ln_mantissa / core::f64::consts::LN_2
+}
+
+/// The next number of the SplitMix64 generator with the given state, which is advanced.
+/// This is enough for reproducible choices: it is not meant to be unpredictable.
+pub(crate) fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// The next number of the generator with the given state, between 0 (included) and 1
+/// (excluded).
+pub(crate) fn next_random_unit(state: &mut u64) -> f64 {
+    // The 53 high bits fill the mantissa of a f64 exactly.
+    (next_random(state) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_round() {
+        let rounded = [0.0, 0.4, 0.5, 1.49, 2.5, -0.5, -1.2, -2.5, 8191.6, -8192.4].map(round);
+        assert_eq!(rounded, [0.0, 0.0, 1.0, 1.0, 3.0, -1.0, -1.0, -3.0, 8192.0, -8192.0]);
+    }
+
+    #[test]
+    fn can_take_logarithms() {
+        assert_eq!([1.0, 2.0, 1024.0, 0.25].map(log2), [0.0, 1.0, 10.0, -2.0]);
+        assert!((log2(3.0) - 1.584_962_500_721_156).abs() < 1e-12);
+        assert!((log2(445.0 / 440.0) - 0.016_301_812_329_100_64).abs() < 1e-12);
+    }
+
+    #[test]
+    fn can_draw_random_numbers() {
+        let (mut state, mut same_state) = (42, 42);
+        let draws: [f64; 100] = core::array::from_fn(|_| next_random_unit(&mut state));
+        assert!(draws.iter().all(|draw| (0.0..1.0).contains(draw)));
+        assert_eq!(draws, core::array::from_fn(|_| next_random_unit(&mut same_state)));
+        assert_ne!(next_random(&mut 1), next_random(&mut 2));
+    }
+}
@@ -0,0 +1,231 @@
+// This module reads notes back from MIDI files. MIDI only gives the height of each
+// note, so notes are spelled according to the file's key signature, if any. Likewise,
+// the meter is guessed from the onsets of the notes when there is no time signature.
+
+use std::collections::BTreeMap;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use super::key::{NamedNote, Note};
+use super::scale::Scale;
+
+/// Spell `note` as it is in `scale`, or with sharps if it is not in the scale or
+/// there is no scale.
+pub(crate) fn spell_note(note: Note, scale: Option<&Scale>) -> NamedNote {
+    scale.and_then(|scale| scale.spell(note)).unwrap_or_else(|| {
+        let (key, _) = note.decompose();
+        note.get_named_note_starting_with(&key.get_default_named_key().base_key).unwrap()
+    })
+}
+
+/// Read the notes of a MIDI file, track after track, in the order they start. Notes
+/// are spelled according to the first key signature event of the file (so that a
+/// piece in D major gets F# rather than Gb), or with sharps if there is none.
+///
+/// # Errors
+/// - if the bytes are not a valid MIDI file.
+pub fn read_named_notes(bytes: &[u8]) -> Result<Vec<NamedNote>, String> {
+    let smf = Smf::parse(bytes).map_err(|e| format!("Invalid MIDI file: {}!", e))?;
+
+    let scale = smf
+        .tracks
+        .iter()
+        .flatten()
+        .find_map(|event| match event.kind {
+            TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => Some((sharps, minor)),
+            _ => None,
+        })
+        .and_then(|(sharps, minor)| Scale::from_key_signature(sharps, minor));
+
+    Ok(smf
+        .tracks
+        .iter()
+        .flatten()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } if vel > 0 => {
+                Some(spell_note(Note(key.as_int()), scale.as_ref()))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Read the time signature of a MIDI file from its first time signature event, or
+/// guess it from the onsets of its notes with `infer_time_signature` if there is none.
+///
+/// # Errors
+/// - if the bytes are not a valid MIDI file.
+pub fn read_time_signature(bytes: &[u8]) -> Result<(u8, u8), String> {
+    let smf = Smf::parse(bytes).map_err(|e| format!("Invalid MIDI file: {}!", e))?;
+
+    let time_signature = smf.tracks.iter().flatten().find_map(|event| match event.kind {
+        TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+            Some((numerator, 1u8.checked_shl(u32::from(denominator)).unwrap_or(4)))
+        }
+        _ => None,
+    });
+    if let Some(time_signature) = time_signature {
+        return Ok(time_signature);
+    }
+
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int(),
+        // Without beats, there is no meter to find.
+        Timing::Timecode(..) => return Ok((4, 4)),
+    };
+    let onsets: Vec<u32> = smf
+        .tracks
+        .iter()
+        .flat_map(|track| {
+            let mut tick: u32 = 0;
+            track.iter().filter_map(move |event| {
+                tick = tick.saturating_add(event.delta.as_int());
+                match event.kind {
+                    TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } if vel > 0 => Some(tick),
+                    _ => None,
+                }
+            })
+        })
+        .collect();
+    Ok(infer_time_signature(&onsets, ticks_per_beat))
+}
+
+/// Guess the time signature of notes starting at the given ticks, with `ticks_per_beat`
+/// ticks per quarter note. The onsets are counted on a grid of eighths, and the bar is
+/// taken to be the length at which the pattern of onsets repeats best: 8 eighths for
+/// 4/4, or 6 eighths for 3/4 and 6/8, which are told apart by whether the onsets group
+/// by quarters or by dotted quarters. Falls back to 4/4 when nothing stands out.
+pub fn infer_time_signature(onsets: &[u32], ticks_per_beat: u16) -> (u8, u8) {
+    let eighth_ticks = u32::from(ticks_per_beat / 2);
+    if eighth_ticks == 0 || onsets.is_empty() {
+        return (4, 4);
+    }
+
+    // How many notes start on each eighth; notes off the grid are left out. Only the
+    // eighths with onsets are kept, so that notes far apart don't take up memory.
+    let length = onsets.iter().max().unwrap() / eighth_ticks + 1;
+    let mut weights: BTreeMap<u32, f64> = BTreeMap::new();
+    for onset in onsets.iter().filter(|onset| onset.is_multiple_of(eighth_ticks)) {
+        *weights.entry(onset / eighth_ticks).or_insert(0.0) += 1.0;
+    }
+
+    // The autocorrelation at each lag, averaged over the pairs of eighths it compares.
+    let autocorrelation = |lag: u32| -> f64 {
+        if lag >= length {
+            return 0.0;
+        }
+        let sum: f64 = weights
+            .iter()
+            .filter_map(|(eighth, weight)| {
+                let other = weights.get(&eighth.checked_add(lag)?)?;
+                Some(weight * other)
+            })
+            .sum();
+        sum / f64::from(length - lag)
+    };
+
+    if autocorrelation(6) > autocorrelation(8) {
+        if autocorrelation(3) > autocorrelation(2) {
+            (6, 8)
+        } else {
+            (3, 4)
+        }
+    } else {
+        (4, 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Format, Header, TrackEvent};
+
+    use super::*;
+
+    fn midi_file(key_signature: Option<(i8, bool)>, keys: &[u8]) -> Vec<u8> {
+        let mut track = Vec::new();
+        if let Some((sharps, minor)) = key_signature {
+            track.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)),
+            });
+        }
+        for key in keys {
+            for vel in [100, 0] {
+                track.push(TrackEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: 0.into(),
+                        message: MidiMessage::NoteOn { key: (*key).into(), vel: vel.into() },
+                    },
+                });
+            }
+        }
+        track.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(24.into())),
+            tracks: vec![track],
+        };
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn names(notes: &[NamedNote]) -> Vec<String> {
+        notes.iter().map(|note| note.to_string()).collect()
+    }
+
+    #[test]
+    fn can_spell_imported_notes() {
+        let d_major = midi_file(Some((2, false)), &[62, 66, 73]);
+        assert_eq!(names(&read_named_notes(&d_major).unwrap()), ["D4", "F♯4", "C♯5"]);
+
+        // Notes outside the key, or without any key signature, are spelled with sharps.
+        let f_major = midi_file(Some((-1, false)), &[70, 66]);
+        assert_eq!(names(&read_named_notes(&f_major).unwrap()), ["B♭4", "F♯4"]);
+        let no_key = midi_file(None, &[70]);
+        assert_eq!(names(&read_named_notes(&no_key).unwrap()), ["A♯4"]);
+
+        assert!(read_named_notes(b"not midi").is_err());
+    }
+
+    #[test]
+    fn can_infer_time_signatures() {
+        let onsets_in_eighths = |pattern: &[u32], bars: u32| -> Vec<u32> {
+            let bar_eighths: u32 = pattern.iter().sum();
+            (0..bars)
+                .flat_map(|bar| {
+                    pattern.iter().scan(bar * bar_eighths, |onset, duration| {
+                        let note_onset = *onset;
+                        *onset += duration;
+                        Some(note_onset * 12)
+                    })
+                })
+                .collect()
+        };
+        // Half and quarter notes, the onsets of a waltz.
+        assert_eq!(infer_time_signature(&onsets_in_eighths(&[4, 2], 8), 24), (3, 4));
+        // Quarter and eighth notes, as in a jig.
+        assert_eq!(infer_time_signature(&onsets_in_eighths(&[2, 1, 2, 1], 8), 24), (6, 8));
+        assert_eq!(infer_time_signature(&onsets_in_eighths(&[2, 2, 4], 8), 24), (4, 4));
+        assert_eq!(infer_time_signature(&[], 24), (4, 4));
+        // A note at the very last tick doesn't need an eighth-by-eighth grid up to it.
+        let mut waltz = onsets_in_eighths(&[4, 2], 8);
+        waltz.push(u32::MAX);
+        assert_eq!(infer_time_signature(&waltz, 24), (3, 4));
+
+        // A time signature event wins over the onsets.
+        let mut with_time_signature = midi_file(None, &[60]);
+        assert_eq!(read_time_signature(&with_time_signature).unwrap(), (4, 4));
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(24.into())),
+            tracks: vec![vec![
+                TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::TimeSignature(6, 3, 36, 8)) },
+                TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) },
+            ]],
+        };
+        with_time_signature.clear();
+        smf.write_std(&mut with_time_signature).unwrap();
+        assert_eq!(read_time_signature(&with_time_signature).unwrap(), (6, 8));
+    }
+}
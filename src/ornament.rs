@@ -0,0 +1,132 @@
+// This module provides functions expanding a principal note into an ornament:
+// trill: the note alternating with its upper neighbor.
+// mordent: the note, its lower neighbor and the note again.
+// turn: the upper neighbor, the note, the lower neighbor and the note again.
+//
+// Positions are scale positions, so the neighbors are the adjacent notes of the
+// voice's scale, e.g. a half step or a whole step away in a major scale.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::scale::Scale;
+use super::track::TimedNote;
+
+/// Alternate the note at `position` of `scale` in `octave` with its upper neighbor,
+/// starting on the note, changing every `note_duration` ticks for `duration` ticks.
+/// The last note takes up whatever time is left.
+///
+/// # Errors
+/// - if a note of the trill falls outside the MIDI range, see `get_ornament_positions`.
+pub fn trill(position: i8, duration: u32, note_duration: u32, scale: &Scale, octave: i8) -> Result<Vec<TimedNote>, String> {
+    let neighbors = get_ornament_positions(position, &[0, 1], scale, octave)?;
+    let note_duration = note_duration.max(1);
+    let mut notes = Vec::new();
+    let mut remaining = duration;
+    while remaining > 0 {
+        let duration = if remaining < 2 * note_duration { remaining } else { note_duration };
+        notes.push(TimedNote::new(Some(neighbors[notes.len() % 2]), duration));
+        remaining -= duration;
+    }
+    Ok(notes)
+}
+
+/// Play the note at `position` of `scale` in `octave`, quickly followed by its lower
+/// neighbor and the note again, which is held for the rest of `duration`.
+///
+/// # Errors
+/// - if a note of the mordent falls outside the MIDI range, see `get_ornament_positions`.
+pub fn mordent(position: i8, duration: u32, scale: &Scale, octave: i8) -> Result<Vec<TimedNote>, String> {
+    Ok(expand(&get_ornament_positions(position, &[0, -1, 0], scale, octave)?, duration))
+}
+
+/// Play the upper neighbor of the note at `position` of `scale` in `octave`, the note,
+/// its lower neighbor and the note again, in equal parts of `duration`.
+///
+/// # Errors
+/// - if a note of the turn falls outside the MIDI range, see `get_ornament_positions`.
+pub fn turn(position: i8, duration: u32, scale: &Scale, octave: i8) -> Result<Vec<TimedNote>, String> {
+    Ok(expand(&get_ornament_positions(position, &[1, 0, -1, 0], scale, octave)?, duration))
+}
+
+/// The positions `steps` away from `position`, in steps of `scale`.
+///
+/// # Errors
+/// - if a position doesn't fit in an i8, or its note in `octave` is outside the MIDI range.
+fn get_ornament_positions(position: i8, steps: &[i8], scale: &Scale, octave: i8) -> Result<Vec<i8>, String> {
+    steps
+        .iter()
+        .map(|step| {
+            position
+                .checked_add(*step)
+                .filter(|neighbor| scale.try_get_note(*neighbor, octave).is_some())
+                .ok_or_else(|| format!("Ornament of position {} in octave {} goes outside the MIDI range!", position, octave))
+        })
+        .collect()
+}
+
+/// Play each of the `positions` for a quarter of `duration`, except the last one,
+/// which is held for the rest of it.
+fn expand(positions: &[i8], duration: u32) -> Vec<TimedNote> {
+    let part = duration / 4;
+    if part == 0 {
+        return vec![TimedNote::new(positions.last().copied(), duration)];
+    }
+    let last = positions.len() - 1;
+    positions
+        .iter()
+        .enumerate()
+        .map(|(i, position)| {
+            let duration = if i == last { duration - part * u32::try_from(last).unwrap() } else { part };
+            TimedNote::new(Some(*position), duration)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::key::{BaseKey, KeyModifier, NamedKey};
+    use super::super::track::TICKS_PER_BEAT;
+    use super::*;
+    use alloc::string::ToString;
+
+    fn positions_and_durations(notes: &[TimedNote]) -> Vec<(Option<i8>, u32)> {
+        notes.iter().map(|note| (note.position, note.duration)).collect()
+    }
+
+    #[test]
+    fn can_expand_ornaments() {
+        let c = NamedKey::new(BaseKey::C, KeyModifier::Natural);
+        let c_major_scale = Scale::new(c, [0, 2, 4, 5, 7, 9, 11].to_vec()).unwrap();
+        let mordent_notes = mordent(2, TICKS_PER_BEAT, &c_major_scale, 4).unwrap();
+        assert_eq!(positions_and_durations(&mordent_notes), [(Some(2), 6), (Some(1), 6), (Some(2), 12)]);
+
+        assert_eq!(
+            positions_and_durations(&turn(2, TICKS_PER_BEAT, &c_major_scale, 4).unwrap()),
+            [(Some(3), 6), (Some(2), 6), (Some(1), 6), (Some(2), 6)]
+        );
+
+        let trill_notes = trill(4, TICKS_PER_BEAT, TICKS_PER_BEAT / 8, &c_major_scale, 4).unwrap();
+        assert_eq!(trill_notes.len(), 8);
+        assert_eq!(trill_notes.iter().map(|note| note.duration).sum::<u32>(), TICKS_PER_BEAT);
+        let positions: Vec<i8> = trill_notes.iter().map(|note| note.position.unwrap()).collect();
+        assert_eq!(positions, [4, 5, 4, 5, 4, 5, 4, 5]);
+
+        // The last note of a trill that doesn't divide evenly is held longer.
+        assert_eq!(
+            positions_and_durations(&trill(4, 10, 3, &c_major_scale, 4).unwrap()),
+            [(Some(4), 3), (Some(5), 3), (Some(4), 4)]
+        );
+
+        // Neighbors beyond the i8 range or the MIDI range are an error.
+        let error = "Ornament of position 127 in octave -1 goes outside the MIDI range!".to_string();
+        assert_eq!(turn(127, TICKS_PER_BEAT, &c_major_scale, -1).err(), Some(error.clone()));
+        assert_eq!(trill(127, TICKS_PER_BEAT, 6, &c_major_scale, -1).err(), Some(error));
+        assert!(mordent(0, TICKS_PER_BEAT, &c_major_scale, -1).is_err());
+        // G9 is the highest MIDI note, so it has no upper neighbor.
+        assert!(mordent(4, TICKS_PER_BEAT, &c_major_scale, 9).is_ok());
+        assert!(trill(4, TICKS_PER_BEAT, 6, &c_major_scale, 9).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+// This module plays pieces in real time by sending their MIDI messages to a MIDI
+// output of the system, through midir, as they are due.
+
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use midir::{MidiOutput, MidiOutputConnection};
+use midly::{MetaMessage, TrackEventKind};
+
+use super::track::{get_microseconds_per_beat, merge_tracks, Piece, TICKS_PER_BEAT};
+
+/// The name moira connects to MIDI outputs under.
+const CLIENT_NAME: &str = "moira";
+
+impl Piece {
+    /// The MIDI messages of the piece in the order they are sent, each with the time
+    /// since the start of the piece at which it is due, following the tempo changes.
+    ///
+    /// # Errors
+    /// - if the piece can't be written as MIDI, e.g. because of invalid velocities or channels.
+    pub fn playback_schedule(&self) -> Result<Vec<(Duration, Vec<u8>)>, String> {
+        let tracks = self.to_midi_tracks().map_err(|err| err.to_string())?;
+
+        let mut schedule = Vec::new();
+        let (mut microseconds, mut microseconds_per_beat) = (0, u64::from(get_microseconds_per_beat(self.bpm, self.tempo_unit_ticks)?));
+        for event in merge_tracks(tracks) {
+            microseconds += u64::from(event.delta.as_int()) * microseconds_per_beat / u64::from(TICKS_PER_BEAT);
+            if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                microseconds_per_beat = u64::from(tempo.as_int());
+            }
+            if let Some(live_event) = event.kind.as_live_event() {
+                let mut message = Vec::new();
+                live_event.write_std(&mut message).map_err(|err| err.to_string())?;
+                schedule.push((Duration::from_micros(microseconds), message));
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Play the piece on the system MIDI output, blocking until it ends. The output is
+    /// the first port whose name contains the MOIRA_MIDI_PORT environment variable, or
+    /// the first port if it isn't set.
+    ///
+    /// # Errors
+    /// - if there is no MIDI output, or none matching MOIRA_MIDI_PORT;
+    /// - if the piece can't be written as MIDI or sent to the output.
+    pub fn play(&self) -> Result<(), String> {
+        // The connection is closed when dropped.
+        let mut connection = connect_midi_output(std::env::var("MOIRA_MIDI_PORT").ok().as_deref())?;
+        self.play_with(|message| connection.send(message).map_err(|err| format!("Could not send MIDI message: {}", err)))
+    }
+
+    /// Play the piece on `output`, sending each MIDI message when it is due. Rests are
+    /// waited out like any other time between messages.
+    ///
+    /// # Errors
+    /// - if the piece can't be written as MIDI or sent to `output`.
+    pub fn play_to<W: Write>(&self, output: &mut W) -> Result<(), String> {
+        self.play_with(|message| {
+            output
+                .write_all(message)
+                .and_then(|_| output.flush())
+                .map_err(|err| format!("Could not send MIDI message: {}", err))
+        })
+    }
+
+    /// Play the piece by calling `send` with each MIDI message when it is due.
+    fn play_with<F: FnMut(&[u8]) -> Result<(), String>>(&self, mut send: F) -> Result<(), String> {
+        let schedule = self.playback_schedule()?;
+
+        // Waiting until each message is due, rather than for the time between messages,
+        // keeps delays in sending from adding up.
+        let start = Instant::now();
+        for (time, message) in schedule {
+            if let Some(wait) = time.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+            send(&message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the first MIDI output whose name contains `port_name`, or to the first
+/// output if no name is given.
+///
+/// # Errors
+/// - if MIDI can't be used on this system;
+/// - if there is no output, or none matching `port_name`;
+/// - if the output can't be connected to.
+fn connect_midi_output(port_name: Option<&str>) -> Result<MidiOutputConnection, String> {
+    let midi_output = MidiOutput::new(CLIENT_NAME).map_err(|err| format!("Could not use MIDI: {}", err))?;
+    let ports = midi_output.ports();
+    let port = ports
+        .iter()
+        .find(|port| {
+            port_name.is_none_or(|port_name| {
+                midi_output.port_name(port).is_ok_and(|name| name.contains(port_name))
+            })
+        })
+        .ok_or_else(|| match port_name {
+            Some(port_name) => format!("No MIDI output named {}!", port_name),
+            None => "No MIDI output found!".to_string(),
+        })?;
+    midi_output
+        .connect(port, CLIENT_NAME)
+        .map_err(|err| format!("Could not connect to MIDI output: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NamedKey, Scale, TimedNote, Voice};
+
+    #[test]
+    fn can_schedule_playback() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+        let voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![
+                TimedNote::new(Some(0), TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT),
+                TimedNote::new(Some(2), TICKS_PER_BEAT),
+            ],
+        );
+        let mut piece = Piece::new(120, vec![Box::new(voice)]).unwrap();
+        // Twice as fast from the third beat on
+        piece.tempo_changes.push((2 * TICKS_PER_BEAT, 240));
+
+        let notes: Vec<(Duration, Vec<u8>)> = piece
+            .playback_schedule()
+            .unwrap()
+            .into_iter()
+            .filter(|(_, message)| message[0] & 0xE0 == 0x80)
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (Duration::from_millis(0), vec![0x90, 60, 100]),
+                (Duration::from_millis(500), vec![0x80, 60, 64]),
+                (Duration::from_millis(1000), vec![0x90, 64, 100]),
+                (Duration::from_millis(1250), vec![0x80, 64, 64]),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_play_to_a_writer() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+        let voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, vec![TimedNote::new(Some(0), 1); 2]);
+        let piece = Piece::new(240, vec![Box::new(voice)]).unwrap();
+
+        let mut output = Vec::new();
+        piece.play_to(&mut output).unwrap();
+        let expected: Vec<u8> = piece.playback_schedule().unwrap().into_iter().flat_map(|(_, message)| message).collect();
+        assert_eq!(output, expected);
+        let notes_on = output.windows(3).filter(|message| *message == [0x90, 60, 100]).count();
+        assert_eq!(notes_on, 2);
+    }
+
+    #[test]
+    #[ignore = "needs a MIDI output"]
+    fn can_play_on_the_midi_output() {
+        let piece = crate::parse_piece(
+            r#"{"bpm": 120, "tracks": [{"id": "voice_1", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0, 2, 4]}]}"#,
+        )
+        .unwrap();
+        piece.play().unwrap();
+    }
+}
@@ -1,12 +1,33 @@
-use std::str::FromStr;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
 
 use log::warn;
+#[cfg(feature = "std")]
 use regex::Regex;
 
-use super::key::{BaseKey, Key, NamedKey, NamedNote, Note};
+use super::key::{BaseKey, Key, KeyModifier, NamedKey, NamedNote, Note};
+use super::tuning::Tuning;
 
 
-#[derive(Clone)]
+const MAJOR_OFFSETS: [i8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// The diatonic modes, in the order of the degrees of the major scale they start on,
+/// with how many fifths their key signature lies from the major key on the same tonic.
+const DIATONIC_MODES: [(&str, i8); 7] = [
+    ("Ionian", 0),
+    ("Dorian", -2),
+    ("Phrygian", -4),
+    ("Lydian", 1),
+    ("Mixolydian", -1),
+    ("Aeolian", -3),
+    ("Locrian", -5),
+];
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Scale {
     /// starting note of the scale: 0 is C, 11 is B
     start: NamedKey,
@@ -14,14 +35,16 @@ pub struct Scale {
     offsets: Vec<i8>,
     /// Will be filled in at struct initialization.
     elements: Vec<NamedKey>,
+    /// Deviations from equal temperament, if any.
+    tuning: Option<Tuning>,
 }
 
 impl Scale {
     /// Create a new scale, starting from the given key and with the specified offsets.
     ///
     /// # Errors
-    ///     - if the offsets are not strictly increasing;
-    ///     - if any offset is not comprised between 0 and 11.
+    /// - if the offsets are not strictly increasing;
+    /// - if any offset is not comprised between 0 and 11.
     pub fn new(start: NamedKey, offsets: Vec<i8>) -> Result<Self, String> {
         // Validate offsets.
         let mut previous_offset: Option<i8> = None;
@@ -34,7 +57,7 @@ impl Scale {
                     return Err("Offsets must be in strictly increasing order!".to_string());
                 }
             }
-            previous_offset = Some(offset.clone());
+            previous_offset = Some(*offset);
         }
 
         // Get the named keys of the scale.
@@ -44,13 +67,124 @@ impl Scale {
             start,
             offsets,
             elements,
+            tuning: None,
         })
     }
+    /// Create a scale from the names of its keys, starting from the first one, keeping
+    /// their spelling.
+    ///
+    /// # Errors
+    /// - if there are no keys;
+    /// - if the keys don't go up within an octave from the first one, e.g. if a key is
+    ///   repeated.
+    pub fn from_named_keys(keys: Vec<NamedKey>) -> Result<Self, String> {
+        let start = *keys.first().ok_or("A scale needs at least one key!")?;
+        let offsets = keys
+            .iter()
+            .map(|key| (key.to_key().get_value() - start.to_key().get_value()).rem_euclid(12))
+            .collect();
+        let mut scale = Self::new(start, offsets)?;
+        scale.elements = keys;
+        Ok(scale)
+    }
+    /// Create a scale from the steps between its keys in semitones, e.g. [2, 2, 1, 2, 2, 2, 1]
+    /// for a major scale. The last step leads back to the start an octave higher.
+    ///
+    /// # Errors
+    /// - if the steps go past the octave or don't add up to it;
+    /// - if a step is 0.
+    pub fn from_steps(start: NamedKey, steps: &[u8]) -> Result<Self, String> {
+        let mut offsets = Vec::with_capacity(steps.len());
+        let mut offset: u8 = 0;
+        for step in steps {
+            if offset >= 12 {
+                return Err("The steps of a scale must not go past the octave!".to_string());
+            }
+            offsets.push(i8::try_from(offset).unwrap());
+            offset = offset.saturating_add(*step);
+        }
+        if offset != 12 {
+            return Err("The steps of a scale must add up to an octave!".to_string());
+        }
+        Self::new(start, offsets)
+    }
+    /// Create the chromatic scale starting from the given key.
+    pub fn chromatic(start: NamedKey) -> Self {
+        Self::new(start, (0..12).collect()).unwrap()
+    }
+    /// Rank the major and minor scales of every key by the share of the given
+    /// notes that they contain. Ties are broken by how well the notes match the
+    /// Krumhansl-Kessler key profile of each scale. The chromatic scale, which
+    /// contains every note, is always returned last.
+    #[cfg(feature = "std")]
+    pub fn detect(notes: &[Note]) -> Vec<(Scale, f64)> {
+        const MAJOR_PROFILE: [f64; 12] =
+            [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+        const MINOR_PROFILE: [f64; 12] =
+            [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+        let major_scales = [
+            "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+        ];
+        let minor_scales = [
+            "C", "C#", "D", "Eb", "E", "F", "F#", "G", "G#", "A", "Bb", "B",
+        ];
+
+        let mut histogram = [0.0; 12];
+        for note in notes {
+            let (key, _) = note.decompose();
+            histogram[usize::try_from(key.get_value()).unwrap()] += 1.0;
+        }
+
+        let candidates = major_scales
+            .map(|key_name| (format!("{}maj", key_name), MAJOR_PROFILE))
+            .into_iter()
+            .chain(minor_scales.map(|key_name| (format!("{}min", key_name), MINOR_PROFILE)));
+
+        let mut ranked: Vec<(Scale, f64, f64)> = candidates
+            .map(|(scale_name, profile)| {
+                let scale = str::parse::<Scale>(&scale_name).unwrap();
+                let tonic = scale.start.to_key().get_value();
+
+                let (mut contained, mut fit) = (0.0, 0.0);
+                for (value, count) in (0..12).zip(histogram) {
+                    if scale.offsets.contains(&(value - tonic).rem_euclid(12)) {
+                        contained += count;
+                    }
+                    fit += count * profile[usize::try_from((value - tonic).rem_euclid(12)).unwrap()];
+                }
+                let share = if notes.is_empty() { 0.0 } else { contained / notes.len() as f64 };
+                (scale, share, fit)
+            })
+            .collect();
+        ranked.sort_by(|(_, share, fit), (_, other_share, other_fit)| {
+            other_share.total_cmp(share).then(other_fit.total_cmp(fit))
+        });
+
+        let c = NamedKey::new(BaseKey::C, KeyModifier::Natural);
+        ranked
+            .into_iter()
+            .map(|(scale, share, _)| (scale, share))
+            .chain([(Self::chromatic(c), 1.0)])
+            .collect()
+    }
+    /// Play the notes of this scale with the given tuning instead of equal temperament.
+    pub fn with_tuning(mut self, tuning: Tuning) -> Self {
+        self.tuning = Some(tuning);
+        self
+    }
+    pub fn get_tuning(&self) -> Option<&Tuning> {
+        self.tuning.as_ref()
+    }
     fn generate_elements(start: &NamedKey, offsets: &Vec<i8>) -> Vec<NamedKey> {
-        //! This bit of logic tries to assign NamedKeys to the offsets, such that,
+        //! Major and minor scales are spelled from their key signature. For other
+        //! scales, this bit of logic tries to assign NamedKeys to the offsets, such that,
         //! as far as possible, the NamedKeys start with different BaseKeys.
         //! If this is not possible, we default to the key's default NamedKey.
 
+        if let Some(elements) = Self::generate_elements_from_key_signature(start, offsets) {
+            return elements;
+        }
+
         let (base_key, _) = start.get_components();
         // Get all base keys in reverse order (so we can use this as a stack)
         let keys_in_order: Vec<BaseKey> = base_key.get_keys_in_order().collect();
@@ -82,6 +216,173 @@ impl Scale {
         }
         elements
     }
+    /// The major (or natural minor) scale of the key with the given key signature, as
+    /// found in a MIDI key signature event: the number of sharps (positive) or flats
+    /// (negative). Returns None if no key has that many accidentals.
+    pub fn from_key_signature(sharps: i8, minor: bool) -> Option<Scale> {
+        let offsets = if minor {
+            Self::get_diatonic_mode_offsets(5)
+        } else {
+            MAJOR_OFFSETS.to_vec()
+        };
+        BaseKey::C
+            .get_keys_in_order()
+            .flat_map(|base_key| {
+                [KeyModifier::Natural, KeyModifier::Sharp, KeyModifier::Flat]
+                    .map(|key_modifier| NamedKey::new(base_key, key_modifier))
+            })
+            .find(|tonic| tonic.key_signature(minor) == Some(sharps))
+            .map(|tonic| Self::new(tonic, offsets).unwrap())
+    }
+    /// The offsets of the diatonic mode starting on the given degree of the major scale.
+    fn get_diatonic_mode_offsets(degree: usize) -> Vec<i8> {
+        (0..7).map(|i| (MAJOR_OFFSETS[(degree + i) % 7] - MAJOR_OFFSETS[degree]).rem_euclid(12)).collect()
+    }
+    /// Get the seven diatonic modes starting on `tonic`, from Ionian to Locrian, with
+    /// their names.
+    pub fn diatonic_modes(tonic: NamedKey) -> Vec<(&'static str, Scale)> {
+        DIATONIC_MODES
+            .iter()
+            .enumerate()
+            .map(|(degree, (name, _))| {
+                (*name, Self::new(tonic, Self::get_diatonic_mode_offsets(degree)).unwrap())
+            })
+            .collect()
+    }
+    /// Spell a diatonic mode or harmonic minor scale with consecutive base keys, each carrying the
+    /// accidental of the key signature, raised where the scale departs from it (such as
+    /// the leading tone of harmonic minor). Returns None for any other scale, or if a
+    /// note would need an accidental that can't be represented.
+    fn generate_elements_from_key_signature(start: &NamedKey, offsets: &[i8]) -> Option<Vec<NamedKey>> {
+        const HARMONIC_MINOR: [i8; 7] = [0, 2, 3, 5, 7, 8, 11];
+        const SHARPS_ORDER: [BaseKey; 7] =
+            [BaseKey::F, BaseKey::C, BaseKey::G, BaseKey::D, BaseKey::A, BaseKey::E, BaseKey::B];
+
+        let key_signature = if offsets == HARMONIC_MINOR {
+            start.key_signature(true)?
+        } else {
+            let degree = (0..DIATONIC_MODES.len()).find(|degree| offsets == Self::get_diatonic_mode_offsets(*degree))?;
+            let (_, fifths_from_major) = DIATONIC_MODES[degree];
+            start.key_signature(false)? + fifths_from_major
+        };
+
+        let (base_key, _) = start.get_components();
+        base_key
+            .get_keys_in_order()
+            .zip(offsets)
+            .map(|(letter, offset)| {
+                // Sharps are added in the order F C G D A E B, and flats in the reverse order.
+                let accidentals = usize::from(key_signature.unsigned_abs());
+                let signature_value = if key_signature >= 0 {
+                    let sharps = SHARPS_ORDER.iter().cycle().take(accidentals);
+                    i8::try_from(sharps.filter(|key| **key == letter).count()).unwrap()
+                } else {
+                    let flats = SHARPS_ORDER.iter().rev().cycle().take(accidentals);
+                    -i8::try_from(flats.filter(|key| **key == letter).count()).unwrap()
+                };
+                let target = (start.to_key() + offset).get_value();
+                let signature_key = (letter.to_key() + &signature_value).get_value();
+                let departure = (target - signature_key + 6).rem_euclid(12) - 6;
+                let key_modifier = KeyModifier::from_value(signature_value + departure)?;
+                Some(NamedKey::new(letter, key_modifier))
+            })
+            .collect()
+    }
+    /// The keys of the scale, in order.
+    fn get_keys(&self) -> Vec<Key> {
+        self.offsets.iter().map(|offset| self.start.to_key() + offset).collect()
+    }
+    /// The keys that are in both scales, in the order of this scale.
+    pub fn shared_pitch_classes(&self, other: &Scale) -> Vec<Key> {
+        let other_keys = other.get_keys();
+        self.get_keys().into_iter().filter(|key| other_keys.contains(key)).collect()
+    }
+    /// Whether the two scales are rotations of each other, i.e. they contain the same keys
+    /// (e.g. C major and A natural minor).
+    pub fn is_mode_of(&self, other: &Scale) -> bool {
+        self.len() == other.len() && self.shared_pitch_classes(other).len() == self.len()
+    }
+    /// The pitch classes of the scale (0 is C, 11 is B), in increasing order.
+    pub fn pitch_class_set(&self) -> Vec<u8> {
+        let mut pitch_classes: Vec<u8> =
+            self.get_keys().iter().map(|key| u8::try_from(key.get_value()).unwrap()).collect();
+        pitch_classes.sort();
+        pitch_classes
+    }
+    /// Count how many pairs of pitch classes of the scale are 1 to 6 semitones apart,
+    /// counting intervals and their inversions together.
+    pub fn interval_vector(&self) -> [u8; 6] {
+        let pitch_classes = self.pitch_class_set();
+        let mut interval_vector = [0; 6];
+        for (i, lower) in pitch_classes.iter().enumerate() {
+            for higher in pitch_classes[i + 1..].iter() {
+                let interval = higher - lower;
+                let interval_class = interval.min(12 - interval);
+                interval_vector[usize::from(interval_class) - 1] += 1;
+            }
+        }
+        interval_vector
+    }
+    /// Get the mode of this scale starting on the given degree (0 is the scale itself),
+    /// keeping the spelling of the notes of this scale.
+    pub fn mode(&self, degree: usize) -> Scale {
+        let degree = degree % self.offsets.len();
+        let offsets = self.offsets[degree..]
+            .iter()
+            .chain(self.offsets[..degree].iter())
+            .map(|offset| (offset - self.offsets[degree]).rem_euclid(12))
+            .collect();
+        let elements = self.elements[degree..]
+            .iter()
+            .chain(self.elements[..degree].iter())
+            .cloned()
+            .collect();
+
+        Self {
+            start: self.elements[degree],
+            offsets,
+            elements,
+            tuning: self.tuning,
+        }
+    }
+    /// The name of the function of the given degree (0 is the tonic) in a scale of seven
+    /// notes, e.g. "dominant" for 4. The seventh degree is the "leading tone" a half step
+    /// below the tonic, and the "subtonic" a whole step below it, as in natural minor.
+    /// Degrees of other scales are numbered from "1". Degrees beyond the scale wrap around.
+    pub fn degree_function(&self, degree: i8) -> &'static str {
+        const FUNCTIONS: [&str; 6] = ["tonic", "supertonic", "mediant", "subdominant", "dominant", "submediant"];
+        const DEGREE_NUMBERS: [&str; 12] = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12"];
+
+        let len = i8::try_from(self.offsets.len()).unwrap();
+        let index = usize::try_from(degree.rem_euclid(len)).unwrap();
+        if self.offsets.len() != 7 {
+            return DEGREE_NUMBERS[index];
+        }
+        match FUNCTIONS.get(index) {
+            Some(function) => function,
+            None if self.offsets[6] == 11 => "leading tone",
+            None => "subtonic",
+        }
+    }
+    /// The number of notes in an octave of this scale.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+    /// The semitones between the start of the scale and each of its notes.
+    pub fn offsets(&self) -> &[i8] {
+        &self.offsets
+    }
+    /// The key the scale starts on.
+    pub fn start(&self) -> NamedKey {
+        self.start
+    }
+    /// The keys of the scale, as they are spelled.
+    pub fn named_keys(&self) -> &[NamedKey] {
+        &self.elements
+    }
     fn get_index_and_additional_octaves(&self, position: i8) -> (usize, i8) {
         let len = i8::try_from(self.offsets.len()).unwrap();
         let (index, additional_octaves) = (position.rem_euclid(len), position.div_euclid(len));
@@ -90,40 +391,151 @@ impl Scale {
     }
     pub fn get_note(&self, position: i8, octave: i8) -> Note {
         let (index_usize, additional_octaves) = self.get_index_and_additional_octaves(position);
-        Note::compose(self.start.to_key(), octave + additional_octaves) + &self.offsets[index_usize]
+        // Go through the named note so that e.g. Cb4 starts at B3 rather than B4.
+        NamedNote::new(self.start, octave + additional_octaves).to_note() + &self.offsets[index_usize]
+    }
+    /// The note at `position` above the start of the scale in `octave`, or `None` if it
+    /// falls outside the MIDI range of 0 to 127.
+    pub fn try_get_note(&self, position: i8, octave: i8) -> Option<Note> {
+        u8::try_from(self.get_height(i32::from(position), octave)).ok().filter(|height| *height <= 127).map(Note)
+    }
+    /// The height of the note at `position` in `octave`, as in MIDI but unbounded.
+    fn get_height(&self, position: i32, octave: i8) -> i32 {
+        let len = i32::try_from(self.offsets.len()).unwrap();
+        let index_usize = usize::try_from(position.rem_euclid(len)).unwrap();
+        (i32::from(octave) + position.div_euclid(len) + 1) * 12
+            + i32::from(self.start.base_key.to_key().get_value())
+            + i32::from(self.start.key_modifier.get_value())
+            + i32::from(self.offsets[index_usize])
+    }
+    /// The position in `octave` whose note is closest to `note`, e.g. to snap a pitch to
+    /// the scale. When two positions are as close, the higher one is chosen, so F#4
+    /// gives G4 (position 4) in C major at octave 4. Returns None if that position
+    /// doesn't fit in an i8, e.g. for G9 at octave -100.
+    pub fn closest_degree(&self, note: &Note, octave: i8) -> Option<i8> {
+        let height = i32::from(note.0);
+        let len = i32::try_from(self.len()).unwrap();
+        // The note is at most an octave away from a position of its own octave.
+        let octaves_away = (height - self.get_height(0, octave)).div_euclid(12);
+        ((octaves_away - 1) * len..=(octaves_away + 2) * len)
+            .min_by_key(|position| ((self.get_height(*position, octave) - height).abs(), -position))
+            .and_then(|position| i8::try_from(position).ok())
+    }
+    /// Spell `note` the way this scale does, or return None if it is not in the scale.
+    pub fn spell(&self, note: Note) -> Option<NamedNote> {
+        let (key, _) = note.decompose();
+        let element = self.elements.iter().find(|element| element.to_key() == key)?;
+        Some(NamedNote::spell(note, *element))
+    }
+    /// Spell any note in the context of this scale: notes of the scale are spelled the
+    /// way it does, and other notes as a key of the scale raised or lowered by a
+    /// semitone, or as a natural. Spellings with fewer accidentals are preferred, then
+    /// sharps, unless the scale has more flats than sharps.
+    pub fn name_note(&self, note: &Note) -> NamedNote {
+        if let Some(named_note) = self.spell(*note) {
+            return named_note;
+        }
+
+        let (key, _) = note.decompose();
+        let prefers_flats = self.elements.iter().map(|element| element.key_modifier.get_value()).sum::<i8>() < 0;
+        let altered_elements = self.elements.iter().filter_map(|element| {
+            let alteration = (key.get_value() - element.to_key().get_value() + 6).rem_euclid(12) - 6;
+            if alteration.abs() != 1 {
+                return None;
+            }
+            let key_modifier = KeyModifier::from_value(element.key_modifier.get_value() + alteration)?;
+            Some(NamedKey::new(element.base_key, key_modifier))
+        });
+        let naturals = BaseKey::C
+            .get_keys_in_order()
+            .filter(|base_key| base_key.to_key() == key)
+            .map(|base_key| NamedKey::new(base_key, KeyModifier::Natural));
+        let named_key = altered_elements
+            .chain(naturals)
+            .min_by_key(|named_key| {
+                let value = named_key.key_modifier.get_value();
+                (value.abs(), (value < 0) != prefers_flats)
+            })
+            .unwrap_or_else(|| key.get_default_named_key());
+        NamedNote::spell(*note, named_key)
     }
     pub fn get_named_note(&self, position: i8, octave: i8) -> NamedNote {
         let (index_usize, _) = self.get_index_and_additional_octaves(position);
-        let note = self.get_note(position, octave);
-        note.get_named_note_starting_with(&self.elements[index_usize].base_key)
-            .unwrap()
+        NamedNote::spell(self.get_note(position, octave), self.elements[index_usize])
+    }
+    /// The note at degree `n` of the scale, counted from 0 like positions, both as a
+    /// `Note` and spelled as a `NamedNote`, e.g. E4 for degree 2 of C major at octave 4.
+    pub fn degree(&self, n: i8, octave: i8) -> (Note, NamedNote) {
+        let (index_usize, _) = self.get_index_and_additional_octaves(n);
+        let note = self.get_note(n, octave);
+        (note, NamedNote::spell(note, self.elements[index_usize]))
     }
 }
 
+#[cfg(feature = "std")]
 impl FromStr for Scale {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re = Regex::new("^([A-G][b♭#♯x𝄪]?)(M|maj|m|min)?$").unwrap();
+        ScaleRegistry::default().parse(s)
+    }
+}
+
+/// Scales registered by name, on top of the built-in major and minor scales, so that
+/// e.g. "C hungarian_minor" can be parsed once "hungarian_minor" is registered.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct ScaleRegistry {
+    offsets_by_name: HashMap<String, Vec<i8>>,
+}
+
+#[cfg(feature = "std")]
+impl ScaleRegistry {
+    /// Register the scale with the given offsets under `name`, replacing any scale
+    /// previously registered under that name.
+    ///
+    /// # Errors
+    /// - if the name is empty or contains whitespace;
+    /// - if the offsets are not valid scale offsets (see `Scale::new`).
+    pub fn register(&mut self, name: &str, offsets: Vec<i8>) -> Result<(), String> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("Invalid scale name: {}", name));
+        }
+        Scale::new(NamedKey::new(BaseKey::C, KeyModifier::Natural), offsets.clone())?;
+        self.offsets_by_name.insert(name.to_string(), offsets);
+        Ok(())
+    }
+    /// Parse a scale such as "Cmaj", "F#m" or, for a registered scale, "C hungarian_minor".
+    pub fn parse(&self, s: &str) -> Result<Scale, String> {
+        let re = Regex::new("^([A-G](?:bb|[b♭#♯x𝄪𝄫])?)(M|maj|m|min)?$").unwrap();
+        if let Some(captures) = re.captures(s) {
+            let start = NamedKey::from_str(&captures[1])?;
+
+            let offsets = match captures.get(2) {
+                None => Ok(vec![0, 2, 4, 5, 7, 9, 11]), // major
+                Some(scale_mode) => match scale_mode.as_str() {
+                    "M" | "maj" => Ok(vec![0, 2, 4, 5, 7, 9, 11]),
+                    "m" | "min" => Ok(vec![0, 2, 3, 5, 7, 8, 11]),
+                    mode => Err(format!("Invalid scale mode: {}", mode)),
+                },
+            }?;
+
+            return Scale::new(start, offsets);
+        }
+
+        let re = Regex::new(r"^([A-G](?:bb|[b♭#♯x𝄪𝄫])?) (\S+)$").unwrap();
         let captures = re
             .captures(s)
             .ok_or_else(|| format!("Invalid scale:{}", s))?;
-
         let start = NamedKey::from_str(&captures[1])?;
-
-        let offsets = match captures.get(2) {
-            None => Ok(vec![0, 2, 4, 5, 7, 9, 11]), // major
-            Some(scale_mode) => match scale_mode.as_str() {
-                "M" | "maj" => Ok(vec![0, 2, 4, 5, 7, 9, 11]),
-                "m" | "min" => Ok(vec![0, 2, 3, 5, 7, 8, 11]),
-                mode => Err(format!("Invalid scale mode: {}", mode)),
-            },
-        }?;
-
-        Self::new(start, offsets)
+        let offsets = self
+            .offsets_by_name
+            .get(&captures[2])
+            .ok_or_else(|| format!("Unknown scale: {}", &captures[2]))?;
+        Scale::new(start, offsets.clone())
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use std::iter;
@@ -149,6 +561,235 @@ mod tests {
         }
     }
 
+    #[test]
+    fn can_parse_scales_with_unicode_accidentals() {
+        // The genuine U+266D flat, U+266F sharp and U+1D12A double sharp.
+        for (unicode, ascii) in [("B\u{266D}maj", "Bbmaj"), ("F\u{266F}m", "F#m"), ("F\u{1D12A}", "Fx")] {
+            assert_eq!(str::parse::<Scale>(unicode).unwrap(), str::parse::<Scale>(ascii).unwrap());
+        }
+        let mut scales = ScaleRegistry::default();
+        scales.register("hungarian_minor", vec![0, 2, 3, 6, 7, 8, 11]).unwrap();
+        assert_eq!(
+            scales.parse("E\u{266D} hungarian_minor").unwrap(),
+            scales.parse("Eb hungarian_minor").unwrap()
+        );
+    }
+
+    #[test]
+    fn can_detect_scales() {
+        let melody: Vec<Note> = ["C4", "D4", "E4", "F4", "G4", "A4", "B4", "C5", "G4", "E4", "C4"]
+            .map(|s| str::parse::<NamedNote>(s).unwrap().to_note())
+            .into();
+
+        let candidates = Scale::detect(&melody);
+        let (best_scale, best_share) = &candidates[0];
+        assert_eq!(best_scale.start, str::parse::<NamedKey>("C").unwrap());
+        assert_eq!(best_scale.offsets, [0, 2, 4, 5, 7, 9, 11]);
+        assert_eq!(*best_share, 1.0);
+
+        let (last_scale, _) = candidates.last().unwrap();
+        assert_eq!(last_scale.offsets.len(), 12);
+    }
+
+    #[test]
+    fn can_get_modes() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+
+        let d_dorian_scale = c_major_scale.mode(1);
+        assert_eq!(d_dorian_scale.start, str::parse::<NamedKey>("D").unwrap());
+        assert_eq!(d_dorian_scale.offsets, [0, 2, 3, 5, 7, 9, 10]);
+
+        let expected_elements = ["D", "E", "F", "G", "A", "B", "C"]
+            .map(|s| str::parse::<NamedKey>(s).unwrap());
+        assert_eq!(d_dorian_scale.elements, expected_elements);
+
+        let b_locrian_scale = c_major_scale.mode(6);
+        assert_eq!(b_locrian_scale.get_named_note(1, 4), str::parse::<NamedNote>("C5").unwrap());
+        assert_eq!(c_major_scale.mode(7).offsets, c_major_scale.offsets);
+    }
+
+    #[test]
+    fn can_compare_scales() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let a = str::parse::<NamedKey>("A").unwrap();
+        let a_natural_minor_scale = Scale::new(a, vec![0, 2, 3, 5, 7, 8, 10]).unwrap();
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_natural_minor_scale = Scale::new(c, vec![0, 2, 3, 5, 7, 8, 10]).unwrap();
+
+        assert!(c_major_scale.is_mode_of(&a_natural_minor_scale));
+        assert!(a_natural_minor_scale.is_mode_of(&c_major_scale));
+        assert!(c_major_scale.is_mode_of(&c_major_scale.mode(4)));
+        assert!(!c_major_scale.is_mode_of(&c_natural_minor_scale));
+        // "min" scales are harmonic minor, which is not a mode of major.
+        assert!(!c_major_scale.is_mode_of(&str::parse::<Scale>("Amin").unwrap()));
+
+        let shared: Vec<i8> = c_major_scale
+            .shared_pitch_classes(&c_natural_minor_scale)
+            .iter()
+            .map(|key| key.get_value())
+            .collect();
+        assert_eq!(shared, [0, 2, 5, 7]);
+    }
+
+    #[test]
+    fn can_get_interval_vectors() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        assert_eq!(c_major_scale.pitch_class_set(), [0, 2, 4, 5, 7, 9, 11]);
+        assert_eq!(c_major_scale.interval_vector(), [2, 5, 4, 3, 6, 1]);
+
+        // The pitch classes wrap around the octave.
+        let g_major_scale = str::parse::<Scale>("Gmaj").unwrap();
+        assert_eq!(g_major_scale.pitch_class_set(), [0, 2, 4, 6, 7, 9, 11]);
+        assert_eq!(g_major_scale.interval_vector(), [2, 5, 4, 3, 6, 1]);
+
+        let c = str::parse::<NamedKey>("C").unwrap();
+        assert_eq!(Scale::chromatic(c).interval_vector(), [12, 12, 12, 12, 12, 6]);
+    }
+
+    #[test]
+    fn can_label_degree_functions() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let functions: Vec<&str> = (0..7).map(|degree| c_major_scale.degree_function(degree)).collect();
+        assert_eq!(
+            functions,
+            ["tonic", "supertonic", "mediant", "subdominant", "dominant", "submediant", "leading tone"]
+        );
+        assert_eq!(c_major_scale.degree_function(11), "dominant");
+        assert_eq!(c_major_scale.degree_function(-1), "leading tone");
+
+        // "min" scales are harmonic minor, with a raised seventh.
+        assert_eq!(str::parse::<Scale>("Amin").unwrap().degree_function(6), "leading tone");
+        let a = str::parse::<NamedKey>("A").unwrap();
+        let a_natural_minor_scale = Scale::new(a, vec![0, 2, 3, 5, 7, 8, 10]).unwrap();
+        assert_eq!(a_natural_minor_scale.degree_function(6), "subtonic");
+
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let pentatonic_scale = Scale::new(c, vec![0, 2, 4, 7, 9]).unwrap();
+        assert_eq!(pentatonic_scale.degree_function(3), "4");
+    }
+
+    #[test]
+    fn can_get_diatonic_modes() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let modes = Scale::diatonic_modes(c);
+        let names: Vec<&str> = modes.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["Ionian", "Dorian", "Phrygian", "Lydian", "Mixolydian", "Aeolian", "Locrian"]);
+
+        assert_eq!(modes[0].1, str::parse::<Scale>("Cmaj").unwrap());
+        let c_natural_minor_scale = Scale::new(c, vec![0, 2, 3, 5, 7, 8, 10]).unwrap();
+        assert_eq!(modes[5].1, c_natural_minor_scale);
+
+        // Modes are spelled from their key signature.
+        let expected_elements = ["C", "Db", "Eb", "F", "Gb", "Ab", "Bb"].map(|s| str::parse::<NamedKey>(s).unwrap());
+        assert_eq!(modes[6].1.elements, expected_elements);
+        let f_sharp = str::parse::<NamedKey>("F#").unwrap();
+        let f_sharp_lydian_scale = &Scale::diatonic_modes(f_sharp)[3].1;
+        let expected_elements = ["F#", "G#", "A#", "B#", "C#", "D#", "E#"].map(|s| str::parse::<NamedKey>(s).unwrap());
+        assert_eq!(f_sharp_lydian_scale.elements, expected_elements);
+    }
+
+    #[test]
+    fn can_read_scale_shape() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        assert_eq!(c_major_scale.len(), 7);
+        assert!(!c_major_scale.is_empty());
+        assert_eq!(c_major_scale.offsets(), [0, 2, 4, 5, 7, 9, 11]);
+        assert_eq!(c_major_scale.start(), str::parse::<NamedKey>("C").unwrap());
+
+        let debug = format!("{:?}", c_major_scale);
+        assert!(debug.contains("start: C"), "{}", debug);
+        assert!(debug.contains("[0, 2, 4, 5, 7, 9, 11]"), "{}", debug);
+        assert!(debug.contains("[C, D, E, F, G, A, B]"), "{}", debug);
+    }
+
+    #[test]
+    fn can_spell_scales_from_key_signatures() {
+        let major_keys = [
+            "Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#",
+        ];
+        for key in major_keys {
+            let start = str::parse::<NamedKey>(key).unwrap();
+            let scale = str::parse::<Scale>(&format!("{}maj", key)).unwrap();
+            let base_keys: Vec<BaseKey> = scale.elements.iter().map(|element| element.base_key).collect();
+            assert_eq!(base_keys, start.base_key.get_keys_in_order().collect::<Vec<_>>(), "{} major", key);
+            let accidentals: i8 = scale.elements.iter().map(|element| element.key_modifier.get_value()).sum();
+            assert_eq!(Some(accidentals), start.key_signature(false), "{} major", key);
+        }
+
+        let g_sharp_minor_scale = str::parse::<Scale>("G#min").unwrap();
+        let expected_elements =
+            ["G#", "A#", "B", "C#", "D#", "E", "Fx"].map(|s| str::parse::<NamedKey>(s).unwrap());
+        assert_eq!(g_sharp_minor_scale.elements, expected_elements);
+
+        assert_eq!(Scale::from_key_signature(2, false), Some(str::parse::<Scale>("Dmaj").unwrap()));
+        assert_eq!(Scale::from_key_signature(-3, true).unwrap().start(), str::parse::<NamedKey>("C").unwrap());
+        assert_eq!(Scale::from_key_signature(13, false), None);
+        let d_major_scale = Scale::from_key_signature(2, false).unwrap();
+        assert_eq!(d_major_scale.spell(Note(66)), Some(str::parse::<NamedNote>("F#4").unwrap()));
+        assert_eq!(d_major_scale.spell(Note(65)), None);
+    }
+
+    #[test]
+    fn can_name_notes() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let f_major_scale = str::parse::<Scale>("Fmaj").unwrap();
+        assert_eq!(c_major_scale.name_note(&Note(61)), str::parse::<NamedNote>("C#4").unwrap());
+        assert_eq!(f_major_scale.name_note(&Note(61)), str::parse::<NamedNote>("Db4").unwrap());
+        // Notes of the scale keep its spelling.
+        assert_eq!(f_major_scale.name_note(&Note(70)), str::parse::<NamedNote>("Bb4").unwrap());
+        assert_eq!(str::parse::<Scale>("Cbmaj").unwrap().name_note(&Note(71)), str::parse::<NamedNote>("Cb5").unwrap());
+        // Naturals are preferred to altering a key of the scale.
+        assert_eq!(str::parse::<Scale>("Dmaj").unwrap().name_note(&Note(60)), str::parse::<NamedNote>("C4").unwrap());
+        assert_eq!(str::parse::<Scale>("Ebmaj").unwrap().name_note(&Note(64)), str::parse::<NamedNote>("E4").unwrap());
+        let pentatonic_scale = Scale::new(str::parse::<NamedKey>("C").unwrap(), vec![0, 2, 4, 7, 9]).unwrap();
+        assert_eq!(pentatonic_scale.name_note(&Note(65)), str::parse::<NamedNote>("F4").unwrap());
+    }
+
+    #[test]
+    fn can_get_degrees() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let e4 = str::parse::<NamedNote>("E4").unwrap();
+        assert_eq!(c_major_scale.degree(2, 4), (e4.to_note(), e4));
+        let eb_major_scale = str::parse::<Scale>("Ebmaj").unwrap();
+        for n in -8..16 {
+            assert_eq!(eb_major_scale.degree(n, 4), (eb_major_scale.get_note(n, 4), eb_major_scale.get_named_note(n, 4)));
+        }
+    }
+
+    #[test]
+    fn can_spell_theoretical_keys() {
+        let cb_major_scale = str::parse::<Scale>("Cbmaj").unwrap();
+        let expected_notes = ["Cb4", "Db4", "Eb4", "Fb4", "Gb4", "Ab4", "Bb4", "Cb5"];
+        for (position, expected_note) in iter::zip(0.., expected_notes) {
+            assert_eq!(cb_major_scale.get_named_note(position, 4), str::parse::<NamedNote>(expected_note).unwrap());
+        }
+
+        let expected_scales = [
+            ("C#maj", ["C#", "D#", "E#", "F#", "G#", "A#", "B#"]),
+            ("Fbmaj", ["Fb", "Gb", "Ab", "Bbb", "Cb", "Db", "Eb"]),
+            ("B#maj", ["B#", "Cx", "Dx", "E#", "Fx", "Gx", "Ax"]),
+        ];
+        for (name, expected_elements) in expected_scales {
+            let scale = str::parse::<Scale>(name).unwrap();
+            assert_eq!(scale.elements, expected_elements.map(|s| str::parse::<NamedKey>(s).unwrap()), "{}", name);
+        }
+
+        // Every note is named after its own letter and round-trips, whatever the octave.
+        for name in ["Cbmaj", "C#maj", "Fbmaj", "B#maj", "Abmin", "D#min"] {
+            let scale = str::parse::<Scale>(name).unwrap();
+            for position in -8..16 {
+                let named_note = scale.get_named_note(position, 4);
+                let index = usize::try_from(position.rem_euclid(7)).unwrap();
+                assert_eq!(named_note.to_string()[..1], scale.elements[index].to_string()[..1], "{} {}", name, position);
+                assert_eq!(named_note.to_note(), scale.get_note(position, 4), "{} {}", name, position);
+            }
+        }
+        assert_eq!(str::parse::<Scale>("B#maj").unwrap().get_named_note(0, 3), str::parse::<NamedNote>("B#3").unwrap());
+        assert_eq!(str::parse::<NamedNote>("B#3").unwrap().to_note(), Note(60));
+        assert_eq!(str::parse::<NamedNote>("C𝄫5").unwrap().to_note(), Note(70));
+        assert_eq!(str::parse::<NamedKey>("Bbb"), str::parse::<NamedKey>("B𝄫"));
+    }
+
     #[test]
     fn can_get_notes() {
         let c = str::parse::<NamedKey>("C").unwrap();
@@ -178,5 +819,91 @@ mod tests {
         for (note, expected_note) in iter::zip(notes, expected_notes) {
             assert_eq!(note, expected_note);
         }
+
+        // Positions wrap around the octave in both directions.
+        let note_positions = [-15, -14, -8, -7, -6, -1, 0, 6, 7, 8, 13, 14, 15];
+        let expected_notes = ["B1", "C2", "B2", "C3", "D3", "B3", "C4", "B4", "C5", "D5", "B5", "C6", "D6"];
+        for (position, expected_note) in iter::zip(note_positions, expected_notes) {
+            let expected_note = str::parse::<NamedNote>(expected_note).unwrap();
+            assert_eq!(c_major_scale.get_named_note(position, 4), expected_note, "position {}", position);
+            assert_eq!(c_major_scale.get_note(position, 4), expected_note.to_note(), "position {}", position);
+        }
+        let expected_notes = ["D2", "Eb2", "D3", "Eb3", "F3", "D4", "Eb4", "D5", "Eb5", "F5", "D6", "Eb6", "F6"];
+        for (position, expected_note) in iter::zip(note_positions, expected_notes) {
+            let expected_note = str::parse::<NamedNote>(expected_note).unwrap();
+            assert_eq!(eb_minor_scale.get_named_note(position, 4), expected_note, "position {}", position);
+        }
+
+        // A scale spelled from Cb starts below the C of its octave.
+        let cb = str::parse::<NamedKey>("Cb").unwrap();
+        let cb_major_scale = Scale::new(cb, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+        for (position, expected_note) in iter::zip([-7, 0, 1, 7], ["Cb3", "Cb4", "Db4", "Cb5"]) {
+            let expected_note = str::parse::<NamedNote>(expected_note).unwrap();
+            assert_eq!(cb_major_scale.get_named_note(position, 4), expected_note, "position {}", position);
+            assert_eq!(cb_major_scale.get_note(position, 4), expected_note.to_note(), "position {}", position);
+        }
+        assert_eq!(cb_major_scale.try_get_note(0, 4), Some(str::parse::<NamedNote>("Cb4").unwrap().to_note()));
+
+        assert_eq!(c_major_scale.try_get_note(4, 9), Some(Note(127)));
+        assert_eq!(c_major_scale.try_get_note(5, 9), None);
+        assert_eq!(c_major_scale.try_get_note(-1, -1), None);
+    }
+}
+
+// These tests only use what is available without std, so that they also run with
+// `cargo test --no-default-features`.
+#[cfg(test)]
+mod core_tests {
+    use super::*;
+
+    #[test]
+    fn can_get_notes_without_std() {
+        let d = NamedKey::new(BaseKey::D, KeyModifier::Natural);
+        let d_major_scale = Scale::new(d, MAJOR_OFFSETS.to_vec()).unwrap();
+        let notes: Vec<u8> = (0..8).map(|position| d_major_scale.get_note(position, 4).0).collect();
+        assert_eq!(notes, [62, 64, 66, 67, 69, 71, 73, 74]);
+        assert_eq!(d_major_scale.get_note(-1, 4), Note(61));
+        assert_eq!(d_major_scale.try_get_note(0, 10), None);
+
+        let f_sharp = d_major_scale.get_named_note(2, 4);
+        assert_eq!(f_sharp, NamedNote::new(NamedKey::new(BaseKey::F, KeyModifier::Sharp), 4));
+        assert_eq!(Scale::from_key_signature(2, false), Some(d_major_scale));
+    }
+
+    #[test]
+    fn can_get_closest_degrees() {
+        let c = NamedKey::new(BaseKey::C, KeyModifier::Natural);
+        let c_major_scale = Scale::new(c, MAJOR_OFFSETS.to_vec()).unwrap();
+        let f_sharp = NamedNote::new(NamedKey::new(BaseKey::F, KeyModifier::Sharp), 4).to_note();
+        assert_eq!(c_major_scale.closest_degree(&f_sharp, 4), Some(4));
+        assert_eq!(c_major_scale.closest_degree(&Note(64), 4), Some(2));
+        // Ties resolve upward, and notes in other octaves get positions beyond the scale.
+        assert_eq!(c_major_scale.closest_degree(&Note(61), 4), Some(1));
+        assert_eq!(c_major_scale.closest_degree(&Note(72), 4), Some(7));
+        assert_eq!(c_major_scale.closest_degree(&Note(59), 4), Some(-1));
+        assert_eq!(c_major_scale.closest_degree(&Note(60), 5), Some(-7));
+        // Positions too far from the octave don't fit in an i8.
+        assert_eq!(c_major_scale.closest_degree(&Note(127), -100), None);
+        assert_eq!(c_major_scale.closest_degree(&Note(0), 100), None);
+
+        let b_flat = NamedKey::new(BaseKey::B, KeyModifier::Flat);
+        let b_flat_minor_scale = Scale::new(b_flat, [0, 2, 3, 5, 7, 8, 11].to_vec()).unwrap();
+        let position = b_flat_minor_scale.closest_degree(&Note(71), 3).unwrap();
+        assert_eq!(b_flat_minor_scale.get_note(position, 3), Note(72));
+    }
+
+    #[test]
+    fn can_create_scales_from_steps() {
+        let d = NamedKey::new(BaseKey::D, KeyModifier::Natural);
+        let d_major_scale = Scale::from_steps(d, &[2, 2, 1, 2, 2, 2, 1]).unwrap();
+        assert_eq!(d_major_scale.offsets(), MAJOR_OFFSETS);
+        assert_eq!(d_major_scale, Scale::new(d, MAJOR_OFFSETS.to_vec()).unwrap());
+        let pentatonic_scale = Scale::from_steps(d, &[2, 2, 3, 2, 3]).unwrap();
+        assert_eq!(pentatonic_scale.offsets(), [0, 2, 4, 7, 9]);
+
+        assert!(Scale::from_steps(d, &[2, 2, 1, 2, 2, 2]).is_err());
+        assert!(Scale::from_steps(d, &[6, 6, 1]).is_err());
+        assert!(Scale::from_steps(d, &[2, 0, 10]).is_err());
+        assert!(Scale::from_steps(d, &[]).is_err());
     }
 }
@@ -1,18 +1,218 @@
-use std::fmt::{self, Display};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
 
-use midly::{Format, Header, MetaMessage, MidiMessage, Timing, TrackEvent, TrackEventKind};
+use midly::num::u7;
+use midly::{MetaMessage, MidiMessage, PitchBend, TrackEvent, TrackEventKind};
+#[cfg(feature = "std")]
+use midly::{Format, Header, Timing};
 
+#[cfg(feature = "std")]
+use super::chord::Chord;
+#[cfg(feature = "std")]
+use super::drums::PERCUSSION_CHANNEL;
+#[cfg(feature = "std")]
+use super::key::{BaseKey, KeyModifier, NamedKey, Note};
+use super::groove::Groove;
+use super::math::{next_random_unit, round};
+use super::tuning::get_pitch_bend;
 use super::Scale;
 
-pub const TICKS_PER_BEAT: u8 = 24;
+pub const TICKS_PER_BEAT: u32 = 24;
+
+/// The velocity of notes that don't specify one, unless the piece overrides it.
+pub const DEFAULT_VELOCITY: u8 = 100;
+
+/// How many ticks a slurred note keeps sounding after the next note starts.
+const SLUR_OVERLAP_TICKS: u32 = TICKS_PER_BEAT / 8;
+
+/// The latest tick a track can reach. MIDI counts the ticks between events in 28 bits,
+/// and longer times would be written as wrong lengths.
+pub const MAX_TICK: u32 = (1 << 28) - 1;
+
+/// How many ticks early a note is released when the same key is played right after it,
+/// so that players never get the next NoteOn before, or on the same tick as, the NoteOff.
+const REARTICULATION_GAP_TICKS: u32 = 1;
+
+/// The shortest time between two steps of an expression ramp, so that slow ramps don't
+/// flood the channel with controller events.
+const EXPRESSION_RAMP_STEP_TICKS: u32 = TICKS_PER_BEAT / 4;
+
+/// The release velocity of voices, which MIDI recommends for devices that don't sense it.
+pub const DEFAULT_RELEASE_VELOCITY: u8 = 64;
 
 /// A note or silence, with associated duration.
-pub type TimedNote = (Option<i8>, u8);
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimedNote {
+    /// The position of the note in the scale, or None for a silence.
+    pub position: Option<i8>,
+    pub duration: u32,
+    /// The velocity of the note, or None to use the default velocity.
+    pub velocity: Option<u8>,
+    /// If the note has a fermata, how many times its duration it is held for.
+    pub fermata: Option<f64>,
+    /// The MIDI channel to play the note on instead of the track's, e.g. to sound it
+    /// on another instrument. The tuning of the scale and quarter tones are bent on
+    /// that channel too.
+    pub channel: Option<u8>,
+    /// The chance that the note is played when its voice is realized with
+    /// `Voice::realize`, between 0 and 1, or None to always play it.
+    pub probability: Option<f64>,
+}
+
+impl TimedNote {
+    pub fn new(position: Option<i8>, duration: u32) -> Self {
+        Self {
+            position,
+            duration,
+            velocity: None,
+            fermata: None,
+            channel: None,
+            probability: None,
+        }
+    }
+    /// The number of ticks the note actually lasts, including any fermata.
+    pub fn get_sounding_duration(&self) -> u32 {
+        let duration = self.duration;
+        match self.fermata {
+            Some(factor) => round(f64::from(duration) * factor) as u32,
+            None => duration,
+        }
+    }
+}
+
+/// The durations that can be notated without ties: whole, half, quarter and eighth
+/// notes, dotted or as triplets, from shortest to longest.
+fn get_notated_durations() -> Vec<u32> {
+    let mut durations: Vec<u32> = [4 * TICKS_PER_BEAT, 2 * TICKS_PER_BEAT, TICKS_PER_BEAT, TICKS_PER_BEAT / 2]
+        .into_iter()
+        .flat_map(|duration| [duration, duration * 3 / 2, duration * 2 / 3])
+        .collect();
+    durations.sort();
+    durations
+}
+
+/// Snap the duration of every played note of a rhythm, given as (played, duration), to
+/// the closest notated duration (the shorter one on a tie), without moving the notes that
+/// follow: a shorter note is followed by a rest (or a longer one), and a longer note
+/// shortens the rest after it, which it can't outlast. Notes too short for any notated
+/// duration that fits are kept as they are.
+///
+/// Returns the new rhythm, with the index of each element in the given rhythm or None
+/// for new rests. Rests that are used up entirely are dropped.
+pub(crate) fn snap_rhythm(rhythm: &[(bool, u32)]) -> Vec<(Option<usize>, bool, u32)> {
+    let notated_durations = get_notated_durations();
+    let mut rhythm = rhythm.to_vec();
+    let mut snapped_rhythm = Vec::new();
+    for i in 0..rhythm.len() {
+        let (is_played, duration) = rhythm[i];
+        if !is_played {
+            if duration > 0 {
+                snapped_rhythm.push((Some(i), false, duration));
+            }
+            continue;
+        }
+
+        let next_rest = rhythm.get(i + 1).filter(|(is_played, _)| !is_played).map_or(0, |(_, duration)| *duration);
+        let snapped_duration = notated_durations
+            .iter()
+            .copied()
+            .filter(|notated_duration| *notated_duration <= duration + next_rest)
+            .min_by_key(|notated_duration| notated_duration.abs_diff(duration))
+            .unwrap_or(duration);
+        snapped_rhythm.push((Some(i), true, snapped_duration));
+        if snapped_duration > duration {
+            rhythm[i + 1].1 -= snapped_duration - duration;
+        } else if snapped_duration < duration {
+            if next_rest > 0 {
+                rhythm[i + 1].1 += duration - snapped_duration;
+            } else {
+                snapped_rhythm.push((None, false, duration - snapped_duration));
+            }
+        }
+    }
+    snapped_rhythm
+}
+
+/// The tick at which a rhythm starting at `start` ends, or None if it goes past
+/// `MAX_TICK`.
+pub(crate) fn get_rhythm_end(start: u32, durations: impl IntoIterator<Item = u32>) -> Option<u32> {
+    durations
+        .into_iter()
+        .try_fold(start, |tick, duration| tick.checked_add(duration))
+        .filter(|end| *end <= MAX_TICK)
+}
+
+/// The ticks of a beat and of a bar in a `(beats per bar, beat unit)` time signature.
+///
+/// # Errors
+/// - if there are no beats in a bar;
+/// - if the beat unit is not a power of two that lasts a whole number of ticks, i.e. 1 to 32.
+pub(crate) fn get_beat_and_bar_ticks(time_signature: (u8, u8)) -> Result<(u32, u32), String> {
+    let (beats_per_bar, beat_unit) = time_signature;
+    if beats_per_bar == 0 || !beat_unit.is_power_of_two() || !(TICKS_PER_BEAT * 4).is_multiple_of(u32::from(beat_unit)) {
+        return Err(format!("Invalid time signature {}/{}!", beats_per_bar, beat_unit));
+    }
+    let beat_ticks = TICKS_PER_BEAT * 4 / u32::from(beat_unit);
+    Ok((beat_ticks, beat_ticks * u32::from(beats_per_bar)))
+}
 
 pub trait Track {
     fn get_id(&self) -> &str;
-    fn get_start(&self) -> &u32;
-    fn to_midi(&self, instrument: u8, channel: u8) -> Vec<TrackEvent>;
+    /// The tick at which the track starts.
+    fn get_start_tick(&self) -> &u32;
+    fn set_start_tick(&mut self, start_tick: u32);
+    /// A copy of the track, boxed so that pieces holding `dyn Track`s can be cloned.
+    fn clone_box(&self) -> Box<dyn Track>;
+    /// Whether the track is left out when writing MIDI.
+    fn is_muted(&self) -> bool;
+    /// Whether the track is soloed: if any track of a piece is, only soloed tracks are
+    /// written to MIDI.
+    fn is_solo(&self) -> bool;
+    /// The MIDI channel the track claims, or None to assign one automatically.
+    fn get_channel(&self) -> Option<u8>;
+    /// The scale the notes of the track are spelled in.
+    fn get_scale(&self) -> &Scale;
+    /// The tick at which the track ends, counting its start offset and any rests, or
+    /// `MAX_TICK` for a track too long for MIDI, which `check_midi` rejects.
+    fn total_ticks(&self) -> u32;
+    /// A short description of the track, for debugging.
+    fn describe(&self) -> String;
+    /// Check that the track can be written to MIDI as it is, which it must be before
+    /// being written with `to_midi`: its ticks must fit in MIDI delta times, and its
+    /// values in their MIDI messages rather than wrap around.
+    ///
+    /// # Errors
+    /// - if the track goes past `MAX_TICK`;
+    /// - if a note has a velocity outside of 1 to 127, or is played on a channel above 15;
+    /// - if a chord has quarter tones, which can't be bent for a single tone.
+    fn check_midi(&self) -> Result<(), String>;
+    /// Create a track of MIDI events, playing notes that don't specify a velocity with `velocity`.
+    fn to_midi(&self, instrument: u8, channel: u8, velocity: u8) -> Vec<TrackEvent<'_>>;
+    /// Nudge the notes landing on the subdivisions of `groove`, changing the velocity
+    /// of notes that don't specify one from `velocity`.
+    fn apply_groove(&mut self, groove: &Groove, velocity: u8);
+    /// Snap the durations of the notes to the closest notated durations, whole to eighth
+    /// notes, dotted or as triplets, without moving any note: the rests between notes
+    /// make up for the difference.
+    fn snap_durations(&mut self);
+    /// The track in the JSON format read by `json_input`.
+    ///
+    /// # Errors
+    /// - if the track uses something that the JSON format can't express.
+    #[cfg(feature = "std")]
+    fn to_json(&self) -> Result<serde_json::Value, String>;
+    /// The only note the track plays, as (octave, position in its scale, duration), if it
+    /// plays a single note as soon as it starts and nothing else, so that it can be
+    /// merged into a chord.
+    fn get_single_note(&self) -> Option<(i8, i8, u32)> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -20,19 +220,443 @@ pub struct Voice {
     pub id: String,
     pub scale: Scale,
     pub octave: i8,
-    pub start: u32,
+    /// The tick at which the voice starts.
+    pub start_tick: u32,
     pub notes: Vec<TimedNote>,
+    /// The syllables sung on each played note, in order. Rests get no syllable.
+    pub lyrics: Vec<String>,
+    /// The velocity at which notes are released, sent with their NoteOff.
+    pub release_velocity: u8,
+    /// Leave the voice out when writing MIDI.
+    pub muted: bool,
+    /// Only write soloed tracks to MIDI, if any track is soloed.
+    pub solo: bool,
+    /// The MIDI channel of the voice, or None to assign one automatically.
+    pub channel: Option<u8>,
+    /// Slurred phrases, as the indices of their first and last notes. Each note of a
+    /// phrase keeps sounding briefly after the next one starts, until a rest ends it.
+    pub slurs: Vec<(usize, usize)>,
+    /// Changes of expression (MIDI CC11), as (first tick, last tick, first value, last
+    /// value), with ticks counted from the start of the voice. Private so that ramps are
+    /// only added through `add_expression_ramp`, which checks them.
+    expression_ramps: Vec<(u32, u32, u8, u8)>,
+    /// Changes of channel pressure (aftertouch), as (first tick, last tick, first value,
+    /// last value), with ticks counted from the start of the voice. The pressure is
+    /// released at the end of the voice. Private so that ramps are only added through
+    /// `add_aftertouch_ramp`, which checks them.
+    aftertouch_ramps: Vec<(u32, u32, u8, u8)>,
+}
+
+impl Voice {
+    pub fn new(id: String, scale: Scale, octave: i8, start_tick: u32, notes: Vec<TimedNote>) -> Self {
+        Self {
+            id,
+            scale,
+            octave,
+            start_tick,
+            notes,
+            lyrics: Vec::new(),
+            release_velocity: DEFAULT_RELEASE_VELOCITY,
+            muted: false,
+            solo: false,
+            channel: None,
+            slurs: Vec::new(),
+            expression_ramps: Vec::new(),
+            aftertouch_ramps: Vec::new(),
+        }
+    }
+    /// Get the retrograde of this voice: the same notes, each with its own duration,
+    /// in reverse order. Lyrics are dropped, since they don't read backwards.
+    pub fn retrograde(&self) -> Voice {
+        let mut voice = self.clone();
+        voice.notes.reverse();
+        voice.lyrics.clear();
+        voice
+    }
+    /// Decide which notes of the voice with a probability are played, drawing from a
+    /// generator seeded with `seed` so that the same seed always gives the same voice.
+    /// Notes that are not played become rests of the same duration, and lose their
+    /// syllable. Every note of the result is certain to play.
+    pub fn realize(&self, seed: u64) -> Voice {
+        let mut voice = self.clone();
+        let mut state = seed;
+        let mut lyric_index = 0;
+        for timed_note in voice.notes.iter_mut() {
+            if timed_note.position.is_none() {
+                continue;
+            }
+            let probability = timed_note.probability.take();
+            if probability.is_some_and(|probability| next_random_unit(&mut state) >= probability) {
+                *timed_note = TimedNote::new(None, timed_note.duration);
+                if lyric_index < voice.lyrics.len() {
+                    voice.lyrics.remove(lyric_index);
+                }
+            } else {
+                lyric_index += 1;
+            }
+        }
+        voice
+    }
+    /// Get the inversion of this voice around the given scale position: each note
+    /// moves as many scale steps below the axis as it was above it. Since this
+    /// happens in the scale, the inversion stays within the scale.
+    ///
+    /// # Errors
+    /// - if a note would move beyond the positions an i8 can hold.
+    pub fn melodic_invert(&self, axis_position: i8) -> Result<Voice, String> {
+        let mut voice = self.clone();
+        for (i, timed_note) in voice.notes.iter_mut().enumerate() {
+            if let Some(position) = timed_note.position {
+                let inverted_position = i8::try_from(2 * i16::from(axis_position) - i16::from(position))
+                    .map_err(|_| format!("Cannot invert note {} of voice {} around {}!", i, self.id, axis_position))?;
+                timed_note.position = Some(inverted_position);
+            }
+        }
+        Ok(voice)
+    }
+    /// Multiply the duration of every note and rest by `factor`.
+    ///
+    /// # Errors
+    /// - if a duration would get too long, in which case the voice is left unchanged.
+    pub fn augment(&mut self, factor: u32) -> Result<(), String> {
+        let durations = self
+            .notes
+            .iter()
+            .map(|timed_note| {
+                timed_note
+                    .duration
+                    .checked_mul(factor)
+                    .ok_or_else(|| format!("Cannot augment a duration of {} by {}!", timed_note.duration, factor))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        for (timed_note, duration) in self.notes.iter_mut().zip(durations) {
+            timed_note.duration = duration;
+        }
+        Ok(())
+    }
+    /// Divide the duration of every note and rest by `divisor`.
+    ///
+    /// # Errors
+    /// - if a duration is not divisible by `divisor`, in which case the voice is left unchanged.
+    pub fn diminish(&mut self, divisor: u32) -> Result<(), String> {
+        let durations = self
+            .notes
+            .iter()
+            .map(|timed_note| {
+                let duration = timed_note.duration;
+                if divisor == 0 || !duration.is_multiple_of(divisor) {
+                    return Err(format!("Cannot diminish a duration of {} by {}!", duration, divisor));
+                }
+                Ok(duration / divisor)
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        for (timed_note, duration) in self.notes.iter_mut().zip(durations) {
+            timed_note.duration = duration;
+        }
+        Ok(())
+    }
+    /// Check that the voice can be played without stuck notes, e.g. before calling
+    /// `to_midi`. Since the notes of a voice follow each other, a note can only start
+    /// before the previous one ends if that one doesn't last.
+    ///
+    /// # Errors
+    /// - if a note or rest has a duration of 0;
+    /// - if a fermata doesn't hold its note for a positive number of ticks;
+    /// - if the voice can't be written to MIDI, see `Track::check_midi`.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, timed_note) in self.notes.iter().enumerate() {
+            if timed_note.duration == 0 {
+                return Err(format!("Note {} of voice {} has a duration of 0!", i, self.id));
+            }
+            if let Some(fermata) = timed_note.fermata {
+                if !(fermata.is_finite() && timed_note.get_sounding_duration() > 0) {
+                    return Err(format!("Note {} of voice {} has an invalid fermata {}!", i, self.id, fermata));
+                }
+            }
+        }
+        self.check_midi()
+    }
+    /// Give the notes on the strong beats of each measure the `strong` velocity,
+    /// and the notes on the other beats the `weak` velocity. Beat 1 is always
+    /// strong; in compound meters (6/8, 9/8, 12/8) every group of three beats
+    /// starts with a strong beat. Notes that don't start on a beat keep their velocity.
+    /// Bars are counted from `anacrusis_ticks`, the length of the piece's pickup.
+    ///
+    /// # Errors
+    /// - if the time signature has no beats or a beat unit that isn't a power of two from 1 to 32.
+    pub fn apply_metric_accents(&mut self, time_signature: (u8, u8), anacrusis_ticks: u32, strong: u8, weak: u8) -> Result<(), String> {
+        let (beat_ticks, bar_ticks) = get_beat_and_bar_ticks(time_signature)?;
+        let beats_per_bar = time_signature.0;
+        let beats_per_strong_beat = if beats_per_bar > 3 && beats_per_bar.is_multiple_of(3) {
+            3
+        } else {
+            u32::from(beats_per_bar)
+        };
+
+        // Shift the ticks so that 0 falls on a bar line; only the tick within the bar matters.
+        let mut tick = (self.start_tick % bar_ticks + bar_ticks - anacrusis_ticks % bar_ticks) % bar_ticks;
+        for timed_note in self.notes.iter_mut() {
+            if timed_note.position.is_some() && tick.is_multiple_of(beat_ticks) {
+                let beat = tick / beat_ticks;
+                timed_note.velocity = Some(if beat.is_multiple_of(beats_per_strong_beat) {
+                    strong
+                } else {
+                    weak
+                });
+            }
+            tick = (tick + timed_note.duration % bar_ticks) % bar_ticks;
+        }
+        Ok(())
+    }
+    /// Add `boost` to the velocity of the notes starting on any of the given `beats` of
+    /// each bar, counting from beat 1: e.g. 2.5 is the "and" of 2. Beats are in the unit
+    /// of the time signature, and bars are counted from `anacrusis_ticks` as in
+    /// `apply_metric_accents`. Notes without a velocity are boosted from `velocity`,
+    /// usually the piece's. Velocities stay at most 127.
+    ///
+    /// # Errors
+    /// - if the time signature has no beats or a beat unit that isn't a power of two from 1 to 32.
+    pub fn accent_positions(
+        &mut self,
+        beats: &[f64],
+        boost: u8,
+        time_signature: (u8, u8),
+        anacrusis_ticks: u32,
+        velocity: u8,
+    ) -> Result<(), String> {
+        let (beat_ticks, bar_ticks) = get_beat_and_bar_ticks(time_signature)?;
+        let accented_ticks: Vec<f64> = beats.iter().map(|beat| (beat - 1.0) * f64::from(beat_ticks)).collect();
+
+        // Shift the ticks so that 0 falls on a bar line; only the tick within the bar matters.
+        let mut tick = (self.start_tick % bar_ticks + bar_ticks - anacrusis_ticks % bar_ticks) % bar_ticks;
+        for timed_note in self.notes.iter_mut() {
+            if timed_note.position.is_some() && accented_ticks.contains(&f64::from(tick)) {
+                let note_velocity = timed_note.velocity.unwrap_or(velocity);
+                timed_note.velocity = Some(note_velocity.saturating_add(boost).min(127));
+            }
+            tick = (tick + timed_note.duration % bar_ticks) % bar_ticks;
+        }
+        Ok(())
+    }
+    /// Change the expression (MIDI CC11) of the voice gradually from `from` to `to`,
+    /// between `start_tick` and `end_tick` counted from the start of the voice.
+    ///
+    /// # Errors
+    /// - if the ramp ends before it starts;
+    /// - if a value is above 127.
+    pub fn add_expression_ramp(&mut self, start_tick: u32, end_tick: u32, from: u8, to: u8) -> Result<(), String> {
+        if end_tick < start_tick {
+            return Err(format!("Expression ramp ends at tick {} before it starts at tick {}!", end_tick, start_tick));
+        }
+        if from > 127 || to > 127 {
+            return Err("Expression values must be between 0 and 127!".to_string());
+        }
+        self.expression_ramps.push((start_tick, end_tick, from, to));
+        Ok(())
+    }
+    /// The expression ramps of the voice, as (first tick, last tick, first value, last value).
+    pub fn expression_ramps(&self) -> &[(u32, u32, u8, u8)] {
+        &self.expression_ramps
+    }
+    /// Change the channel pressure (aftertouch) of the voice gradually from `from` to
+    /// `to`, between `start_tick` and `end_tick` counted from the start of the voice.
+    ///
+    /// # Errors
+    /// - if the ramp ends before it starts;
+    /// - if a value is above 127.
+    pub fn add_aftertouch_ramp(&mut self, start_tick: u32, end_tick: u32, from: u8, to: u8) -> Result<(), String> {
+        if end_tick < start_tick {
+            return Err(format!("Aftertouch ramp ends at tick {} before it starts at tick {}!", end_tick, start_tick));
+        }
+        if from > 127 || to > 127 {
+            return Err("Aftertouch values must be between 0 and 127!".to_string());
+        }
+        self.aftertouch_ramps.push((start_tick, end_tick, from, to));
+        Ok(())
+    }
+    /// The aftertouch ramps of the voice, as (first tick, last tick, first value, last value).
+    pub fn aftertouch_ramps(&self) -> &[(u32, u32, u8, u8)] {
+        &self.aftertouch_ramps
+    }
+    /// The events realizing a ramp of expression or aftertouch on `channel`, built by
+    /// `message` from each value: one per step of value, but at least
+    /// `EXPRESSION_RAMP_STEP_TICKS` apart.
+    fn get_ramp_events(
+        &self,
+        ramp: (u32, u32, u8, u8),
+        channel: u8,
+        message: fn(u7) -> MidiMessage,
+    ) -> Vec<TrackEvent<'_>> {
+        let (start_tick, end_tick, from, to) = ramp;
+        let duration = end_tick - start_tick;
+        let value_steps = u32::from(from.abs_diff(to));
+        let steps = value_steps.min(duration / EXPRESSION_RAMP_STEP_TICKS).max(1);
+
+        let mut events = Vec::new();
+        let mut previous_tick = 0;
+        for step in 0..=steps {
+            let tick = self.start_tick + start_tick + duration * step / steps;
+            let value = f64::from(from) + (f64::from(to) - f64::from(from)) * f64::from(step) / f64::from(steps);
+            events.push(TrackEvent {
+                delta: (tick - previous_tick).into(),
+                kind: TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: message((round(value) as u8).into()),
+                },
+            });
+            previous_tick = tick;
+        }
+        events
+    }
+    /// Whether each note is slurred into the next one.
+    fn get_slurred_notes(&self) -> Vec<bool> {
+        let mut slurred = vec![false; self.notes.len()];
+        for (first, last) in self.slurs.iter() {
+            for (i, pair) in self.notes.windows(2).enumerate().take(*last).skip(*first) {
+                // A rest ends the slur.
+                if pair.iter().any(|timed_note| timed_note.position.is_none()) {
+                    break;
+                }
+                slurred[i] = true;
+            }
+        }
+        slurred
+    }
+    /// Map the velocity of each note through `127 * (velocity / 127) ^ gamma`, which
+    /// expands the dynamics for a gamma above 1 and compresses them below 1. Velocities
+    /// stay between 1 and 127. Notes without a velocity keep the piece's default.
+    ///
+    /// # Errors
+    /// - if gamma is not a finite number above 0.
+    #[cfg(feature = "std")]
+    pub fn apply_velocity_curve(&mut self, gamma: f64) -> Result<(), String> {
+        if !(gamma.is_finite() && gamma > 0.0) {
+            return Err(format!("Invalid velocity curve gamma {}!", gamma));
+        }
+        for timed_note in self.notes.iter_mut() {
+            if let Some(velocity) = timed_note.velocity {
+                let curved = 127.0 * (f64::from(velocity) / 127.0).powf(gamma);
+                timed_note.velocity = Some(curved.round().clamp(1.0, 127.0) as u8);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Track for Voice {
     fn get_id(&self) -> &str {
         &self.id
     }
-    fn get_start(&self) -> &u32 {
-        &self.start
+    fn get_start_tick(&self) -> &u32 {
+        &self.start_tick
+    }
+    fn set_start_tick(&mut self, start_tick: u32) {
+        self.start_tick = start_tick;
+    }
+    fn clone_box(&self) -> Box<dyn Track> {
+        Box::new(self.clone())
+    }
+    fn is_muted(&self) -> bool {
+        self.muted
+    }
+    fn is_solo(&self) -> bool {
+        self.solo
+    }
+    fn get_channel(&self) -> Option<u8> {
+        self.channel
+    }
+    fn get_scale(&self) -> &Scale {
+        &self.scale
+    }
+    fn total_ticks(&self) -> u32 {
+        get_rhythm_end(self.start_tick, self.notes.iter().map(TimedNote::get_sounding_duration)).unwrap_or(MAX_TICK)
+    }
+    fn describe(&self) -> String {
+        format!("voice {}", self.id)
+    }
+    fn check_midi(&self) -> Result<(), String> {
+        let ramp_ends = self.expression_ramps.iter().chain(self.aftertouch_ramps.iter()).map(|(_, end_tick, _, _)| *end_tick);
+        let fits = get_rhythm_end(self.start_tick, self.notes.iter().map(TimedNote::get_sounding_duration)).is_some()
+            && ramp_ends.into_iter().all(|end_tick| get_rhythm_end(self.start_tick, [end_tick]).is_some());
+        if !fits {
+            return Err(format!("Voice {} goes past tick {}, the last a MIDI file can hold!", self.id, MAX_TICK));
+        }
+        for (i, timed_note) in self.notes.iter().enumerate() {
+            if let Some(velocity) = timed_note.velocity.filter(|velocity| !(1..=127).contains(velocity)) {
+                return Err(format!("Note {} of voice {} has velocity {}, velocities go from 1 to 127!", i, self.id, velocity));
+            }
+            if let Some(channel) = timed_note.channel.filter(|channel| *channel > 15) {
+                return Err(format!("Note {} of voice {} is on channel {}, channels go from 0 to 15!", i, self.id, channel));
+            }
+        }
+        Ok(())
+    }
+    /// Notes with a velocity, fermata, channel or probability of their own, and voices
+    /// with lyrics, slurs, ramps or a release velocity, have more to them than a chord tone.
+    fn get_single_note(&self) -> Option<(i8, i8, u32)> {
+        let [timed_note] = self.notes.as_slice() else {
+            return None;
+        };
+        let is_plain_note = timed_note.velocity.is_none()
+            && timed_note.fermata.is_none()
+            && timed_note.channel.is_none()
+            && timed_note.probability.is_none();
+        let is_plain_voice = self.lyrics.is_empty()
+            && self.slurs.is_empty()
+            && self.expression_ramps.is_empty()
+            && self.aftertouch_ramps.is_empty()
+            && self.release_velocity == DEFAULT_RELEASE_VELOCITY;
+        match timed_note.position {
+            Some(position) if is_plain_note && is_plain_voice => Some((self.octave, position, timed_note.duration)),
+            _ => None,
+        }
+    }
+    fn snap_durations(&mut self) {
+        let rhythm: Vec<(bool, u32)> =
+            self.notes.iter().map(|timed_note| (timed_note.position.is_some(), timed_note.duration)).collect();
+        let snapped_rhythm = snap_rhythm(&rhythm);
+
+        // Slurs refer to notes by index, which move when rests are added or dropped.
+        let mut new_indices = vec![snapped_rhythm.len(); self.notes.len()];
+        for (new_index, (index, _, _)) in snapped_rhythm.iter().enumerate().rev() {
+            if let Some(index) = index {
+                new_indices[*index] = new_index;
+            }
+        }
+        for index in (0..self.notes.len().saturating_sub(1)).rev() {
+            new_indices[index] = new_indices[index].min(new_indices[index + 1]);
+        }
+        for (first, last) in self.slurs.iter_mut() {
+            (*first, *last) = (new_indices[*first], new_indices[*last]);
+        }
+
+        self.notes = snapped_rhythm
+            .into_iter()
+            .map(|(index, _, duration)| match index {
+                Some(index) => TimedNote { duration, ..self.notes[index] },
+                None => TimedNote::new(None, duration),
+            })
+            .collect();
+    }
+    fn apply_groove(&mut self, groove: &Groove, velocity: u8) {
+        let notes: Vec<(bool, u32)> =
+            self.notes.iter().map(|timed_note| (timed_note.position.is_some(), timed_note.duration)).collect();
+        let (start_tick, durations, velocity_changes) = groove.apply(self.start_tick, &notes);
+        self.start_tick = start_tick;
+        for ((timed_note, duration), velocity_change) in self.notes.iter_mut().zip(durations).zip(velocity_changes) {
+            timed_note.duration = duration;
+            if velocity_change != 0 {
+                let velocity = i16::from(timed_note.velocity.unwrap_or(velocity)) + i16::from(velocity_change);
+                timed_note.velocity = Some(u8::try_from(velocity.clamp(1, 127)).unwrap());
+            }
+        }
+    }
+    #[cfg(feature = "std")]
+    fn to_json(&self) -> Result<serde_json::Value, String> {
+        super::json_output::voice_to_json(self)
     }
     /// Create a track of MIDI events, writing notes to the given MIDI channel.
-    fn to_midi(&self, instrument: u8, channel: u8) -> Vec<TrackEvent> {
+    fn to_midi(&self, instrument: u8, channel: u8, velocity: u8) -> Vec<TrackEvent<'_>> {
         let mut track_events = Vec::<TrackEvent>::new();
 
         // Set instrument
@@ -44,47 +668,146 @@ impl Track for Voice {
             },
         });
 
-        let mut next_note_delta = self.start * u32::from(TICKS_PER_BEAT);
+        let mut next_note_delta = self.start_tick;
+        // The pitch bend of each channel the voice plays on.
+        let mut pitch_bends = [PitchBend::mid_raw_value(); 16];
+        let mut lyrics = self.lyrics.iter();
+        let slurred_notes = self.get_slurred_notes();
+        // The release of a slurred note, to send some ticks after the next note starts.
+        let mut pending_note_off: Option<(TrackEventKind, u32)> = None;
+
+        for (i, timed_note) in self.notes.iter().enumerate() {
+            let duration = timed_note.get_sounding_duration();
+            // A note without duration would have its NoteOff at the same time as its
+            // NoteOn, which some players leave stuck: skip it.
+            if duration == 0 {
+                continue;
+            }
+
+            if let Some(position) = timed_note.position {
+                let note = self.scale.get_note(position, self.octave);
+
+                let note_channel = timed_note.channel.unwrap_or(channel);
+
+                // Realize the tuning and the quarter tones of the note by bending its whole channel.
+                let (key, _) = note.decompose();
+                let cents = self.scale.get_tuning().map_or(0.0, |tuning| tuning.get_cents(key))
+                    + self.scale.get_named_note(position, self.octave).get_cents_offset();
+                let note_pitch_bend = get_pitch_bend(cents);
+                let pitch_bend = &mut pitch_bends[usize::from(note_channel)];
+                if note_pitch_bend != *pitch_bend {
+                    track_events.push(TrackEvent {
+                        delta: next_note_delta.into(),
+                        kind: TrackEventKind::Midi {
+                            channel: note_channel.into(),
+                            message: MidiMessage::PitchBend { bend: note_pitch_bend },
+                        },
+                    });
+                    *pitch_bend = note_pitch_bend;
+                    next_note_delta = 0;
+                }
 
-        for (note, duration) in self.notes.iter() {
-            let duration = u32::from(duration.clone());
+                if let Some(syllable) = lyrics.next() {
+                    track_events.push(TrackEvent {
+                        delta: next_note_delta.into(),
+                        kind: TrackEventKind::Meta(MetaMessage::Lyric(syllable.as_bytes())),
+                    });
+                    next_note_delta = 0;
+                }
 
-            if let Some(note) = note {
                 track_events.push(TrackEvent {
                     delta: (next_note_delta).into(),
                     kind: TrackEventKind::Midi {
-                        channel: channel.into(),
+                        channel: note_channel.into(),
                         message: MidiMessage::NoteOn {
-                            key: self.scale.get_note(*note, self.octave).0.into(),
-                            vel: 127.into(),
+                            key: note.0.into(),
+                            vel: timed_note.velocity.unwrap_or(velocity).into(),
                         },
                     },
                 });
 
-                track_events.push(TrackEvent {
-                    delta: duration.into(),
-                    kind: TrackEventKind::Midi {
-                        channel: channel.into(),
-                        message: MidiMessage::NoteOff {
-                            key: self.scale.get_note(*note, self.octave).0.into(),
-                            vel: 127.into(),
-                        },
+                let mut remaining_duration = duration;
+                if let Some((note_off, overlap)) = pending_note_off.take() {
+                    track_events.push(TrackEvent { delta: overlap.into(), kind: note_off });
+                    remaining_duration -= overlap;
+                }
+
+                let note_off = TrackEventKind::Midi {
+                    channel: note_channel.into(),
+                    message: MidiMessage::NoteOff {
+                        key: note.0.into(),
+                        vel: self.release_velocity.into(),
                     },
+                };
+                // Overlapping a repeated key would cut the next note short instead.
+                let slurred_into = self.notes.get(i + 1).filter(|next_note| {
+                    slurred_notes[i]
+                        && next_note.get_sounding_duration() > 0
+                        && next_note.position.map(|position| self.scale.get_note(position, self.octave)) != Some(note)
                 });
-
-                next_note_delta = 0;
+                let is_rearticulated = self.notes[i + 1..]
+                    .iter()
+                    .find(|next_note| next_note.get_sounding_duration() > 0)
+                    .is_some_and(|next_note| {
+                        next_note.position.map(|position| self.scale.get_note(position, self.octave)) == Some(note)
+                            && next_note.channel.unwrap_or(channel) == note_channel
+                    });
+                if let Some(next_note) = slurred_into {
+                    pending_note_off = Some((note_off, SLUR_OVERLAP_TICKS.min(next_note.get_sounding_duration())));
+                    next_note_delta = remaining_duration;
+                } else if is_rearticulated && remaining_duration > REARTICULATION_GAP_TICKS {
+                    track_events.push(TrackEvent {
+                        delta: (remaining_duration - REARTICULATION_GAP_TICKS).into(),
+                        kind: note_off,
+                    });
+                    next_note_delta = REARTICULATION_GAP_TICKS;
+                } else {
+                    track_events.push(TrackEvent { delta: remaining_duration.into(), kind: note_off });
+                    next_note_delta = 0;
+                }
             } else {
                 next_note_delta += duration;
             }
         }
 
-        // Track end
+        // Leave the channels untuned for whatever plays on them next
+        for (bent_channel, _) in pitch_bends.iter().enumerate().filter(|(_, bend)| **bend != PitchBend::mid_raw_value()) {
+            track_events.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Midi {
+                    channel: u8::try_from(bent_channel).unwrap().into(),
+                    message: MidiMessage::PitchBend { bend: PitchBend::mid_raw_value() },
+                },
+            });
+        }
+
+        // Track end, after any trailing silence
         track_events.push(TrackEvent {
-            delta: 0.into(),
+            delta: next_note_delta.into(),
             kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
         });
 
-        track_events
+        if self.expression_ramps.is_empty() && self.aftertouch_ramps.is_empty() {
+            return track_events;
+        }
+        let expression = |value| MidiMessage::Controller { controller: 11.into(), value };
+        let aftertouch = |vel| MidiMessage::ChannelAftertouch { vel };
+        let mut ramp_events: Vec<Vec<TrackEvent>> = self
+            .expression_ramps
+            .iter()
+            .map(|ramp| self.get_ramp_events(*ramp, channel, expression))
+            .chain(self.aftertouch_ramps.iter().map(|ramp| self.get_ramp_events(*ramp, channel, aftertouch)))
+            .collect();
+        // Release the pressure once the voice and its ramps are over.
+        if !self.aftertouch_ramps.is_empty() {
+            let voice_end: u32 = track_events.iter().map(|event| event.delta.as_int()).sum();
+            let ramps_end = self.aftertouch_ramps.iter().map(|(_, end_tick, _, _)| self.start_tick + end_tick).max();
+            ramp_events.push(vec![TrackEvent {
+                delta: voice_end.max(ramps_end.unwrap_or(0)).into(),
+                kind: TrackEventKind::Midi { channel: channel.into(), message: aftertouch(0.into()) },
+            }]);
+        }
+        merge_tracks([track_events].into_iter().chain(ramp_events).collect())
     }
 }
 
@@ -92,14 +815,14 @@ impl Display for Voice {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut note_names = String::new();
         let mut note_symbols = String::new();
-        for (position, duration) in self.notes.iter() {
-            let note_name = match position {
+        for timed_note in self.notes.iter() {
+            let note_name = match timed_note.position {
                 Some(position) => {
-                    format!("{:4}", self.scale.get_named_note(*position, self.octave).to_string())
+                    format!("{:4}", self.scale.get_named_note(position, self.octave).to_string())
                 }
                 None => "    ".to_string(),
             };
-            let note_symbol = match duration * 16 / TICKS_PER_BEAT {
+            let note_symbol = match timed_note.duration * 16 / TICKS_PER_BEAT {
                 64 => "𝅝   ",
                 48 => "𝅗𝅥𝅭   ",
                 32 => "𝅗𝅥   ",
@@ -111,32 +834,199 @@ impl Display for Voice {
                 2 => "𝅘𝅥𝅰   ",
                 _ => "?   ",
             };
-            note_names.extend(note_name.chars());
-            note_symbols.extend(note_symbol.chars());
+            note_names.push_str(&note_name);
+            note_symbols.push_str(note_symbol);
         }
         write!(f, "{}\n{}", note_names, note_symbols)
     }
 }
 
+/// A piece of music: tracks played together at a given tempo. Prefer `Piece::new`,
+/// which validates the piece, to building it field by field.
+#[cfg(feature = "std")]
 pub struct Piece {
     pub bpm: u8,
     pub tracks: Vec<Box<dyn Track>>,
+    pub title: Option<String>,
+    pub copyright: Option<String>,
+    pub text: Option<String>,
+    /// Rehearsal markers, as (tick, name).
+    pub markers: Vec<(u32, String)>,
+    /// Changes of tempo during the piece, as (tick, bpm).
+    pub tempo_changes: Vec<(u32, u8)>,
+    /// The note value that `bpm` and the tempo changes count, in ticks: a quarter note
+    /// by default, or e.g. a dotted quarter for a tempo marked "♩. = 60".
+    pub tempo_unit_ticks: u32,
+    /// The velocity of notes that don't specify one, between 1 and 127.
+    pub default_velocity: u8,
+    /// The length of the pickup before the first full bar, which doesn't change
+    /// when notes are played but where bars are counted from.
+    pub anacrusis_ticks: u32,
+    /// Write note releases as NoteOn events with velocity 0 rather than NoteOff events.
+    /// This is understood more widely, and lets consecutive note events share a status byte.
+    pub note_off_as_note_on: bool,
+    /// Write the tempo, time signature and other meta events in the first track of the
+    /// piece rather than in a conductor track of their own, which some tools expect.
+    pub merge_conductor_track: bool,
+    /// The pan position of tracks by id, from 0 (left) to 127 (right).
+    pub pans: HashMap<String, u8>,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tracks: Vec<String> = self.tracks.iter().map(|track| track.describe()).collect();
+        f.debug_struct("Piece")
+            .field("bpm", &self.bpm)
+            .field("tracks", &tracks)
+            .finish_non_exhaustive()
+    }
+}
+
+// Tracks are boxed trait objects, so they are cloned through `Track::clone_box`.
+#[cfg(feature = "std")]
+impl Clone for Piece {
+    fn clone(&self) -> Self {
+        Self {
+            bpm: self.bpm,
+            tracks: self.tracks.iter().map(|track| track.clone_box()).collect(),
+            title: self.title.clone(),
+            copyright: self.copyright.clone(),
+            text: self.text.clone(),
+            markers: self.markers.clone(),
+            tempo_changes: self.tempo_changes.clone(),
+            tempo_unit_ticks: self.tempo_unit_ticks,
+            default_velocity: self.default_velocity,
+            anacrusis_ticks: self.anacrusis_ticks,
+            note_off_as_note_on: self.note_off_as_note_on,
+            merge_conductor_track: self.merge_conductor_track,
+            pans: self.pans.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for Piece {
+    fn default() -> Self {
+        Self {
+            bpm: 120,
+            tracks: Vec::new(),
+            title: None,
+            copyright: None,
+            text: None,
+            markers: Vec::new(),
+            tempo_changes: Vec::new(),
+            tempo_unit_ticks: TICKS_PER_BEAT,
+            default_velocity: DEFAULT_VELOCITY,
+            anacrusis_ticks: 0,
+            note_off_as_note_on: false,
+            merge_conductor_track: false,
+            pans: HashMap::new(),
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 impl Piece {
+    /// Create a piece playing `tracks` at `bpm` beats per minute, with the other fields
+    /// set to their defaults.
+    ///
+    /// # Errors
+    /// - if bpm is 0;
+    /// - if there are no tracks;
+    /// - if two tracks share the same id.
+    pub fn new(bpm: u8, tracks: Vec<Box<dyn Track>>) -> Result<Piece, String> {
+        if bpm == 0 {
+            return Err("bpm must be positive!".to_string());
+        }
+        if tracks.is_empty() {
+            return Err("A piece needs at least one track!".to_string());
+        }
+        for (i, track) in tracks.iter().enumerate() {
+            if tracks[..i].iter().any(|other_track| other_track.get_id() == track.get_id()) {
+                return Err(format!("Track id {} is used more than once!", track.get_id()));
+            }
+        }
+        Ok(Piece {
+            bpm,
+            tracks,
+            ..Default::default()
+        })
+    }
+
     pub fn write_midi<W>(&self, w: &mut W) -> std::io::Result<()>
     where
         W: std::io::Write,
     {
-        let header = Header::new(
-            Format::Parallel,
-            Timing::Metrical(u16::from(TICKS_PER_BEAT).into()),
-        );
+        self.write_midi_with_format(Format::Parallel, w)
+    }
+
+    /// Write the piece as MIDI in the given format. With `Format::SingleTrack`,
+    /// all tracks are merged into one.
+    pub fn write_midi_with_format<W>(&self, format: Format, w: &mut W) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        let header = Header::new(format, Timing::Metrical(u16::try_from(TICKS_PER_BEAT).unwrap().into()));
+
+        let mut tracks = self.to_midi_tracks()?;
+        if format == Format::SingleTrack {
+            midly::write_std(&header, [merge_tracks(tracks)].iter(), w)
+        } else {
+            if self.merge_conductor_track && tracks.len() > 1 {
+                let first_tracks = tracks.drain(..2).collect();
+                tracks.insert(0, merge_tracks(first_tracks));
+            }
+            midly::write_std(&header, tracks.iter(), w)
+        }
+    }
+
+    /// Create the conductor track followed by one track per track of the piece.
+    pub(crate) fn to_midi_tracks(&self) -> std::io::Result<Vec<Vec<TrackEvent<'_>>>> {
+        if !(1..=127).contains(&self.default_velocity) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "default velocity must be between 1 and 127!",
+            ));
+        }
+        if self.pans.values().any(|pan| *pan > 127) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "pans must be between 0 and 127!",
+            ));
+        }
+        for track in self.tracks.iter() {
+            track.check_midi().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        }
+        let mut meta_ticks = self.tempo_changes.iter().map(|(tick, _)| tick).chain(self.markers.iter().map(|(tick, _)| tick));
+        if meta_ticks.any(|tick| *tick > MAX_TICK) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Tempo changes and markers must not go past tick {}!", MAX_TICK),
+            ));
+        }
 
-        let microseconds_per_beat = 500000 * 120 / u32::from(self.bpm);
+        let instrument_report = self
+            .instrument_report()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+        let to_io_error = |err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err);
+        let microseconds_per_beat = get_microseconds_per_beat(self.bpm, self.tempo_unit_ticks).map_err(to_io_error)?;
 
         // The first track must contain tempo and time signature information.
-        let mut tracks: Vec<Vec<TrackEvent>> = vec![vec![
+        let mut conductor_track: Vec<TrackEvent> = [
+            self.title.as_ref().map(|title| MetaMessage::TrackName(title.as_bytes())),
+            self.copyright.as_ref().map(|copyright| MetaMessage::Copyright(copyright.as_bytes())),
+            self.text.as_ref().map(|text| MetaMessage::Text(text.as_bytes())),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|meta_message| TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(meta_message),
+        })
+        .collect();
+        conductor_track.extend([
             // MIDI sets tempo in microseconds per beat, e.g. 120bpm is 500000 microseconds/beat.
             // Note that the number of MIDI ticks per beat is set with the TICKS_PER_BEAT constant.
             TrackEvent {
@@ -148,67 +1038,2037 @@ impl Piece {
                 delta: 0.into(),
                 kind: TrackEventKind::Meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
             },
-            TrackEvent {
-                delta: 0.into(),
-                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-            },
-        ]];
+        ]);
 
-        for (i, track) in self.tracks.iter().enumerate() {
-            let track_to_midi = track.to_midi(1, u8::try_from(i).unwrap() % 16);
+        let mut markers: Vec<&(u32, String)> = self.markers.iter().collect();
+        markers.sort();
+        markers.dedup();
+        let mut timed_meta_messages: Vec<(u32, MetaMessage)> = self
+            .tempo_changes
+            .iter()
+            .map(|(tick, bpm)| Ok((*tick, MetaMessage::Tempo(get_microseconds_per_beat(*bpm, self.tempo_unit_ticks)?.into()))))
+            .chain(markers.into_iter().map(|(tick, name)| Ok((*tick, MetaMessage::Marker(name.as_bytes())))))
+            .collect::<Result<_, String>>()
+            .map_err(to_io_error)?;
+        timed_meta_messages.sort_by_key(|(tick, _)| *tick);
+
+        let mut tick = 0;
+        for (message_tick, meta_message) in timed_meta_messages {
+            conductor_track.push(TrackEvent {
+                delta: (message_tick - tick).into(),
+                kind: TrackEventKind::Meta(meta_message),
+            });
+            tick = message_tick;
+        }
+
+        conductor_track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        let mut tracks: Vec<Vec<TrackEvent>> = vec![conductor_track];
+
+        // Skipped tracks keep their channels free, so that the others don't move.
+        let any_solo = self.tracks.iter().any(|track| track.is_solo());
+        for (track, (_, program, channel)) in self.tracks.iter().zip(instrument_report) {
+            if track.is_muted() || (any_solo && !track.is_solo()) {
+                continue;
+            }
+            let mut track_to_midi = track.to_midi(program, channel, self.default_velocity);
+            // Pan right after the instrument is set
+            if let Some(pan) = self.pans.get(track.get_id()) {
+                track_to_midi.insert(
+                    1,
+                    TrackEvent {
+                        delta: 0.into(),
+                        kind: TrackEventKind::Midi {
+                            channel: channel.into(),
+                            message: MidiMessage::Controller { controller: 10.into(), value: (*pan).into() },
+                        },
+                    },
+                );
+            }
+            if self.note_off_as_note_on {
+                for event in track_to_midi.iter_mut() {
+                    if let TrackEventKind::Midi { message, .. } = &mut event.kind {
+                        if let MidiMessage::NoteOff { key, .. } = *message {
+                            *message = MidiMessage::NoteOn { key, vel: 0.into() };
+                        }
+                    }
+                }
+            }
             tracks.push(track_to_midi);
         }
-        midly::write_std(&header, tracks.iter(), w)
+        Ok(tracks)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::super::NamedKey;
-    use super::*;
-    use std::io::Cursor;
+    /// Play `other` after this piece: its tracks start `gap_beats` beats after the last
+    /// track of this piece ends, rounded up to a whole beat. The tempo of `other` is
+    /// kept by changing tempo where it starts, counted in the tempo unit of this piece.
+    ///
+    /// # Errors
+    /// - if a piece has a tempo unit of 0 ticks;
+    /// - if a tempo of `other` is above 255 bpm in the tempo unit of this piece;
+    /// - if the ticks of `other` would go past u32 once moved after this piece.
+    ///
+    /// Nothing is appended in case of an error.
+    pub fn append(&mut self, other: &Piece, gap_beats: u32) -> Result<(), String> {
+        if self.tempo_unit_ticks == 0 || other.tempo_unit_ticks == 0 {
+            return Err("Tempo units must last at least a tick!".to_string());
+        }
+        let too_long = || "The appended piece would go past the last tick!".to_string();
+        let offset_ticks = self
+            .get_end_tick()
+            .div_ceil(TICKS_PER_BEAT)
+            .checked_add(gap_beats)
+            .and_then(|offset_beats| offset_beats.checked_mul(TICKS_PER_BEAT))
+            .ok_or_else(too_long)?;
+        let shift = |tick: u32| tick.checked_add(offset_ticks).ok_or_else(too_long);
 
-    #[test]
-    fn can_generate_midi_harpsichord() {
-        let c = str::parse::<NamedKey>("C").unwrap();
-        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
-        let octave = 4;
+        // Rounded to the nearest bpm when the pieces count different note values.
+        let to_tempo_unit = |bpm: u8| {
+            let (unit, other_unit) = (u64::from(self.tempo_unit_ticks), u64::from(other.tempo_unit_ticks));
+            let converted_bpm = (u64::from(bpm) * other_unit + unit / 2) / unit;
+            u8::try_from(converted_bpm.max(1))
+                .map_err(|_| format!("A tempo of {} bpm is {} bpm in the tempo unit of this piece, above 255!", bpm, converted_bpm))
+        };
+        let bpm_at_end = self
+            .tempo_changes
+            .iter()
+            .max_by_key(|(tick, _)| *tick)
+            .map_or(self.bpm, |(_, bpm)| *bpm);
+        let bpm = to_tempo_unit(other.bpm)?;
+        let tempo_changes = other
+            .tempo_changes
+            .iter()
+            .map(|(tick, bpm)| Ok((shift(*tick)?, to_tempo_unit(*bpm)?)))
+            .collect::<Result<Vec<(u32, u8)>, String>>()?;
+        let markers = other
+            .markers
+            .iter()
+            .map(|(tick, name)| Ok((shift(*tick)?, name.clone())))
+            .collect::<Result<Vec<(u32, String)>, String>>()?;
+        let tracks = other
+            .tracks
+            .iter()
+            .map(|track| {
+                let mut track = track.clone_box();
+                track.set_start_tick(shift(*track.get_start_tick())?);
+                Ok(track)
+            })
+            .collect::<Result<Vec<Box<dyn Track>>, String>>()?;
 
-        let wtc_1_1_prelude = Piece {
-            bpm: 120,
-            tracks: vec![Box::new(Voice {
-                id: "voice_1".to_string(),
-                start: 0,
-                scale: c_major_scale,
-                octave,
-                notes: [0, 2, 4, 7, 9, 4, 7, 9]
-                    .into_iter()
-                    .map(|position| (Some(position), TICKS_PER_BEAT / 2))
-                    .collect(),
-            })],
+        if bpm != bpm_at_end {
+            self.tempo_changes.push((offset_ticks, bpm));
+        }
+        self.tempo_changes.extend(tempo_changes);
+        self.markers.extend(markers);
+        self.tracks.extend(tracks);
+        for (id, pan) in other.pans.iter() {
+            self.pans.entry(id.clone()).or_insert(*pan);
+        }
+        Ok(())
+    }
+
+    /// Play `other` at the same time as this piece. Its tracks are added after the tracks
+    /// of this piece, so that they get channels of their own.
+    ///
+    /// # Errors
+    /// - if the pieces don't share the same tempo;
+    /// - if there would be more tracks than MIDI channels.
+    pub fn overlay(&mut self, other: &Piece) -> Result<(), String> {
+        if other.bpm != self.bpm
+            || other.tempo_changes != self.tempo_changes
+            || other.tempo_unit_ticks != self.tempo_unit_ticks
+        {
+            return Err(format!(
+                "Cannot overlay a piece at {} bpm on a piece at {} bpm, or with different tempo changes!",
+                other.bpm, self.bpm
+            ));
+        }
+        if self.tracks.len() + other.tracks.len() > 16 {
+            return Err("Cannot overlay pieces with more than 16 tracks in total!".to_string());
+        }
+
+        self.tracks.extend(other.tracks.iter().map(|track| track.clone_box()));
+        self.markers.extend(other.markers.iter().cloned());
+        for (id, pan) in other.pans.iter() {
+            self.pans.entry(id.clone()).or_insert(*pan);
+        }
+        Ok(())
+    }
+
+    /// Merge the tracks that each play a single note, starting on the same tick and
+    /// lasting as long, into chord tracks, e.g. the stacked notes of block chords. Only
+    /// tracks in the same scale, and with the same flags, are merged: notes that merely
+    /// overlap are not, nor are notes too far apart for the positions of a chord. Each
+    /// chord takes the id and place of its first track.
+    pub fn coalesce_chords(&mut self) {
+        let is_same_chord = |track: &dyn Track, other: &dyn Track| {
+            track.get_start_tick() == other.get_start_tick()
+                && track.get_single_note().map(|(_, _, duration)| duration)
+                    == other.get_single_note().map(|(_, _, duration)| duration)
+                && track.get_scale() == other.get_scale()
+                && track.is_muted() == other.is_muted()
+                && track.is_solo() == other.is_solo()
+                && track.get_channel() == other.get_channel()
         };
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for (index, track) in self.tracks.iter().enumerate() {
+            if track.get_single_note().is_none() {
+                continue;
+            }
+            match groups.iter_mut().find(|group| is_same_chord(self.tracks[group[0]].as_ref(), track.as_ref())) {
+                Some(group) => group.push(index),
+                None => groups.push(vec![index]),
+            }
+        }
+        groups.retain(|group| group.len() > 1);
 
-        let mut buffer = Cursor::new(vec![0; 100]);
-        wtc_1_1_prelude.write_midi(&mut buffer).unwrap();
+        let mut chords: HashMap<usize, Box<dyn Track>> = HashMap::new();
+        let mut merged: Vec<usize> = Vec::new();
+        for group in groups.iter() {
+            let first = self.tracks[group[0]].as_ref();
+            let notes: Vec<(i8, i8, u32)> =
+                group.iter().filter_map(|index| self.tracks[*index].get_single_note()).collect();
+            let octave = notes.iter().map(|(octave, _, _)| *octave).min().unwrap();
+            let Ok(scale_len) = i16::try_from(first.get_scale().len()) else { continue };
+            let Some(mut positions) = notes
+                .iter()
+                .map(|(note_octave, position, _)| {
+                    let position = i16::from(*position) + (i16::from(*note_octave) - i16::from(octave)) * scale_len;
+                    i8::try_from(position).ok()
+                })
+                .collect::<Option<Vec<i8>>>()
+            else {
+                continue;
+            };
+            positions.sort_unstable();
+            positions.dedup();
+            let mut chord = Chord::new(
+                first.get_id().to_string(),
+                first.get_scale().clone(),
+                octave,
+                *first.get_start_tick(),
+                positions,
+                vec![(true, notes[0].2)],
+            );
+            chord.muted = first.is_muted();
+            chord.solo = first.is_solo();
+            chord.channel = first.get_channel();
+            chords.insert(group[0], Box::new(chord));
+            merged.extend(group[1..].iter().copied());
+        }
+
+        let tracks = core::mem::take(&mut self.tracks);
+        self.tracks = tracks
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !merged.contains(index))
+            .map(|(index, track)| chords.remove(&index).unwrap_or(track))
+            .collect();
     }
 
-    #[test]
-    fn can_format_track() {
-        let c = str::parse::<NamedKey>("C").unwrap();
-        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
-        let octave = 4;
+    /// Add a metronome track on the General MIDI percussion channel, clicking on every
+    /// beat of the piece's `(beats per bar, beat unit)` time signature until its last
+    /// track ends. Downbeats are accented with a metronome bell (key 34) and the other
+    /// beats are rim shots (key 37). Bars are counted from the end of the pickup, like
+    /// other metric functions.
+    ///
+    /// # Errors
+    /// - if the time signature has no beats or a beat unit that isn't a power of two from 1 to 32;
+    /// - if a track already plays on the percussion channel;
+    /// - if a track already has the id "click".
+    pub fn add_click_track(&mut self, time_signature: (u8, u8)) -> Result<(), String> {
+        const DOWNBEAT_KEY: i8 = 34;
+        const BEAT_KEY: i8 = 37;
+        let (beat_ticks, bar_ticks) = get_beat_and_bar_ticks(time_signature)?;
+        if let Some(track) = self.tracks.iter().find(|track| track.get_channel() == Some(PERCUSSION_CHANNEL)) {
+            return Err(format!("Track {} already plays on the percussion channel!", track.get_id()));
+        }
+        if self.tracks.iter().any(|track| track.get_id() == "click") {
+            return Err("A track with id click already exists!".to_string());
+        }
 
-        let wtc_1_1_prelude_track = Voice {
-            id: "voice_1".to_string(),
-            start: 0,
-            scale: c_major_scale,
-            octave,
-            notes: [0, 2, 4, 7, 9, 4, 7, 9]
-                .into_iter()
-                .map(|position| (Some(position), TICKS_PER_BEAT / 2))
-                .collect(),
+        // Shift the ticks so that 0 falls on a bar line.
+        let bar_offset = bar_ticks - self.anacrusis_ticks % bar_ticks;
+        let notes = (0..self.get_end_tick().div_ceil(beat_ticks))
+            .map(|beat| {
+                let is_downbeat = (beat * beat_ticks + bar_offset).is_multiple_of(bar_ticks);
+                let mut timed_note =
+                    TimedNote::new(Some(if is_downbeat { DOWNBEAT_KEY } else { BEAT_KEY }), beat_ticks);
+                if is_downbeat {
+                    timed_note.velocity = Some(127);
+                }
+                timed_note
+            })
+            .collect();
+
+        // Keys are the positions of a chromatic scale starting at C-1.
+        let c = NamedKey::new(BaseKey::C, KeyModifier::Natural);
+        let mut click_track = Voice::new("click".to_string(), Scale::chromatic(c), -1, 0, notes);
+        click_track.channel = Some(PERCUSSION_CHANNEL);
+        self.tracks.push(Box::new(click_track));
+        Ok(())
+    }
+
+    /// Move every track, marker and tempo change of the piece by `ticks`, which may be
+    /// negative, e.g. to make room for a pickup before the first downbeat.
+    ///
+    /// # Errors
+    /// - if anything would start before tick 0, in which case nothing is moved.
+    pub fn shift_all(&mut self, ticks: i64) -> Result<(), String> {
+        let shift = |tick: u32| -> Result<u32, String> {
+            u32::try_from(i64::from(tick) + ticks)
+                .map_err(|_| format!("Cannot shift tick {} by {} ticks!", tick, ticks))
         };
+        let starts = self.tracks.iter().map(|track| shift(*track.get_start_tick())).collect::<Result<Vec<u32>, String>>()?;
+        let markers = self
+            .markers
+            .iter()
+            .map(|(tick, name)| Ok((shift(*tick)?, name.clone())))
+            .collect::<Result<Vec<(u32, String)>, String>>()?;
+        let tempo_changes = self
+            .tempo_changes
+            .iter()
+            .map(|(tick, bpm)| Ok((shift(*tick)?, *bpm)))
+            .collect::<Result<Vec<(u32, u8)>, String>>()?;
 
-        wtc_1_1_prelude_track.to_string();
+        for (track, start) in self.tracks.iter_mut().zip(starts) {
+            track.set_start_tick(start);
+        }
+        self.markers = markers;
+        self.tempo_changes = tempo_changes;
+        Ok(())
+    }
+
+    /// The id, MIDI program and MIDI channel of each track, as they are written out.
+    /// Tracks keep the channel they claim, and the others get the remaining channels in
+    /// order, wrapping around once they are all used.
+    ///
+    /// # Errors
+    /// - if a track claims a channel above 15;
+    /// - if two tracks claim the same channel;
+    /// - if every channel is claimed but some tracks don't claim one.
+    pub fn instrument_report(&self) -> Result<Vec<(String, u8, u8)>, String> {
+        let mut claimed_channels = Vec::new();
+        for track in self.tracks.iter() {
+            if let Some(channel) = track.get_channel() {
+                if channel > 15 {
+                    return Err(format!("Track {} claims channel {}, channels go from 0 to 15!", track.get_id(), channel));
+                }
+                if claimed_channels.contains(&channel) {
+                    return Err(format!("Channel {} is claimed by more than one track!", channel));
+                }
+                claimed_channels.push(channel);
+            }
+        }
+        let free_channels: Vec<u8> = (0..16).filter(|channel| !claimed_channels.contains(channel)).collect();
+
+        let mut free_channels_cycle = free_channels.iter().cycle();
+        self.tracks
+            .iter()
+            .map(|track| {
+                let channel = match track.get_channel() {
+                    Some(channel) => channel,
+                    None => *free_channels_cycle
+                        .next()
+                        .ok_or_else(|| format!("No channel left for track {}!", track.get_id()))?,
+                };
+                Ok((track.get_id().to_string(), 1, channel))
+            })
+            .collect()
+    }
+
+    /// Snap the notes of every track to the closest notated durations, keeping every note
+    /// where it starts, e.g. to clean up a piece read from a performance.
+    pub fn snap_durations(&mut self) {
+        for track in self.tracks.iter_mut() {
+            track.snap_durations();
+        }
+    }
+
+    /// Apply `groove` to every track of the piece, e.g. `Groove::swing_8ths()` to swing
+    /// all of its eighths.
+    pub fn apply_groove(&mut self, groove: &Groove) {
+        for track in self.tracks.iter_mut() {
+            track.apply_groove(groove, self.default_velocity);
+        }
+    }
+
+    /// Spread the tracks evenly from left to right, in order.
+    pub fn auto_pan(&mut self) {
+        let last_index = self.tracks.len().saturating_sub(1);
+        for (i, track) in self.tracks.iter().enumerate() {
+            // A single track stays in the center.
+            let pan = (127 * i + last_index / 2).checked_div(last_index).unwrap_or(64);
+            self.pans.insert(track.get_id().to_string(), u8::try_from(pan).unwrap());
+        }
+    }
+
+    /// The tick at which the last track of the piece ends.
+    fn get_end_tick(&self) -> u32 {
+        self.tracks.iter().map(|track| track.total_ticks()).max().unwrap_or(0)
+    }
+
+    /// How long the piece plays, in seconds, until its last track ends, following its
+    /// tempo changes.
+    pub fn duration_seconds(&self) -> f64 {
+        let end_tick = self.get_end_tick();
+        let mut tempo_changes: Vec<(u32, u8)> =
+            self.tempo_changes.iter().copied().filter(|(tick, _)| *tick < end_tick).collect();
+        tempo_changes.sort_by_key(|(tick, _)| *tick);
+
+        let mut seconds = 0.0;
+        let (mut tick, mut bpm) = (0, self.bpm);
+        for (change_tick, change_bpm) in tempo_changes.into_iter().chain([(end_tick, self.bpm)]) {
+            seconds += f64::from(change_tick - tick) / f64::from(self.tempo_unit_ticks) * 60.0 / f64::from(bpm);
+            (tick, bpm) = (change_tick, change_bpm);
+        }
+        seconds
+    }
+
+    /// List the notes of the piece as CSV, one row per note in the order the tracks write
+    /// them to MIDI, with the columns track_id, onset_tick, pitch_midi, pitch_name,
+    /// duration_tick, velocity and channel. Rests, and tracks left out of the MIDI file,
+    /// are left out. Pitches are spelled in the scale of their track when they belong to
+    /// it, and with sharps otherwise.
+    ///
+    /// # Errors
+    /// - if the tracks can't be given MIDI channels, see `instrument_report`.
+    pub fn to_csv(&self) -> Result<String, String> {
+        let mut csv = "track_id,onset_tick,pitch_midi,pitch_name,duration_tick,velocity,channel\n".to_string();
+
+        let any_solo = self.tracks.iter().any(|track| track.is_solo());
+        for (track, (_, program, channel)) in self.tracks.iter().zip(self.instrument_report()?) {
+            if track.is_muted() || (any_solo && !track.is_solo()) {
+                continue;
+            }
+            let track_id = if track.get_id().contains([',', '"', '\n']) {
+                format!("\"{}\"", track.get_id().replace('"', "\"\""))
+            } else {
+                track.get_id().to_string()
+            };
+
+            // Notes that are sounding, as (channel, key, onset tick, velocity).
+            let mut sounding_notes: Vec<(u8, u8, u32, u8)> = Vec::new();
+            let mut rows: Vec<(u32, u8, u32, u8, u8)> = Vec::new();
+            let mut tick = 0;
+            for event in track.to_midi(program, channel, self.default_velocity) {
+                tick += event.delta.as_int();
+                let TrackEventKind::Midi { channel, message } = event.kind else {
+                    continue;
+                };
+                let channel = channel.as_int();
+                match message {
+                    MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                        sounding_notes.push((channel, key.as_int(), tick, vel.as_int()));
+                    }
+                    MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                        let released_note = sounding_notes
+                            .iter()
+                            .position(|(note_channel, note_key, _, _)| *note_channel == channel && *note_key == key.as_int());
+                        if let Some(index) = released_note {
+                            let (_, key, onset, velocity) = sounding_notes.remove(index);
+                            rows.push((onset, key, tick - onset, velocity, channel));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            rows.sort_by_key(|(onset, ..)| *onset);
+
+            for (onset, key, duration, velocity, channel) in rows {
+                let pitch_name = super::midi_input::spell_note(Note(key), Some(track.get_scale()));
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{}\n",
+                    track_id, onset, key, pitch_name, duration, velocity, channel
+                ));
+            }
+        }
+        Ok(csv)
+    }
+
+    /// Write the piece as MIDI to an in-memory buffer.
+    pub fn to_midi_bytes(&self) -> std::io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.write_midi(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// MIDI sets tempo in microseconds per beat, e.g. 120bpm is 500000 microseconds/beat,
+/// where a beat is a quarter note. When `bpm` counts another note value, lasting
+/// `tempo_unit_ticks`, it is converted, e.g. 60 dotted quarters a minute is 666666
+/// microseconds/beat.
+///
+/// # Errors
+/// - if the bpm or the tempo unit is 0;
+/// - if a beat doesn't last between 1 and 2^24 - 1 microseconds, as MIDI requires.
+#[cfg(feature = "std")]
+pub(crate) fn get_microseconds_per_beat(bpm: u8, tempo_unit_ticks: u32) -> Result<u32, String> {
+    Some(u64::from(bpm) * u64::from(tempo_unit_ticks))
+        .filter(|ticks_per_minute| *ticks_per_minute > 0)
+        .map(|ticks_per_minute| 500000 * 120 * u64::from(TICKS_PER_BEAT) / ticks_per_minute)
+        .and_then(|microseconds| u32::try_from(microseconds).ok())
+        .filter(|microseconds| (1..1 << 24).contains(microseconds))
+        .ok_or_else(|| format!("A tempo of {} bpm counting {} ticks can't be written to MIDI!", bpm, tempo_unit_ticks))
+}
+
+/// Merge MIDI tracks into a single track, keeping every event at the same time.
+pub(crate) fn merge_tracks(tracks: Vec<Vec<TrackEvent>>) -> Vec<TrackEvent> {
+    let mut timed_events: Vec<(u32, TrackEvent)> = Vec::new();
+    let mut end = 0;
+    for track in tracks {
+        let mut time = 0;
+        for event in track {
+            time += event.delta.as_int();
+            if event.kind != TrackEventKind::Meta(MetaMessage::EndOfTrack) {
+                timed_events.push((time, event));
+            }
+        }
+        end = end.max(time);
+    }
+    // This sort is stable, so simultaneous events keep the order of their tracks.
+    timed_events.sort_by_key(|(time, _)| *time);
+
+    let mut merged_track = Vec::new();
+    let mut previous_time = 0;
+    for (time, event) in timed_events {
+        merged_track.push(TrackEvent {
+            delta: (time - previous_time).into(),
+            kind: event.kind,
+        });
+        previous_time = time;
+    }
+    merged_track.push(TrackEvent {
+        delta: (end - previous_time).into(),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    merged_track
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::super::tuning::Tuning;
+    use super::super::{json_input, Chord, NamedKey};
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn can_generate_midi_harpsichord() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+        let octave = 4;
+
+        let wtc_1_1_prelude = Piece {
+            bpm: 120,
+            tracks: vec![Box::new(Voice::new(
+                "voice_1".to_string(),
+                c_major_scale,
+                octave,
+                0,
+                [0, 2, 4, 7, 9, 4, 7, 9]
+                    .into_iter()
+                    .map(|position| TimedNote::new(Some(position), TICKS_PER_BEAT / 2))
+                    .collect(),
+            ))],
+            ..Default::default()
+        };
+
+        let mut buffer = Cursor::new(vec![0; 100]);
+        wtc_1_1_prelude.write_midi(&mut buffer).unwrap();
+    }
+
+    #[test]
+    fn can_generate_midi_bytes() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+
+        let piece = Piece {
+            bpm: 120,
+            tracks: vec![Box::new(Voice::new(
+                "voice_1".to_string(),
+                c_major_scale,
+                4,
+                0,
+                vec![TimedNote::new(Some(0), TICKS_PER_BEAT), TimedNote::new(None, TICKS_PER_BEAT)],
+            ))],
+            ..Default::default()
+        };
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let mut expected_bytes = Vec::new();
+        piece.write_midi(&mut expected_bytes).unwrap();
+
+        assert_eq!(&bytes[..4], b"MThd");
+        assert_eq!(bytes.len(), expected_bytes.len());
+    }
+
+    #[test]
+    fn can_set_default_velocity() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let mut loud_note = TimedNote::new(Some(2), TICKS_PER_BEAT);
+        loud_note.velocity = Some(120);
+
+        let mut piece = Piece {
+            tracks: vec![
+                Box::new(Voice::new(
+                    "voice_1".to_string(),
+                    c_major_scale.clone(),
+                    4,
+                    0,
+                    vec![TimedNote::new(Some(0), TICKS_PER_BEAT), loud_note],
+                )),
+                Box::new(Chord::new(
+                    "chord_1".to_string(),
+                    c_major_scale,
+                    3,
+                    0,
+                    vec![0, 2, 4],
+                    vec![(true, TICKS_PER_BEAT)],
+                )),
+            ],
+            default_velocity: 64,
+            ..Default::default()
+        };
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let velocities: Vec<u8> = smf
+            .tracks
+            .iter()
+            .flatten()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } => {
+                    Some(vel.as_int())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(velocities, [64, 120, 64, 64, 64]);
+
+        piece.default_velocity = 0;
+        assert!(piece.to_midi_bytes().is_err());
+    }
+
+    #[test]
+    fn can_write_single_track() {
+        let piece = json_input::parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
+        let count_note_events = |smf: &midly::Smf| {
+            smf.tracks
+                .iter()
+                .flatten()
+                .filter(|event| {
+                    matches!(
+                        event.kind,
+                        TrackEventKind::Midi {
+                            message: MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. },
+                            ..
+                        }
+                    )
+                })
+                .count()
+        };
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let parallel = midly::Smf::parse(&bytes).unwrap();
+
+        let mut bytes = Vec::new();
+        piece.write_midi_with_format(Format::SingleTrack, &mut bytes).unwrap();
+        let single_track = midly::Smf::parse(&bytes).unwrap();
+
+        assert_eq!(single_track.header.format, Format::SingleTrack);
+        assert_eq!(single_track.tracks.len(), 1);
+        assert_eq!(count_note_events(&single_track), count_note_events(&parallel));
+
+        let track_length = |track: &Vec<TrackEvent>| -> u32 {
+            track.iter().map(|event| event.delta.as_int()).sum()
+        };
+        let longest_track = parallel.tracks.iter().map(track_length).max();
+        assert_eq!(Some(track_length(&single_track.tracks[0])), longest_track);
+    }
+
+    #[test]
+    fn can_write_note_off_as_note_on() {
+        let mut piece = json_input::parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
+        let count_messages = |bytes: &[u8]| -> (usize, usize) {
+            let smf = midly::Smf::parse(bytes).unwrap();
+            let messages: Vec<MidiMessage> = smf
+                .tracks
+                .iter()
+                .flatten()
+                .filter_map(|event| match event.kind {
+                    TrackEventKind::Midi { message, .. } => Some(message),
+                    _ => None,
+                })
+                .collect();
+            let silent_note_ons = messages
+                .iter()
+                .filter(|message| matches!(message, MidiMessage::NoteOn { vel, .. } if *vel == 0))
+                .count();
+            let note_offs = messages
+                .iter()
+                .filter(|message| matches!(message, MidiMessage::NoteOff { .. }))
+                .count();
+            (silent_note_ons, note_offs)
+        };
+
+        let (silent_note_ons, note_offs) = count_messages(&piece.to_midi_bytes().unwrap());
+        assert_eq!(silent_note_ons, 0);
+        assert!(note_offs > 0);
+
+        piece.note_off_as_note_on = true;
+        let bytes = piece.to_midi_bytes().unwrap();
+        assert_eq!(count_messages(&bytes), (note_offs, 0));
+    }
+
+    #[test]
+    fn can_merge_conductor_track() {
+        let mut piece = json_input::parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
+        let has_tempo = |track: &Vec<TrackEvent>| {
+            track.iter().any(|event| matches!(event.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))))
+        };
+        let has_notes = |track: &Vec<TrackEvent>| {
+            track.iter().any(|event| matches!(event.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. }))
+        };
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.tracks.len(), piece.tracks.len() + 1);
+        assert!(has_tempo(&smf.tracks[0]) && !has_notes(&smf.tracks[0]));
+
+        piece.merge_conductor_track = true;
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.tracks.len(), piece.tracks.len());
+        assert!(has_tempo(&smf.tracks[0]) && has_notes(&smf.tracks[0]));
+        assert!(smf.tracks[1..].iter().all(|track| !has_tempo(track)));
+    }
+
+    #[test]
+    fn can_clone_pieces() {
+        let mut piece = json_input::parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
+        piece.markers.push((0, "Exposition".to_string()));
+        let clone = piece.clone();
+        assert!(clone.tracks.len() > 1);
+        assert_eq!(clone.to_midi_bytes().unwrap(), piece.to_midi_bytes().unwrap());
+
+        // Changing the clone leaves the original alone.
+        let mut clone = clone;
+        clone.tracks[0].set_start_tick(TICKS_PER_BEAT);
+        assert_ne!(clone.to_midi_bytes().unwrap(), piece.to_midi_bytes().unwrap());
+        assert_eq!(piece.tracks[0].get_start_tick(), &0);
+    }
+
+    #[test]
+    fn can_auto_pan() {
+        let mut piece = json_input::parse_piece(
+            r#"{"bpm": 120, "tracks": [
+                {"id": "soprano", "scale": "Cmaj", "octave": 5, "start": 0, "type": "voice", "notes": [0]},
+                {"id": "alto", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0]},
+                {"id": "bass", "scale": "Cmaj", "octave": 3, "start": 0, "type": "voice", "notes": [0]}
+            ]}"#,
+        )
+        .unwrap();
+        piece.auto_pan();
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let pans: Vec<(u8, u8)> = smf
+            .tracks
+            .iter()
+            .flatten()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } }
+                    if controller == 10 =>
+                {
+                    Some((channel.as_int(), value.as_int()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pans, [(0, 0), (1, 64), (2, 127)]);
+
+        piece.pans.insert("alto".to_string(), 128);
+        assert!(piece.to_midi_bytes().is_err());
+    }
+
+    #[test]
+    fn can_report_instruments() {
+        let piece = json_input::parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
+        let report = piece.instrument_report().unwrap();
+        assert_eq!(report.len(), piece.tracks.len());
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let written: Vec<(String, u8, u8)> = smf.tracks[1..]
+            .iter()
+            .zip(piece.tracks.iter())
+            .map(|(track_events, track)| {
+                let (program, channel) = track_events
+                    .iter()
+                    .find_map(|event| match event.kind {
+                        TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { program } } => {
+                            Some((program.as_int(), channel.as_int()))
+                        }
+                        _ => None,
+                    })
+                    .unwrap();
+                (track.get_id().to_string(), program, channel)
+            })
+            .collect();
+        assert_eq!(report, written);
+    }
+
+    #[test]
+    fn can_get_duration_in_seconds() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+        let voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![TimedNote::new(Some(0), TICKS_PER_BEAT); 8],
+        );
+        let mut piece = Piece {
+            bpm: 120,
+            tracks: vec![Box::new(voice)],
+            ..Default::default()
+        };
+        assert_eq!(piece.duration_seconds(), 4.0);
+
+        // Half of the piece at half the tempo, and a change after its end.
+        piece.tempo_changes = vec![(10 * TICKS_PER_BEAT, 240), (4 * TICKS_PER_BEAT, 60)];
+        assert_eq!(piece.duration_seconds(), 6.0);
+
+        // Counting half notes, 60 bpm plays two quarters a second.
+        piece.tempo_changes.clear();
+        (piece.bpm, piece.tempo_unit_ticks) = (60, 2 * TICKS_PER_BEAT);
+        assert_eq!(piece.duration_seconds(), 4.0);
+
+        assert_eq!(Piece::default().duration_seconds(), 0.0);
+    }
+
+    #[test]
+    fn can_list_notes_as_csv() {
+        let piece = json_input::parse_piece(
+            r#"{
+                "bpm": 120,
+                "tracks": [
+                    {"id": "melody", "scale": "Fmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0, null, 3]},
+                    {"id": "alto", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0], "muted": true},
+                    {"id": "bass, left hand", "scale": "Cmaj", "octave": 3, "start": 1, "type": "chord", "chord": [0, 4], "notes": [{"2": true}]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let csv = piece.to_csv().unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            rows,
+            [
+                "track_id,onset_tick,pitch_midi,pitch_name,duration_tick,velocity,channel",
+                "melody,0,65,F4,24,100,0",
+                "melody,48,70,B♭4,24,100,0",
+                "\"bass, left hand\",24,48,C3,48,100,2",
+                "\"bass, left hand\",24,55,G3,48,100,2",
+            ]
+        );
+    }
+
+    #[test]
+    fn can_mute_and_solo_tracks() {
+        let piece = json_input::parse_piece(
+            r#"{
+                "bpm": 120,
+                "tracks": [
+                    {"id": "soprano", "scale": "Cmaj", "octave": 5, "start": 0, "type": "voice", "notes": [0], "solo": true},
+                    {"id": "alto", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0]},
+                    {"id": "bass", "scale": "Cmaj", "octave": 3, "start": 0, "type": "chord", "chord": [0, 4], "notes": [0]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let written_channels = |piece: &Piece| -> Vec<u8> {
+            let bytes = piece.to_midi_bytes().unwrap();
+            let smf = midly::Smf::parse(&bytes).unwrap();
+            smf.tracks[1..]
+                .iter()
+                .map(|track_events| {
+                    track_events
+                        .iter()
+                        .find_map(|event| match event.kind {
+                            TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+                            _ => None,
+                        })
+                        .unwrap()
+                })
+                .collect()
+        };
+        assert_eq!(written_channels(&piece), [0]);
+
+        // Without solos, muted tracks are skipped and the others keep their channels.
+        let piece = json_input::parse_piece(
+            r#"{
+                "bpm": 120,
+                "tracks": [
+                    {"id": "soprano", "scale": "Cmaj", "octave": 5, "start": 0, "type": "voice", "notes": [0]},
+                    {"id": "alto", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0], "muted": true},
+                    {"id": "bass", "scale": "Cmaj", "octave": 3, "start": 0, "type": "chord", "chord": [0, 4], "notes": [0]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(written_channels(&piece), [0, 2]);
+
+        let invalid_flag = r#"{"bpm": 120, "tracks": [{"id": "alto", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0], "muted": 1}]}"#;
+        assert_eq!(json_input::parse_piece(invalid_flag).unwrap_err(), "muted should be bool!");
+    }
+
+    #[test]
+    fn can_claim_channels() {
+        let track = |id: &str, channel: Option<u8>| -> String {
+            let channel = channel.map_or(String::new(), |channel| format!(r#", "channel": {}"#, channel));
+            format!(r#"{{"id": "{}", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0]{}}}"#, id, channel)
+        };
+        let channels = |tracks: &[String]| -> Result<Vec<u8>, String> {
+            let piece = json_input::parse_piece(&format!(r#"{{"bpm": 120, "tracks": [{}]}}"#, tracks.join(",")))?;
+            Ok(piece.instrument_report()?.into_iter().map(|(_, _, channel)| channel).collect())
+        };
+
+        let drums = track("drums", Some(5));
+        let others: Vec<String> = (0..6).map(|i| track(&format!("voice_{}", i), None)).collect();
+        for position in 0..=others.len() {
+            let mut tracks = others.clone();
+            tracks.insert(position, drums.clone());
+            let channels = channels(&tracks).unwrap();
+            assert_eq!(channels[position], 5);
+            let mut other_channels: Vec<u8> = channels.into_iter().filter(|channel| *channel != 5).collect();
+            other_channels.sort();
+            assert_eq!(other_channels, [0, 1, 2, 3, 4, 6]);
+        }
+
+        assert_eq!(
+            channels(&[drums.clone(), track("percussion", Some(5))]).unwrap_err(),
+            "Channel 5 is claimed by more than one track!"
+        );
+        assert!(channels(&[track("voice_1", Some(16))]).is_err());
+
+        let mut piece = json_input::parse_piece(&format!(r#"{{"bpm": 120, "tracks": [{}]}}"#, drums)).unwrap();
+        let voice = Voice { channel: Some(5), ..Voice::new("voice_2".to_string(), str::parse::<Scale>("Cmaj").unwrap(), 4, 0, Vec::new()) };
+        piece.tracks.push(Box::new(voice));
+        assert!(piece.to_midi_bytes().is_err());
+    }
+
+    #[test]
+    fn can_shift_pieces() {
+        let mut piece = json_input::parse_piece(include_str!("../examples/wtc_1_1_fugue.json")).unwrap();
+        piece.markers = vec![(0, "A".to_string())];
+        piece.tempo_changes = vec![(4 * TICKS_PER_BEAT, 60)];
+        let bytes = piece.to_midi_bytes().unwrap();
+        let starts: Vec<u32> = piece.tracks.iter().map(|track| *track.get_start_tick()).collect();
+
+        piece.shift_all(i64::from(TICKS_PER_BEAT)).unwrap();
+        assert_eq!(*piece.tracks[0].get_start_tick(), starts[0] + TICKS_PER_BEAT);
+        assert_eq!(piece.markers, [(TICKS_PER_BEAT, "A".to_string())]);
+        assert_eq!(piece.tempo_changes, [(5 * TICKS_PER_BEAT, 60)]);
+
+        piece.shift_all(-i64::from(TICKS_PER_BEAT)).unwrap();
+        assert_eq!(piece.to_midi_bytes().unwrap(), bytes);
+
+        // Nothing moves if the marker at tick 0 can't.
+        assert!(piece.shift_all(-1).is_err());
+        assert_eq!(piece.to_midi_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn can_add_click_tracks() {
+        let mut piece = json_input::parse_piece(
+            r#"{"bpm": 120, "tracks": [{"id": "melody", "scale": "Cmaj", "octave": 4, "start": 1, "type": "voice", "notes": [0, 1, 2, 3, 4, 5, 6, 7, 8]}]}"#,
+        )
+        .unwrap();
+        piece.add_click_track((4, 4)).unwrap();
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let clicks: Vec<(u8, u8)> = smf.tracks[2]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, .. } } => {
+                    Some((channel.as_int(), key.as_int()))
+                }
+                _ => None,
+            })
+            .collect();
+        // The melody lasts 10 beats, so there is a click on each beat of 3 bars, the last
+        // one incomplete.
+        let mut expected = vec![(9, 37); 10];
+        for downbeat in [0, 4, 8] {
+            expected[downbeat] = (9, 34);
+        }
+        assert_eq!(clicks, expected);
+
+        // With a one-beat pickup, the first downbeat is on the second beat.
+        piece.tracks.pop();
+        piece.anacrusis_ticks = TICKS_PER_BEAT;
+        piece.add_click_track((4, 4)).unwrap();
+        let click_track = piece.tracks[1].to_midi(1, 9, DEFAULT_VELOCITY);
+        let downbeats = click_track
+            .iter()
+            .filter(|event| matches!(event.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } if key == 34))
+            .count();
+        assert_eq!(downbeats, 3);
+
+        // In 6/8, there is a click on each of the 20 eighths, and a downbeat every six.
+        piece.tracks.pop();
+        piece.anacrusis_ticks = 0;
+        piece.add_click_track((6, 8)).unwrap();
+        let click_track = piece.tracks[1].to_midi(1, 9, DEFAULT_VELOCITY);
+        let keys: Vec<u8> = click_track
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect();
+        let mut expected = vec![37; 20];
+        for downbeat in [0, 6, 12, 18] {
+            expected[downbeat] = 34;
+        }
+        assert_eq!(keys, expected);
+        piece.tracks.pop();
+        assert_eq!(piece.add_click_track((0, 4)), Err("Invalid time signature 0/4!".to_string()));
+        piece.add_click_track((4, 4)).unwrap();
+
+        // A second click track, or any other track on the percussion channel, is an error.
+        assert_eq!(piece.add_click_track((4, 4)), Err("Track click already plays on the percussion channel!".to_string()));
+        piece.tracks.pop();
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        piece.tracks.push(Box::new(Voice::new("click".to_string(), c_major_scale, 4, 0, vec![TimedNote::new(Some(0), 1)])));
+        assert_eq!(piece.add_click_track((4, 4)), Err("A track with id click already exists!".to_string()));
+    }
+
+    #[test]
+    fn can_create_pieces() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let voice = |id: &str| -> Box<dyn Track> {
+            Box::new(Voice::new(id.to_string(), c_major_scale.clone(), 4, 0, vec![TimedNote::new(Some(0), TICKS_PER_BEAT)]))
+        };
+
+        let piece = Piece::new(90, vec![voice("voice_1"), voice("voice_2")]).unwrap();
+        assert_eq!(piece.bpm, 90);
+        assert_eq!(piece.default_velocity, DEFAULT_VELOCITY);
+
+        assert_eq!(
+            Piece::new(90, vec![voice("voice_1"), voice("voice_2"), voice("voice_1")]).unwrap_err(),
+            "Track id voice_1 is used more than once!"
+        );
+        assert_eq!(Piece::new(0, vec![voice("voice_1")]).unwrap_err(), "bpm must be positive!");
+        assert_eq!(Piece::new(90, Vec::new()).unwrap_err(), "A piece needs at least one track!");
+    }
+
+    #[test]
+    fn can_coalesce_chords() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let note = |id: &str, octave: i8, start: u32, position: i8, duration: u32| -> Box<dyn Track> {
+            let voice = Voice::new(
+                id.to_string(),
+                c_major_scale.clone(),
+                octave,
+                start,
+                vec![TimedNote::new(Some(position), duration)],
+            );
+            Box::new(voice)
+        };
+        // C E G in two octaves, with a melody note held longer and one starting later.
+        let tracks = vec![
+            note("c", 4, 0, 0, TICKS_PER_BEAT),
+            note("melody", 5, 0, 2, 2 * TICKS_PER_BEAT),
+            note("e", 4, 0, 2, TICKS_PER_BEAT),
+            note("g", 3, 0, 11, TICKS_PER_BEAT),
+            note("late", 4, TICKS_PER_BEAT / 2, 4, TICKS_PER_BEAT),
+        ];
+        let mut piece = Piece::new(120, tracks).unwrap();
+        let expected_chord = Chord::new(
+            "c".to_string(),
+            c_major_scale.clone(),
+            4,
+            0,
+            vec![0, 2, 4],
+            vec![(true, TICKS_PER_BEAT)],
+        );
+        let expected_bytes = {
+            let mut expected_piece = piece.clone();
+            expected_piece.tracks.remove(3);
+            expected_piece.tracks.remove(2);
+            expected_piece.tracks[0] = Box::new(expected_chord.clone());
+            expected_piece.to_midi_bytes().unwrap()
+        };
+
+        piece.coalesce_chords();
+
+        let ids: Vec<&str> = piece.tracks.iter().map(|track| track.get_id()).collect();
+        assert_eq!(ids, ["c", "melody", "late"]);
+        assert_eq!(piece.tracks[0].to_midi(0, 0, 100), expected_chord.to_midi(0, 0, 100));
+        assert_eq!(piece.to_midi_bytes().unwrap(), expected_bytes);
+
+        // Notes too far apart for the positions of a chord are left alone.
+        let tracks = vec![note("low", -1, 0, 0, TICKS_PER_BEAT), note("high", 18, 0, 2, TICKS_PER_BEAT)];
+        let mut piece = Piece::new(120, tracks).unwrap();
+        piece.coalesce_chords();
+        let ids: Vec<&str> = piece.tracks.iter().map(|track| track.get_id()).collect();
+        assert_eq!(ids, ["low", "high"]);
+
+        // Tracks playing more than a note are left alone.
+        let mut voice = Voice::new("voice".to_string(), c_major_scale, 4, 0, vec![TimedNote::new(Some(0), 12); 2]);
+        assert_eq!(voice.get_single_note(), None);
+        voice.notes.pop();
+        assert_eq!(voice.get_single_note(), Some((4, 0, 12)));
+    }
+
+    #[test]
+    fn can_append_pieces() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let quarter_notes = |count: usize| vec![TimedNote::new(Some(0), TICKS_PER_BEAT); count];
+
+        let mut piece = Piece {
+            tracks: vec![Box::new(Voice::new(
+                "voice_1".to_string(),
+                c_major_scale.clone(),
+                4,
+                0,
+                quarter_notes(8),
+            ))],
+            ..Default::default()
+        };
+        let other = Piece {
+            bpm: 60,
+            tracks: vec![
+                Box::new(Voice::new("voice_2".to_string(), c_major_scale.clone(), 4, 0, quarter_notes(4))),
+                Box::new(Chord::new(
+                    "chord_1".to_string(),
+                    c_major_scale,
+                    3,
+                    TICKS_PER_BEAT,
+                    vec![0, 2, 4],
+                    vec![(true, TICKS_PER_BEAT)],
+                )),
+            ],
+            markers: vec![(0, "B".to_string())],
+            ..Default::default()
+        };
+
+        piece.append(&other, 2).unwrap();
+
+        assert_eq!(piece.tracks.len(), 3);
+        assert_eq!(*piece.tracks[1].get_start_tick(), 10 * TICKS_PER_BEAT);
+        assert_eq!(*piece.tracks[2].get_start_tick(), 11 * TICKS_PER_BEAT);
+        assert_eq!(piece.get_end_tick(), (8 + 2 + 4) * TICKS_PER_BEAT);
+
+        let seam = 10 * TICKS_PER_BEAT;
+        assert_eq!(piece.tempo_changes, [(seam, 60)]);
+        assert_eq!(piece.markers, [(seam, "B".to_string())]);
+
+        // 60 dotted quarters a minute are 90 quarters a minute.
+        let mut piece = Piece { tracks: vec![piece.tracks[0].clone_box()], ..Default::default() };
+        let other = Piece { bpm: 60, tempo_unit_ticks: 3 * TICKS_PER_BEAT / 2, ..other };
+        piece.append(&other, 0).unwrap();
+        assert_eq!(piece.tempo_changes, [(8 * TICKS_PER_BEAT, 90)]);
+        // And the other way around, 90 quarters are 45 half notes.
+        let mut halves = Piece { bpm: 60, tempo_unit_ticks: 2 * TICKS_PER_BEAT, ..Default::default() };
+        halves.append(&Piece { bpm: 90, ..Default::default() }, 0).unwrap();
+        assert_eq!(halves.tempo_changes, [(0, 45)]);
+        assert!(halves.append(&Piece { tempo_unit_ticks: 0, ..Default::default() }, 0).is_err());
+
+        // 200 whole notes a minute are 800 quarters, too fast to count in a u8.
+        let mut quarters = Piece::default();
+        let wholes = Piece { bpm: 200, tempo_unit_ticks: 4 * TICKS_PER_BEAT, ..Default::default() };
+        assert_eq!(
+            quarters.append(&wholes, 0),
+            Err("A tempo of 200 bpm is 800 bpm in the tempo unit of this piece, above 255!".to_string())
+        );
+        assert!(quarters.tempo_changes.is_empty());
+        // Ticks that would go past u32 are an error, and nothing is appended.
+        let late = Piece { markers: vec![(u32::MAX, "end".to_string())], ..Default::default() };
+        assert!(quarters.append(&late, 1).is_err());
+        assert!(quarters.append(&Piece::default(), u32::MAX).is_err());
+        assert!(quarters.markers.is_empty());
+    }
+
+    #[test]
+    fn can_overlay_pieces() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let voice = |id: &str| -> Box<dyn Track> {
+            Box::new(Voice::new(
+                id.to_string(),
+                c_major_scale.clone(),
+                4,
+                0,
+                vec![TimedNote::new(Some(0), TICKS_PER_BEAT)],
+            ))
+        };
+
+        let mut piece = Piece {
+            tracks: vec![voice("voice_1"), voice("voice_2")],
+            ..Default::default()
+        };
+        let other = Piece {
+            tracks: vec![voice("voice_3")],
+            ..Default::default()
+        };
+        piece.overlay(&other).unwrap();
+        assert_eq!(piece.tracks.len(), 3);
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let mut channels: Vec<u8> = smf.tracks[1..]
+            .iter()
+            .filter_map(|track| {
+                track.iter().find_map(|event| match event.kind {
+                    TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+                    _ => None,
+                })
+            })
+            .collect();
+        channels.sort();
+        channels.dedup();
+        assert_eq!(channels.len(), 3);
+
+        let slower = Piece {
+            bpm: 60,
+            tracks: vec![voice("voice_4")],
+            ..Default::default()
+        };
+        assert!(piece.overlay(&slower).is_err());
+    }
+
+    #[test]
+    fn can_write_markers() {
+        let piece = Piece {
+            bpm: 120,
+            markers: vec![
+                (96, "B".to_string()),
+                (0, "A".to_string()),
+                (96, "B".to_string()),
+            ],
+            ..Default::default()
+        };
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let smf = midly::Smf::parse(&bytes).unwrap();
+        let markers: Vec<(u32, &[u8])> = smf.tracks[0]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::Marker(name)) => Some((event.delta.as_int(), name)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(markers, [(0, b"A".as_slice()), (96, b"B".as_slice())]);
+    }
+
+    #[test]
+    fn can_debug_pieces() {
+        let piece = json_input::parse_piece(
+            r#"{"bpm": 90, "tracks": [
+                {"id": "melody", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [0]},
+                {"id": "left_hand", "scale": "Cmaj", "octave": 3, "start": 0, "type": "chord", "chord": [0, 2, 4], "notes": [0]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", piece),
+            r#"Piece { bpm: 90, tracks: ["voice melody", "chord left_hand"], .. }"#
+        );
+    }
+
+    #[test]
+    fn can_write_copyright() {
+        let piece = Piece {
+            bpm: 120,
+            title: Some("Prélude".to_string()),
+            copyright: Some("© 2024 Moira".to_string()),
+            ..Default::default()
+        };
+
+        let bytes = piece.to_midi_bytes().unwrap();
+        let copyright = "© 2024 Moira".as_bytes();
+        let mut expected_event = vec![0xFF, 0x02, u8::try_from(copyright.len()).unwrap()];
+        expected_event.extend(copyright);
+        assert!(bytes.windows(expected_event.len()).any(|window| window == expected_event));
+    }
+
+    #[test]
+    fn can_format_track() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+        let octave = 4;
+
+        let wtc_1_1_prelude_track = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            octave,
+            0,
+            [0, 2, 4, 7, 9, 4, 7, 9]
+                .into_iter()
+                .map(|position| TimedNote::new(Some(position), TICKS_PER_BEAT / 2))
+                .collect(),
+        );
+
+        wtc_1_1_prelude_track.to_string();
+    }
+
+    #[test]
+    fn can_count_total_ticks() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let notes = (0..8)
+            .map(|i| TimedNote::new(if i == 3 { None } else { Some(i) }, TICKS_PER_BEAT / 2))
+            .collect();
+        let mut voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, notes);
+        assert_eq!(voice.total_ticks(), 8 * (TICKS_PER_BEAT / 2));
+
+        voice.set_start_tick(2 * TICKS_PER_BEAT);
+        assert_eq!(voice.total_ticks(), 12 * (TICKS_PER_BEAT / 2));
+
+        // Notes adding up past u32 end the track at the last tick MIDI can hold.
+        let long_notes = vec![r#"{"1000": {"1000": [0]}}"#; 200].join(", ");
+        let piece = json_input::parse_piece(&format!(
+            r#"{{"bpm": 120, "tracks": [{{"id": "long", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [{}]}}]}}"#,
+            long_notes
+        ))
+        .unwrap();
+        assert_eq!(piece.tracks[0].total_ticks(), MAX_TICK);
+        assert!(piece.duration_seconds() > 0.0);
+        assert!(piece.to_midi_bytes().is_err());
+    }
+
+    #[test]
+    fn can_validate_voices() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let notes = vec![TimedNote::new(Some(0), TICKS_PER_BEAT), TimedNote::new(None, TICKS_PER_BEAT)];
+        let mut voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, notes);
+        assert!(voice.validate().is_ok());
+
+        voice.notes.push(TimedNote::new(Some(2), 0));
+        let error = voice.validate().unwrap_err();
+        assert!(error.contains("Note 2 of voice voice_1"), "{}", error);
+
+        voice.notes[2].duration = TICKS_PER_BEAT;
+        voice.notes[0].fermata = Some(0.0);
+        assert!(voice.validate().is_err());
+
+        // Channels above 15 would wrap around onto another channel.
+        voice.notes[0].fermata = None;
+        voice.notes[2].channel = Some(15);
+        assert!(voice.validate().is_ok());
+        voice.notes[2].channel = Some(16);
+        assert_eq!(voice.validate().unwrap_err(), "Note 2 of voice voice_1 is on channel 16, channels go from 0 to 15!");
+        let piece = Piece { tracks: vec![voice.clone_box()], ..Default::default() };
+        assert!(piece.to_midi_bytes().is_err());
+
+        // A velocity of 0 would be a NoteOff, and above 127 it would wrap around.
+        voice.notes[2].channel = None;
+        for (velocity, is_valid) in [(0, false), (1, true), (127, true), (128, false)] {
+            voice.notes[0].velocity = Some(velocity);
+            assert_eq!(voice.validate().is_ok(), is_valid, "velocity {}", velocity);
+        }
+    }
+
+    #[test]
+    fn can_skip_zero_duration_notes() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let notes = vec![
+            TimedNote::new(Some(0), TICKS_PER_BEAT),
+            TimedNote::new(Some(1), 0),
+            TimedNote::new(Some(2), TICKS_PER_BEAT),
+        ];
+        let voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, notes);
+        let note_ons: Vec<(u32, u8)> = voice
+            .to_midi(1, 0, DEFAULT_VELOCITY)
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => {
+                    Some((event.delta.as_int(), key.as_int()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(note_ons, [(0, 60), (0, 64)]);
+    }
+
+    #[test]
+    fn can_set_release_velocity() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![TimedNote::new(Some(0), TICKS_PER_BEAT), TimedNote::new(Some(1), TICKS_PER_BEAT)],
+        );
+        let release_velocities = |voice: &Voice| -> Vec<u8> {
+            voice
+                .to_midi(1, 0, DEFAULT_VELOCITY)
+                .iter()
+                .filter_map(|event| match event.kind {
+                    TrackEventKind::Midi { message: MidiMessage::NoteOff { vel, .. }, .. } => Some(vel.as_int()),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(release_velocities(&voice), [DEFAULT_RELEASE_VELOCITY; 2]);
+
+        voice.release_velocity = 0;
+        assert_eq!(release_velocities(&voice), [0, 0]);
+    }
+
+    #[test]
+    fn can_override_note_channels() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let mut accent = TimedNote::new(Some(1), TICKS_PER_BEAT);
+        accent.channel = Some(9);
+        let voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            TICKS_PER_BEAT,
+            vec![
+                TimedNote::new(Some(0), TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT),
+                accent,
+                TimedNote::new(Some(2), TICKS_PER_BEAT),
+            ],
+        );
+
+        let note_events: Vec<(u32, u8, bool)> = voice
+            .to_midi(1, 2, DEFAULT_VELOCITY)
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { .. } } => {
+                    Some((event.delta.as_int(), channel.as_int(), true))
+                }
+                TrackEventKind::Midi { channel, message: MidiMessage::NoteOff { .. } } => {
+                    Some((event.delta.as_int(), channel.as_int(), false))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            note_events,
+            [(24, 2, true), (24, 2, false), (24, 9, true), (24, 9, false), (0, 2, true), (24, 2, false)]
+        );
+    }
+
+    #[test]
+    fn can_accent_downbeats() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            [0, 1, 2, 3, 4, 5, 6, 7]
+                .into_iter()
+                .map(|position| TimedNote::new(Some(position), TICKS_PER_BEAT))
+                .chain([TimedNote::new(Some(0), TICKS_PER_BEAT / 2); 2])
+                .collect(),
+        );
+        voice.apply_metric_accents((4, 4), 0, 110, 70).unwrap();
+
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        assert_eq!(
+            velocities,
+            [110, 70, 70, 70, 110, 70, 70, 70, 110]
+                .map(Some)
+                .into_iter()
+                .chain([None])
+                .collect::<Vec<_>>()
+        );
+
+        // In 6/8, the fourth eighth of the bar is a secondary strong beat.
+        voice.notes = vec![TimedNote::new(Some(0), TICKS_PER_BEAT / 2); 6];
+        voice.apply_metric_accents((6, 8), 0, 110, 70).unwrap();
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        assert_eq!(velocities, [110, 70, 70, 110, 70, 70].map(Some));
+
+        // With a one-beat pickup, the first downbeat is the second note.
+        voice.notes = vec![TimedNote::new(Some(0), TICKS_PER_BEAT); 6];
+        voice.apply_metric_accents((4, 4), TICKS_PER_BEAT, 110, 70).unwrap();
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        assert_eq!(velocities, [70, 110, 70, 70, 70, 110].map(Some));
+
+        // Time signatures without whole beats or bars are rejected.
+        for time_signature in [(4, 0), (0, 4), (4, 3), (4, 64), (4, 128)] {
+            assert_eq!(
+                voice.apply_metric_accents(time_signature, 0, 110, 70),
+                Err(format!("Invalid time signature {}/{}!", time_signature.0, time_signature.1))
+            );
+        }
+    }
+
+    #[test]
+    fn can_accent_positions() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![TimedNote::new(Some(0), TICKS_PER_BEAT / 2); 16],
+        );
+        voice.notes[11].position = None;
+        voice.notes[13].velocity = Some(90);
+        voice.notes[15].velocity = Some(120);
+        voice.accent_positions(&[2.5, 4.5], 20, (4, 4), 0, DEFAULT_VELOCITY).unwrap();
+
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        let mut expected = vec![None; 16];
+        expected[3] = Some(DEFAULT_VELOCITY + 20);
+        expected[7] = Some(DEFAULT_VELOCITY + 20);
+        expected[13] = Some(90);
+        expected[15] = Some(127);
+        assert_eq!(velocities, expected);
+
+        // In 6/8 after an eighth-note pickup, the second eighth of each bar is the third note.
+        voice.notes = vec![TimedNote::new(Some(0), TICKS_PER_BEAT / 2); 8];
+        voice.accent_positions(&[2.0], 20, (6, 8), TICKS_PER_BEAT / 2, 80).unwrap();
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        assert_eq!(velocities, [None, None, Some(100), None, None, None, None, None]);
+
+        assert_eq!(
+            voice.accent_positions(&[2.0], 20, (4, 0), 0, 80),
+            Err("Invalid time signature 4/0!".to_string())
+        );
+    }
+
+    #[test]
+    fn can_snap_durations() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let notes = [(Some(0), 23), (None, 1), (Some(1), 25), (Some(2), 12), (Some(3), 50)]
+            .into_iter()
+            .map(|(position, duration)| TimedNote::new(position, duration))
+            .collect();
+        let mut voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, notes);
+        voice.notes[2].velocity = Some(80);
+        voice.slurs = vec![(2, 4)];
+
+        voice.snap_durations();
+
+        let snapped_notes: Vec<(Option<i8>, u32)> =
+            voice.notes.iter().map(|timed_note| (timed_note.position, timed_note.duration)).collect();
+        // The notes still start on ticks 0, 24, 49 and 61.
+        assert_eq!(
+            snapped_notes,
+            [(Some(0), 24), (Some(1), 24), (None, 1), (Some(2), 12), (Some(3), 48), (None, 2)]
+        );
+        assert_eq!(voice.notes[1].velocity, Some(80));
+        assert_eq!(voice.slurs, [(1, 4)]);
+
+        // A note too short to be notated is kept as it is, and the last note doesn't
+        // outlast the voice.
+        let mut voice = Voice::new(
+            "voice_2".to_string(),
+            str::parse::<Scale>("Cmaj").unwrap(),
+            4,
+            0,
+            vec![TimedNote::new(Some(0), 3), TimedNote::new(Some(1), 21)],
+        );
+        voice.snap_durations();
+        assert_eq!(voice.notes[0].duration, 3);
+        assert_eq!(voice.notes[1].duration, 18);
+
+        let mut piece = json_input::parse_piece(
+            r#"{"bpm": 120, "tracks": [{"id": "chord", "scale": "Cmaj", "octave": 3, "start": 0, "type": "chord", "chord": [0, 2, 4], "notes": [{"1/24": [null]}, {"97/24": [true]}]}]}"#,
+        )
+        .unwrap();
+        piece.snap_durations();
+        assert_eq!(piece.tracks[0].total_ticks(), 97 + 1);
+    }
+
+    #[test]
+    fn can_reject_ticks_beyond_midi() {
+        let piece = json_input::parse_piece(
+            r#"{"bpm": 120, "tracks": [{"id": "long", "scale": "Cmaj", "octave": 4, "start": 0, "type": "voice", "notes": [{"20000": {"1000": [0]}}]}]}"#,
+        )
+        .unwrap();
+        assert!(piece.tracks[0].check_midi().is_err());
+        assert!(piece.to_midi_bytes().is_err());
+
+        // Durations adding up past u32 are an error rather than a panic.
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, vec![TimedNote::new(Some(0), u32::MAX / 2); 3]);
+        assert!(voice.check_midi().is_err());
+        let voice = Voice { notes: vec![TimedNote::new(Some(0), MAX_TICK)], ..voice };
+        assert!(voice.check_midi().is_ok());
+        assert!(Voice { start_tick: 1, ..voice }.check_midi().is_err());
+
+        let mut piece = Piece::default();
+        piece.markers.push((MAX_TICK + 1, "end".to_string()));
+        assert!(piece.to_midi_bytes().is_err());
+    }
+
+    #[test]
+    fn can_reject_tempos_beyond_midi() {
+        assert_eq!(get_microseconds_per_beat(120, TICKS_PER_BEAT), Ok(500000));
+        assert_eq!(get_microseconds_per_beat(4, TICKS_PER_BEAT), Ok(15000000));
+        for (bpm, tempo_unit_ticks) in [(3, TICKS_PER_BEAT), (0, TICKS_PER_BEAT), (120, 0), (1, u32::MAX)] {
+            assert_eq!(
+                get_microseconds_per_beat(bpm, tempo_unit_ticks),
+                Err(format!("A tempo of {} bpm counting {} ticks can't be written to MIDI!", bpm, tempo_unit_ticks))
+            );
+        }
+
+        let mut piece = Piece { tempo_unit_ticks: 0, ..Default::default() };
+        assert!(piece.to_midi_bytes().is_err());
+        piece.tempo_unit_ticks = TICKS_PER_BEAT;
+        assert!(piece.to_midi_bytes().is_ok());
+        piece.tempo_changes.push((0, 2));
+        assert!(piece.to_midi_bytes().is_err());
+    }
+
+    #[test]
+    fn can_rearticulate_repeated_notes() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let notes = [(Some(0), 12), (Some(0), 12), (Some(0), 0), (Some(0), 1), (Some(0), 12), (Some(2), 12)]
+            .into_iter()
+            .map(|(position, duration)| TimedNote::new(position, duration))
+            .collect();
+        let voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, notes);
+
+        let mut tick = 0;
+        let mut note_ons: Vec<u32> = Vec::new();
+        let mut note_offs: Vec<u32> = Vec::new();
+        for event in voice.to_midi(1, 0, DEFAULT_VELOCITY).iter() {
+            tick += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } if key == 60 => note_ons.push(tick),
+                TrackEventKind::Midi { message: MidiMessage::NoteOff { key, .. }, .. } if key == 60 => note_offs.push(tick),
+                _ => {}
+            }
+        }
+        // Each C4 is released before the next one starts, without moving any NoteOn.
+        assert_eq!(note_ons, [0, 12, 24, 25]);
+        assert!(note_offs.iter().zip(note_ons.iter().skip(1)).all(|(note_off, next_note_on)| note_off <= next_note_on));
+        // A note of a single tick can't be shortened, but is still released before the
+        // next NoteOn of its tick, and the last C4 is held to its end.
+        assert_eq!(note_offs, [11, 23, 25, 37]);
+    }
+
+    #[test]
+    fn can_slur_notes() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let notes = [Some(0), Some(1), Some(1), None, Some(3), Some(4)]
+            .into_iter()
+            .map(|position| TimedNote::new(position, TICKS_PER_BEAT))
+            .collect();
+        let mut voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, notes);
+        voice.slurs = vec![(0, 5)];
+
+        let mut tick = 0;
+        let note_events: Vec<(u32, u8, bool)> = voice
+            .to_midi(1, 0, DEFAULT_VELOCITY)
+            .iter()
+            .filter_map(|event| {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some((tick, key.as_int(), true)),
+                    TrackEventKind::Midi { message: MidiMessage::NoteOff { key, .. }, .. } => Some((tick, key.as_int(), false)),
+                    _ => None,
+                }
+            })
+            .collect();
+        let overlap = SLUR_OVERLAP_TICKS;
+        assert_eq!(
+            note_events,
+            [
+                // C4 is held until after D4 starts, but the repeated D4 is released first.
+                (0, 60, true),
+                (24, 62, true),
+                (24 + overlap, 60, false),
+                (48 - REARTICULATION_GAP_TICKS, 62, false),
+                (48, 62, true),
+                (72, 62, false),
+                // The rest ends the slur.
+                (96, 65, true),
+                (120, 65, false),
+                (120, 67, true),
+                (144, 67, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_ramp_expression() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            TICKS_PER_BEAT,
+            vec![TimedNote::new(Some(0), TICKS_PER_BEAT); 4],
+        );
+        voice.add_expression_ramp(0, 4 * TICKS_PER_BEAT, 40, 100).unwrap();
+        assert!(voice.add_expression_ramp(2, 1, 40, 100).is_err());
+        assert!(voice.add_expression_ramp(0, 1, 40, 128).is_err());
+        assert_eq!(voice.expression_ramps(), [(0, 4 * TICKS_PER_BEAT, 40, 100)]);
+
+        let events = voice.to_midi(1, 3, DEFAULT_VELOCITY);
+        assert!(matches!(events[0].kind, TrackEventKind::Midi { message: MidiMessage::ProgramChange { .. }, .. }));
+        let mut tick = 0;
+        let expression: Vec<(u32, u8)> = events
+            .iter()
+            .filter_map(|event| {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } }
+                        if controller == 11 && channel == 3 => Some((tick, value.as_int())),
+                    _ => None,
+                }
+            })
+            .collect();
+        assert_eq!(expression.first(), Some(&(TICKS_PER_BEAT, 40)));
+        assert_eq!(expression.last(), Some(&(5 * TICKS_PER_BEAT, 100)));
+        // One step every EXPRESSION_RAMP_STEP_TICKS, going up steadily.
+        assert_eq!(expression.len(), 17);
+        assert!(expression.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        assert_eq!(tick, 5 * TICKS_PER_BEAT);
+    }
+
+    #[test]
+    fn can_realize_probabilities() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let notes = (0..64)
+            .map(|i| TimedNote {
+                probability: [Some(0.0), Some(1.0), Some(0.5), None][i % 4],
+                ..TimedNote::new(Some(i8::try_from(i % 7).unwrap()), TICKS_PER_BEAT)
+            })
+            .collect();
+        let mut voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, notes);
+        voice.lyrics = vec!["never".to_string(), "always".to_string()];
+
+        let realized = voice.realize(7);
+        assert_eq!(realized.notes.len(), voice.notes.len());
+        assert!(realized.notes.iter().all(|timed_note| timed_note.duration == TICKS_PER_BEAT));
+        assert!(realized.notes.iter().all(|timed_note| timed_note.probability.is_none()));
+        for (i, timed_note) in realized.notes.iter().enumerate() {
+            match i % 4 {
+                0 => assert_eq!(timed_note.position, None, "note {}", i),
+                1 | 3 => assert_eq!(timed_note.position, voice.notes[i].position, "note {}", i),
+                _ => {}
+            }
+        }
+        // A fair coin lands on both sides in 16 throws.
+        let halves: Vec<bool> = realized.notes.iter().skip(2).step_by(4).map(|timed_note| timed_note.position.is_some()).collect();
+        assert!(halves.contains(&true) && halves.contains(&false));
+        // The syllable of a note that isn't played is dropped.
+        assert_eq!(realized.lyrics, ["always"]);
+
+        assert_eq!(voice.realize(7).to_midi(1, 0, 100), realized.to_midi(1, 0, 100));
+        let other_halves: Vec<bool> =
+            voice.realize(8).notes.iter().skip(2).step_by(4).map(|timed_note| timed_note.position.is_some()).collect();
+        assert_ne!(other_halves, halves);
+    }
+
+    #[test]
+    fn can_ramp_aftertouch() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            TICKS_PER_BEAT,
+            vec![TimedNote::new(Some(0), TICKS_PER_BEAT); 2],
+        );
+        voice.add_aftertouch_ramp(TICKS_PER_BEAT / 2, TICKS_PER_BEAT / 2, 90, 90).unwrap();
+        voice.add_aftertouch_ramp(TICKS_PER_BEAT, 2 * TICKS_PER_BEAT, 90, 30).unwrap();
+        assert!(voice.add_aftertouch_ramp(2, 1, 40, 100).is_err());
+        assert!(voice.add_aftertouch_ramp(0, 1, 128, 100).is_err());
+        assert_eq!(voice.aftertouch_ramps().len(), 2);
+
+        let events = voice.to_midi(1, 3, DEFAULT_VELOCITY);
+        let mut tick = 0;
+        let aftertouch: Vec<(u32, u32, u8)> = events
+            .iter()
+            .filter_map(|event| {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Midi { channel, message: MidiMessage::ChannelAftertouch { vel } } if channel == 3 => {
+                        Some((event.delta.as_int(), tick, vel.as_int()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        // The first value is due half a beat after the first note on.
+        assert_eq!(aftertouch.first(), Some(&(TICKS_PER_BEAT / 2, 3 * TICKS_PER_BEAT / 2, 90)));
+        assert_eq!(aftertouch[aftertouch.len() - 2], (0, 3 * TICKS_PER_BEAT, 30));
+        assert!(aftertouch[2..aftertouch.len() - 1].windows(2).all(|pair| pair[0].2 > pair[1].2));
+        // The pressure is released at the end of the voice.
+        assert_eq!(aftertouch.last(), Some(&(0, 3 * TICKS_PER_BEAT, 0)));
+        assert_eq!(tick, 3 * TICKS_PER_BEAT);
+        assert_eq!(events.last().unwrap().kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+    }
+
+    #[test]
+    fn can_apply_velocity_curves() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            [Some(64), Some(127), Some(1), None]
+                .into_iter()
+                .map(|velocity| {
+                    let mut timed_note = TimedNote::new(Some(0), TICKS_PER_BEAT);
+                    timed_note.velocity = velocity;
+                    timed_note
+                })
+                .collect(),
+        );
+        voice.apply_velocity_curve(2.0).unwrap();
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        assert_eq!(velocities, [Some(32), Some(127), Some(1), None]);
+
+        voice.apply_velocity_curve(0.5).unwrap();
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        assert_eq!(velocities, [Some(64), Some(127), Some(11), None]);
+
+        // A huge gamma sends every velocity below 127 to 0, which stays at 1.
+        voice.apply_velocity_curve(1000.0).unwrap();
+        let velocities: Vec<Option<u8>> = voice.notes.iter().map(|note| note.velocity).collect();
+        assert_eq!(velocities, [Some(1), Some(127), Some(1), None]);
+
+        for gamma in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(voice.apply_velocity_curve(gamma), Err(format!("Invalid velocity curve gamma {}!", gamma)));
+        }
+    }
+
+    #[test]
+    fn can_hold_fermatas() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+
+        let mut held_note = TimedNote::new(Some(0), TICKS_PER_BEAT);
+        held_note.fermata = Some(2.0);
+        let voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![held_note, TimedNote::new(Some(1), TICKS_PER_BEAT)],
+        );
+
+        let note_off_deltas: Vec<u32> = voice
+            .to_midi(1, 0, DEFAULT_VELOCITY)
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOff { .. }, .. } => {
+                    Some(event.delta.as_int())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(note_off_deltas, [2 * TICKS_PER_BEAT, TICKS_PER_BEAT]);
+    }
+
+    #[test]
+    fn can_sync_lyrics() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let c_major_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11]).unwrap();
+
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![
+                TimedNote::new(Some(0), TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT),
+                TimedNote::new(Some(1), TICKS_PER_BEAT),
+                TimedNote::new(Some(2), TICKS_PER_BEAT),
+                TimedNote::new(Some(3), TICKS_PER_BEAT),
+            ],
+        );
+        voice.lyrics = ["Ky", "ri", "e"].map(str::to_string).to_vec();
+
+        // Each syllable comes right before the NoteOn it is sung on.
+        let track_events = voice.to_midi(1, 0, DEFAULT_VELOCITY);
+        let mut syllables = Vec::new();
+        for (event, next_event) in track_events.iter().zip(track_events.iter().skip(1)) {
+            if let TrackEventKind::Meta(MetaMessage::Lyric(syllable)) = event.kind {
+                assert!(matches!(
+                    next_event.kind,
+                    TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. }
+                ));
+                assert_eq!(next_event.delta, 0);
+                syllables.push(std::str::from_utf8(syllable).unwrap());
+            }
+        }
+        assert_eq!(syllables, ["Ky", "ri", "e"]);
+    }
+
+    #[test]
+    fn can_get_retrograde() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![
+                TimedNote::new(Some(0), TICKS_PER_BEAT),
+                TimedNote::new(None, TICKS_PER_BEAT / 2),
+                TimedNote::new(Some(4), TICKS_PER_BEAT / 2),
+                TimedNote::new(Some(2), TICKS_PER_BEAT * 2),
+            ],
+        );
+
+        let retrograde = voice.retrograde();
+        let positions: Vec<Option<i8>> = retrograde.notes.iter().map(|note| note.position).collect();
+        assert_eq!(positions, [Some(2), Some(4), None, Some(0)]);
+        assert_eq!(retrograde.notes[2].duration, TICKS_PER_BEAT / 2);
+
+        let total_duration = |voice: &Voice| -> u32 {
+            voice.notes.iter().map(|note| note.duration).sum()
+        };
+        assert_eq!(total_duration(&retrograde), total_duration(&voice));
+        assert_eq!(retrograde.retrograde().notes, voice.notes);
+    }
+
+    #[test]
+    fn can_invert_melody() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let voice = Voice::new(
+            "voice_1".to_string(),
+            c_major_scale,
+            4,
+            0,
+            vec![
+                TimedNote::new(Some(2), TICKS_PER_BEAT),
+                TimedNote::new(Some(3), TICKS_PER_BEAT / 2),
+                TimedNote::new(None, TICKS_PER_BEAT / 2),
+                TimedNote::new(Some(4), TICKS_PER_BEAT),
+            ],
+        );
+
+        let inversion = voice.melodic_invert(2).unwrap();
+        let positions: Vec<Option<i8>> = inversion.notes.iter().map(|note| note.position).collect();
+        assert_eq!(positions, [Some(2), Some(1), None, Some(0)]);
+
+        let durations: Vec<u32> = inversion.notes.iter().map(|note| note.duration).collect();
+        assert_eq!(durations, voice.notes.iter().map(|note| note.duration).collect::<Vec<_>>());
+
+        // E F G becomes E D C
+        let note_names: Vec<String> = positions
+            .iter()
+            .flatten()
+            .map(|position| inversion.scale.get_named_note(*position, 4).to_string())
+            .collect();
+        assert_eq!(note_names, ["E4", "D4", "C4"]);
+
+        let low_voice = Voice { notes: vec![TimedNote::new(Some(-100), TICKS_PER_BEAT)], ..voice };
+        assert_eq!(low_voice.melodic_invert(100).err(), Some("Cannot invert note 0 of voice voice_1 around 100!".to_string()));
+        assert_eq!(low_voice.melodic_invert(0).unwrap().notes[0].position, Some(100));
+    }
+
+    #[test]
+    fn can_augment_and_diminish() {
+        let c_major_scale = str::parse::<Scale>("Cmaj").unwrap();
+        let original_notes = vec![
+            TimedNote::new(Some(0), TICKS_PER_BEAT),
+            TimedNote::new(None, TICKS_PER_BEAT / 2),
+            TimedNote::new(Some(1), TICKS_PER_BEAT / 4),
+        ];
+        let mut voice = Voice::new("voice_1".to_string(), c_major_scale, 4, 0, original_notes.clone());
+        let total_duration = |voice: &Voice| -> u32 {
+            voice.notes.iter().map(|note| note.duration).sum()
+        };
+        let original_duration = total_duration(&voice);
+
+        voice.augment(2).unwrap();
+        assert_eq!(total_duration(&voice), 2 * original_duration);
+        voice.diminish(2).unwrap();
+        assert_eq!(voice.notes, original_notes);
+
+        // 6 ticks can't be divided by 4, and nothing changes.
+        assert!(voice.diminish(4).is_err());
+        assert_eq!(voice.notes, original_notes);
+        assert!(voice.augment(u32::MAX).is_err());
+        assert_eq!(voice.notes, original_notes);
+    }
+
+    #[test]
+    fn can_bend_tuned_notes() {
+        let c = str::parse::<NamedKey>("C").unwrap();
+        let mut cents = [0.0; 12];
+        cents[0] = 50.0;
+        let tuned_scale = Scale::new(c, vec![0, 2, 4, 5, 7, 9, 11])
+            .unwrap()
+            .with_tuning(Tuning::new(cents).unwrap());
+
+        let voice = Voice::new(
+            "voice_1".to_string(),
+            tuned_scale,
+            4,
+            0,
+            vec![TimedNote::new(Some(0), TICKS_PER_BEAT), TimedNote::new(Some(1), TICKS_PER_BEAT)],
+        );
+
+        let pitch_bends: Vec<i16> = voice
+            .to_midi(1, 0, DEFAULT_VELOCITY)
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::PitchBend { bend }, .. } => {
+                    Some(bend.as_int())
+                }
+                _ => None,
+            })
+            .collect();
+
+        // +50 cents is a quarter of the ±200 cents bend range, reset for the untuned D
+        assert_eq!(pitch_bends, [2048, 0]);
+    }
+
+    #[test]
+    fn can_bend_quarter_tones() {
+        let scale = Scale::from_named_keys(
+            ["C", "D", "E𝄳", "F", "G", "A", "B𝄳"].map(|key| str::parse::<NamedKey>(key).unwrap()).to_vec(),
+        )
+        .unwrap();
+        let mut voice = Voice::new(
+            "voice_1".to_string(),
+            scale,
+            4,
+            0,
+            [0, 2, 2, 6].map(|position| TimedNote::new(Some(position), TICKS_PER_BEAT)).to_vec(),
+        );
+        voice.notes[3].channel = Some(3);
+
+        let mut tick = 0;
+        let events: Vec<(u32, u8, i16)> = voice
+            .to_midi(1, 0, DEFAULT_VELOCITY)
+            .iter()
+            .filter_map(|event| {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Midi { channel, message: MidiMessage::PitchBend { bend } } => {
+                        Some((tick, channel.as_int(), bend.as_int()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        // E𝄳 is bent a quarter tone down once, and B𝄳 on its own channel, and both channels
+        // are reset at the end.
+        assert_eq!(
+            events,
+            [(TICKS_PER_BEAT, 0, -2048), (3 * TICKS_PER_BEAT, 3, -2048), (4 * TICKS_PER_BEAT, 0, 0), (4 * TICKS_PER_BEAT, 3, 0)]
+        );
     }
 }
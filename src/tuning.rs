@@ -0,0 +1,48 @@
+// This module provides the following struct:
+// Tuning: how far each of the 12 keys deviates from equal temperament, in cents.
+
+use alloc::format;
+use alloc::string::String;
+
+use midly::PitchBend;
+
+use super::key::Key;
+use super::math::round;
+
+/// The pitch bend range assumed when rendering a tuning to MIDI. This is the
+/// General MIDI default of ±2 semitones.
+pub const PITCH_BEND_RANGE_CENTS: f64 = 200.0;
+
+/// Deviations from equal temperament, in cents, for each key (0 is C, 11 is B).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tuning([f64; 12]);
+
+impl Tuning {
+    /// Create a tuning from the cent offsets of each key, starting from C.
+    ///
+    /// # Errors
+    /// - if any offset lies outside of the pitch bend range.
+    pub fn new(cents: [f64; 12]) -> Result<Self, String> {
+        if cents.iter().any(|offset| offset.abs() > PITCH_BEND_RANGE_CENTS) {
+            return Err(format!(
+                "All cent offsets must be between -{0} and {0}!",
+                PITCH_BEND_RANGE_CENTS
+            ));
+        }
+        Ok(Self(cents))
+    }
+    pub fn get_cents(&self, key: Key) -> f64 {
+        self.0[usize::try_from(key.get_value()).unwrap()]
+    }
+    /// Get the pitch bend that realizes this key's offset.
+    pub fn get_pitch_bend(&self, key: Key) -> PitchBend {
+        get_pitch_bend(self.get_cents(key))
+    }
+}
+
+/// Get the pitch bend that raises a note by the given number of cents, e.g. to play
+/// a quarter tone.
+pub fn get_pitch_bend(cents: f64) -> PitchBend {
+    let bend = round(cents / PITCH_BEND_RANGE_CENTS * 8192.0);
+    PitchBend::from_int(bend.clamp(-8192.0, 8191.0) as i16)
+}